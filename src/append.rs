@@ -0,0 +1,196 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{Category, LabeledError, PipelineData, Signature, Spanned, SyntaxShape, Type, Value};
+
+use kdl::{FormatConfig, KdlDocument, KdlDocumentFormat, KdlNode};
+
+use crate::{
+    error::KdlPluginError,
+    query::{self, Query},
+    KDL,
+};
+
+pub struct KdlAppend;
+
+impl PluginCommand for KdlAppend {
+    type Plugin = KDL;
+
+    fn name(&self) -> &str {
+        "kdl append"
+    }
+
+    fn description(&self) -> &str {
+        "Add nodes as the last children of the nodes matching a query, keeping the rest of the document as is"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .input_output_type(Type::String, Type::String)
+            .required_named("at", SyntaxShape::String, "The query of the nodes to append to, e.g. \"tab > pane\"", Some('a'))
+            .required_named("node", SyntaxShape::String, "The KDL of the nodes to append, e.g. 'pane size=1'", Some('n'))
+            .named("case", SyntaxShape::String, "How to match the names of nodes and properties, sensitive (default) or insensitive", None)
+            .switch("v1", "Force parsing as KDL v1 only", Some('1'))
+            .switch("v1-fallback", "Try KDL v2, fall back to v1 if parsing fails", None)
+            .category(Category::Experimental)
+    }
+
+    fn run(
+        &self,
+        plugin: &KDL,
+        _engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let value = input.into_value(call.head)?;
+
+        let at: Spanned<String> = call.get_flag("at")?.ok_or(KdlPluginError::MissingFlag { name: "at", span: call.head })?;
+        let node: Spanned<String> = call.get_flag("node")?.ok_or(KdlPluginError::MissingFlag { name: "node", span: call.head })?;
+
+        let result = append(plugin, call, &value, &at, &node)?;
+        Ok(PipelineData::Value(result, None))
+    }
+}
+
+/// Appends the nodes of the `node` snippet to the children of every node matching the `at` query,
+/// giving back the document with only these children blocks changed. The snippet is in the version
+/// the document was parsed as, and so are the nodes written.
+pub(crate) fn append(
+    plugin: &KDL,
+    call: &EvaluatedCall,
+    input: &Value,
+    at: &Spanned<String>,
+    node: &Spanned<String>,
+) -> Result<Value, LabeledError> {
+    let query = Query::parse_arg(at)?.with_case(query::case(call)?);
+    let (mut document, fell_back) = plugin.parse(call, input)?;
+    let v1 = fell_back || call.has_flag("v1")?;
+    let snippet = match v1 {
+        true => KdlDocument::parse_v1(&node.item),
+        false => node.item.parse(),
+    };
+    let snippet = snippet.map_err(|error| KdlPluginError::ParseFailed {
+        version: if v1 { "v1" } else { "v2" },
+        error,
+        span: node.span,
+    })?;
+
+    let paths = query.paths(&document);
+    if paths.is_empty() {
+        return Err(KdlPluginError::NoMatch { query: at.item.clone(), span: at.span }.into());
+    }
+
+    for path in paths {
+        let depth = path.len();
+        let target = query::node_mut(&mut document, &path);
+        for child in snippet.nodes() {
+            append_child(target, child.clone(), depth);
+            if v1 {
+                // autoformatting wrote its values as v2
+                target.children_mut().as_mut().and_then(|children| children.nodes_mut().last_mut()).unwrap().ensure_v1();
+            }
+        }
+    }
+
+    Ok(Value::string(document.to_string(), call.head))
+}
+
+/// Appends `child` to the children of `target`, `depth` levels deep, in the style of the block: on
+/// its own line or after a `;` for a single-line block, the block being created when missing.
+pub(crate) fn append_child(target: &mut KdlNode, mut child: KdlNode, depth: usize) {
+    child.autoformat_config(&FormatConfig::builder().indent_level(depth).build());
+
+    // the closing `}` of a new block goes at the indentation of the node itself
+    let indent = target
+        .format()
+        .and_then(|f| f.leading.rsplit('\n').next())
+        .unwrap_or_default()
+        .to_string();
+
+    let children = target.ensure_children();
+    match children.nodes_mut().last_mut() {
+        None => {
+            children.set_format(KdlDocumentFormat { leading: "\n".to_string(), trailing: indent });
+        }
+        Some(last) if !last.format().is_some_and(|f| f.terminator.is_empty() || f.terminator == ";") => {}
+        Some(last) => {
+            // a single-line block, e.g. `node { a 1; b 2 }`
+            if let Some(format) = last.format_mut() {
+                format.terminator = ";".to_string();
+            }
+            if let Some(format) = child.format_mut() {
+                format.leading = " ".to_string();
+                format.terminator = String::new();
+            }
+        }
+    }
+    children.nodes_mut().push(child);
+
+    if let Some(format) = target.format_mut() {
+        if format.before_children.is_empty() {
+            format.before_children = " ".to_string();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nu_protocol::{IntoSpanned, Span};
+
+    fn append_kdl(input: &str, at: &str, node: &str) -> Result<String, LabeledError> {
+        let call = EvaluatedCall::new(Span::test_data());
+        let at = at.to_string().into_spanned(Span::test_data());
+        let node = node.to_string().into_spanned(Span::test_data());
+
+        append(&KDL, &call, &Value::test_string(input), &at, &node).map(|v| v.into_string().unwrap())
+    }
+
+    const LAYOUT: &str = "// my layout\nlayout {\n    tab name=main {\n        pane   size=1 // the top one\n    }\n    tab name=logs\n}\n";
+
+    #[test]
+    fn test_append_to_block() {
+        let output = append_kdl(LAYOUT, "tab[name=main]", "pane size=2").unwrap();
+
+        assert_eq!(
+            output,
+            "// my layout\nlayout {\n    tab name=main {\n        pane   size=1 // the top one\n        pane size=2\n    }\n    tab name=logs\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_append_creates_block() {
+        let output = append_kdl(LAYOUT, "tab[name=logs]", "pane").unwrap();
+
+        assert_eq!(
+            output,
+            "// my layout\nlayout {\n    tab name=main {\n        pane   size=1 // the top one\n    }\n    tab name=logs {\n        pane\n    }\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_append_to_every_match() {
+        let output = append_kdl("a x=1 { b 1; }\nc x=2\n", "*[x]", "d").unwrap();
+
+        assert_eq!(output, "a x=1 { b 1; d }\nc x=2 {\n    d\n}\n");
+    }
+
+    #[test]
+    fn test_append_to_v1_document() {
+        let call = EvaluatedCall::new(Span::test_data()).with_flag("v1-fallback".to_string().into_spanned(Span::test_data()));
+        let at = "tab".to_string().into_spanned(Span::test_data());
+        let node = "pane borderless=true name=\"new\"".to_string().into_spanned(Span::test_data());
+        let input = include_str!("../zellij-layout.kdl");
+
+        let output = append(&KDL, &call, &Value::test_string(input), &at, &node).unwrap().into_string().unwrap();
+
+        assert!(output.contains("pane borderless=true name=\"new\"\n"), "{}", output);
+        assert!(KdlDocument::parse_v1(&output).is_ok());
+    }
+
+    #[test]
+    fn test_append_no_match() {
+        let error = append_kdl(LAYOUT, "window", "pane").unwrap_err();
+
+        assert_eq!(error.msg, "no node matches `window`");
+    }
+}
@@ -0,0 +1,79 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{Category, LabeledError, PipelineData, Signature, Spanned, SyntaxShape, Type, Value};
+
+use crate::{
+    query::{self, Query},
+    KDL,
+};
+
+pub struct KdlCount;
+
+impl PluginCommand for KdlCount {
+    type Plugin = KDL;
+
+    fn name(&self) -> &str {
+        "kdl count"
+    }
+
+    fn description(&self) -> &str {
+        "Count the nodes matching a query, without converting them"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .input_output_type(Type::String, Type::Int)
+            .required("query", SyntaxShape::String, "The query of the nodes, e.g. \"pane[focus=#true]\"")
+            .named("case", SyntaxShape::String, "How to match the names of nodes and properties, sensitive (default) or insensitive", None)
+            .switch("v1", "Force parsing as KDL v1 only", Some('1'))
+            .switch("v1-fallback", "Try KDL v2, fall back to v1 if parsing fails", None)
+            .category(Category::Experimental)
+    }
+
+    fn run(
+        &self,
+        plugin: &KDL,
+        _engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let value = input.into_value(call.head)?;
+        let result = count(plugin, call, &value)?;
+        Ok(PipelineData::Value(result, None))
+    }
+}
+
+/// Counts the nodes matching the query given as the first positional argument, anywhere in the
+/// document.
+pub(crate) fn count(plugin: &KDL, call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let at: Spanned<String> = call.req(0)?;
+
+    let query = Query::parse_arg(&at)?.with_case(query::case(call)?);
+    let (document, _) = plugin.parse(call, input)?;
+
+    Ok(Value::int(query.paths(&document).len() as i64, call.head))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nu_protocol::Span;
+
+    fn count_kdl(query: &str, input: &str) -> Value {
+        let call = EvaluatedCall::new(Span::test_data()).with_positional(Value::test_string(query));
+        count(&KDL, &call, &Value::test_string(input)).unwrap()
+    }
+
+    const LAYOUT: &str = "tab {\n    pane focus=#true\n    pane { pane focus=#true; }\n}\ntab { pane focus=#true; pane }\n";
+
+    #[test]
+    fn test_count() {
+        assert_eq!(count_kdl("pane[focus=#true]", LAYOUT), Value::test_int(3));
+        assert_eq!(count_kdl("tab > pane", LAYOUT), Value::test_int(4));
+    }
+
+    #[test]
+    fn test_count_no_match() {
+        assert_eq!(count_kdl("window", LAYOUT), Value::test_int(0));
+    }
+}
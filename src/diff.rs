@@ -0,0 +1,215 @@
+use std::path::Path;
+
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{record, Category, LabeledError, PipelineData, Signature, Span, Spanned, SyntaxShape, Type, Value};
+
+use kdl::{KdlEntry, KdlNode};
+
+use crate::{error::KdlPluginError, from, read_file, KDL};
+
+pub struct KdlDiff;
+
+impl PluginCommand for KdlDiff {
+    type Plugin = KDL;
+
+    fn name(&self) -> &str {
+        "kdl diff"
+    }
+
+    fn description(&self) -> &str {
+        "Compare a KDL document with another one, node by node"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .input_output_type(Type::String, Type::table())
+            .required_named("against", SyntaxShape::Filepath, "The KDL file to compare the input with", Some('a'))
+            .switch("v1", "Force parsing as KDL v1 only", Some('1'))
+            .switch("v1-fallback", "Try KDL v2, fall back to v1 if parsing fails", None)
+            .category(Category::Experimental)
+    }
+
+    fn run(
+        &self,
+        plugin: &KDL,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let value = input.into_value(call.head)?;
+
+        let against: Spanned<String> = call.get_flag("against")?.ok_or(KdlPluginError::MissingFlag {
+            name: "against",
+            span: call.head,
+        })?;
+        let contents = read_file(Path::new(&engine.get_current_dir()?), &against)?;
+
+        let result = diff(plugin, call, &value, &Value::string(contents, against.span))?;
+        Ok(PipelineData::Value(result, None))
+    }
+}
+
+/// Lists the nodes `input` adds, removes or changes compared to `against`, as a table with the
+/// columns `change`, `path`, `old` and `new`.
+///
+/// Nodes are matched by name and rank among their siblings of the same name, the first `pane` of
+/// a block is at `pane`, the second one at `pane#1` and so on, and paths join the names of the
+/// parents with dots.
+pub(crate) fn diff(plugin: &KDL, call: &EvaluatedCall, input: &Value, against: &Value) -> Result<Value, LabeledError> {
+    let (old, _) = plugin.parse(call, against)?;
+    let (new, _) = plugin.parse(call, input)?;
+
+    let mut changes = Vec::new();
+    diff_nodes(old.nodes(), new.nodes(), "", call.head, &mut changes);
+
+    Ok(Value::list(changes, call.head))
+}
+
+fn diff_nodes(old: &[KdlNode], new: &[KdlNode], parent: &str, span: Span, changes: &mut Vec<Value>) {
+    let old = keyed(old, parent);
+    let new = keyed(new, parent);
+
+    for (path, old_node) in &old {
+        match new.iter().find(|(p, _)| p == path) {
+            None => changes.push(change("removed", path, convert(old_node), Value::nothing(span), span)),
+            Some((_, new_node)) => {
+                // annotations are only kept when the ones of the nodes differ, for the change to show
+                let same_type = old_node.ty().map(|ty| ty.value()) == new_node.ty().map(|ty| ty.value());
+                if !same_type || !same_entries(old_node.entries(), new_node.entries()) {
+                    let old_entries = convert_with_type(&without_children(old_node), !same_type);
+                    let new_entries = convert_with_type(&without_children(new_node), !same_type);
+                    changes.push(change("changed", path, old_entries, new_entries, span));
+                }
+                diff_nodes(children(old_node), children(new_node), path, span, changes);
+            }
+        }
+    }
+
+    for (path, new_node) in &new {
+        if !old.iter().any(|(p, _)| p == path) {
+            changes.push(change("added", path, Value::nothing(span), convert(new_node), span));
+        }
+    }
+}
+
+/// Gives each node its path, suffixing the name with `#n` for the n-th repetition of a name.
+pub(crate) fn keyed<'a>(nodes: &'a [KdlNode], parent: &str) -> Vec<(String, &'a KdlNode)> {
+    let mut keyed: Vec<(String, &KdlNode)> = Vec::with_capacity(nodes.len());
+
+    for (i, node) in nodes.iter().enumerate() {
+        let name = node.name().value();
+        let rank = nodes[..i].iter().filter(|n| n.name().value() == name).count();
+
+        let key = if rank == 0 { name.to_string() } else { format!("{}#{}", name, rank) };
+        let path = if parent.is_empty() { key } else { format!("{}.{}", parent, key) };
+
+        keyed.push((path, node));
+    }
+
+    keyed
+}
+
+/// Compares entries by meaning only, `1` and `0x1` or `"a"` and `a` are the same.
+fn same_entries(old: &[KdlEntry], new: &[KdlEntry]) -> bool {
+    old.len() == new.len()
+        && old.iter().zip(new).all(|(o, n)| {
+            o.value() == n.value()
+                && o.name().map(|i| i.value()) == n.name().map(|i| i.value())
+                && o.ty().map(|i| i.value()) == n.ty().map(|i| i.value())
+        })
+}
+
+fn children(node: &KdlNode) -> &[KdlNode] {
+    node.children().map(|c| c.nodes()).unwrap_or_default()
+}
+
+fn without_children(node: &KdlNode) -> KdlNode {
+    let mut node = node.clone();
+    node.clear_children();
+    node
+}
+
+fn convert(node: &KdlNode) -> Value {
+    convert_with_type(node, false)
+}
+
+/// Converts a node, keeping its annotation and the ones of its entries as `$node_type` and
+/// `{$type, $value}` when `keep_annotations`.
+fn convert_with_type(node: &KdlNode, keep_annotations: bool) -> Value {
+    from::parse_node(node, &from::ParseOptions { keep_annotations, ..Default::default() })
+}
+
+fn change(change: &str, path: &str, old: Value, new: Value, span: Span) -> Value {
+    Value::record(
+        record! {
+            "change" => Value::string(change, span),
+            "path" => Value::string(path, span),
+            "old" => old,
+            "new" => new,
+        },
+        span,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff_kdl(old: &str, new: &str) -> Vec<Value> {
+        let call = EvaluatedCall::new(Span::test_data());
+        let changes = diff(&KDL, &call, &Value::test_string(new), &Value::test_string(old)).unwrap();
+        changes.into_list().unwrap()
+    }
+
+    fn column(change: &Value, name: &str) -> Value {
+        change.get_data_by_key(name).unwrap()
+    }
+
+    #[test]
+    fn test_diff_added_node() {
+        let changes = diff_kdl("a 1", "a 1\nb 2");
+
+        assert_eq!(changes.len(), 1);
+        assert_eq!(column(&changes[0], "change"), Value::test_string("added"));
+        assert_eq!(column(&changes[0], "path"), Value::test_string("b"));
+        assert_eq!(column(&changes[0], "new"), Value::test_int(2));
+    }
+
+    #[test]
+    fn test_diff_removed_node() {
+        let changes = diff_kdl("server { host x; port 80 }", "server { host x }");
+
+        assert_eq!(changes.len(), 1);
+        assert_eq!(column(&changes[0], "change"), Value::test_string("removed"));
+        assert_eq!(column(&changes[0], "path"), Value::test_string("server.port"));
+        assert_eq!(column(&changes[0], "old"), Value::test_int(80));
+    }
+
+    #[test]
+    fn test_diff_changed_property() {
+        let changes = diff_kdl("pane size=1\npane size=2", "pane size=1\npane size=3");
+
+        assert_eq!(changes.len(), 1);
+        assert_eq!(column(&changes[0], "change"), Value::test_string("changed"));
+        assert_eq!(column(&changes[0], "path"), Value::test_string("pane#1"));
+        assert_eq!(column(&changes[0], "old").get_data_by_key("size").unwrap(), Value::test_int(2));
+        assert_eq!(column(&changes[0], "new").get_data_by_key("size").unwrap(), Value::test_int(3));
+    }
+
+    #[test]
+    fn test_diff_changed_node_type() {
+        let changes = diff_kdl("(a)node 1 { child 2; }", "node 1 { child 2; }");
+
+        assert_eq!(changes.len(), 1);
+        assert_eq!(column(&changes[0], "change"), Value::test_string("changed"));
+        assert_eq!(column(&changes[0], "path"), Value::test_string("node"));
+        assert_eq!(column(&changes[0], "old").get_data_by_key("$node_type").unwrap(), Value::test_string("a"));
+        assert_eq!(column(&changes[0], "new"), Value::test_int(1));
+        assert!(diff_kdl("(a)node 1", "(a)node 1").is_empty());
+    }
+
+    #[test]
+    fn test_diff_ignores_formatting() {
+        assert!(diff_kdl("a 0x10 \"b\"", "a 16 b").is_empty());
+    }
+}
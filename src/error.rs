@@ -0,0 +1,278 @@
+use std::{fmt, path::PathBuf};
+
+use nu_protocol::{LabeledError, Span, Type};
+
+/// The errors of the plugin, turned into a [`LabeledError`] with a consistent message and label
+/// when given back to Nushell.
+#[derive(Debug)]
+pub(crate) enum KdlPluginError {
+    /// The input of a command reading KDL isn't a string.
+    NotAString { ty: Type, span: Span },
+    /// Binary input which isn't UTF-8 text, `offset` being where the first invalid byte is.
+    InvalidUtf8 { offset: usize, span: Span },
+    /// A value which has no KDL representation.
+    UnsupportedValue { ty: Type, span: Span },
+    /// The `$type` of an explicitly annotated value which isn't a string.
+    InvalidAnnotation { ty: Type, span: Span },
+    /// A record used as a property, which should have exactly one key.
+    InvalidProperty { len: usize, span: Span },
+    /// The input isn't a valid KDL document in the given version(s), e.g. `v2` or `v2 and v1`.
+    ParseFailed { version: &'static str, error: kdl::KdlError, span: Span },
+    /// A flag the command can't do without.
+    MissingFlag { name: &'static str, span: Span },
+    /// A file which could not be read.
+    ReadFailed { path: PathBuf, error: std::io::Error, span: Span },
+    /// A query which isn't valid, see [`crate::query::Query`].
+    InvalidQuery { reason: String, span: Span },
+    /// A query which had to match at least one node.
+    NoMatch { query: String, span: Span },
+    /// A glob pattern which isn't valid.
+    InvalidPattern { error: nu_glob::PatternError, span: Span },
+    /// The `$radix` of an integer which isn't one KDL has.
+    InvalidRadix { radix: String, span: Span },
+    /// The `$bignum` of an integer which isn't the digits of a KDL integer.
+    InvalidBignum { digits: String, span: Span },
+    /// A `${name}` placeholder of `kdl template --strict` without a value.
+    UnresolvedPlaceholder { name: String, span: Span },
+    /// A flag given a value which isn't one of its choices, e.g. `error, quote or replace`.
+    InvalidChoice { flag: &'static str, value: String, choices: &'static str, span: Span },
+    /// A node or property name with spaces, for `to kdl --identifier-policy error`.
+    SpaceInIdentifier { name: String, span: Span },
+    /// The input of `kdl from-json` isn't valid JSON.
+    InvalidJson { error: serde_json::Error, span: Span },
+    /// The record keys which can't be KDL names, even quoted, for `to kdl --strict-identifiers`.
+    InvalidIdentifiers { names: Vec<String>, span: Span },
+    /// A value with more levels of records and lists than `to kdl --max-depth`.
+    TooDeep { max_depth: usize, span: Span },
+    /// A NaN or infinite float given to `to kdl --v1`, which KDL v1 has no way to write.
+    NonFiniteInV1 { value: f64, span: Span },
+    /// The `$order` of a record, or one of its keys, which isn't a list of strings.
+    InvalidOrder { ty: Type, span: Span },
+    /// An element of the document given to `to kdl --allow-duplicate-keys` which isn't a
+    /// `[key, value]` pair.
+    InvalidPair { ty: Type, span: Span },
+    /// A dotted path of `kdl patch` which can't be set, see [`crate::patch::patch`].
+    InvalidPatch { path: String, reason: String, span: Span },
+    /// A value of `from kdl --schema` which isn't of the type its schema says, and can't be
+    /// converted to it.
+    SchemaMismatch { path: String, expected: String, found: Type, span: Span },
+    /// A node `from kdl --schema` requires which isn't in the document.
+    MissingField { path: String, span: Span },
+    /// A `from kdl --schema` which isn't made of type names, records and lists of one type.
+    InvalidSchema { span: Span },
+    /// A type annotation `from kdl --error-on-unknown-annotation` doesn't allow.
+    UnknownAnnotation { name: String, span: Span },
+}
+
+impl fmt::Display for KdlPluginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotAString { .. } => write!(f, "input is not a string"),
+            Self::InvalidUtf8 { .. } => write!(f, "input is not valid UTF-8"),
+            Self::UnsupportedValue { .. } => {
+                write!(f, "value not supported, expected string, int, float, bool, duration or null")
+            }
+            Self::InvalidAnnotation { .. } => write!(f, "type annotation should be a string"),
+            Self::InvalidProperty { .. } => write!(f, "entry should be either a record with one key"),
+            Self::ParseFailed { version, error, .. } => write!(f, "invalid KDL {} format: {}", version, error),
+            Self::MissingFlag { name, .. } => write!(f, "missing --{}", name),
+            Self::ReadFailed { path, error, .. } => write!(f, "could not read {}: {}", path.display(), error),
+            Self::InvalidQuery { reason, .. } => write!(f, "invalid query: {}", reason),
+            Self::NoMatch { query, .. } => write!(f, "no node matches `{}`", query),
+            Self::InvalidPattern { error, .. } => write!(f, "invalid glob pattern: {}", error.msg),
+            Self::InvalidRadix { radix, .. } => write!(f, "invalid radix `{}`", radix),
+            Self::InvalidBignum { digits, .. } => write!(f, "invalid $bignum `{}`", digits),
+            Self::UnresolvedPlaceholder { name, .. } => write!(f, "unresolved placeholder `${{{}}}`", name),
+            Self::InvalidChoice { flag, value, .. } => write!(f, "invalid --{} `{}`", flag, value),
+            Self::SpaceInIdentifier { name, .. } => write!(f, "`{}` has spaces", name),
+            Self::InvalidJson { error, .. } => write!(f, "invalid JSON: {}", error),
+            Self::InvalidIdentifiers { names, .. } => {
+                let names: Vec<String> = names.iter().map(|name| format!("`{}`", name.escape_debug())).collect();
+                write!(f, "invalid identifiers: {}", names.join(", "))
+            }
+            Self::TooDeep { max_depth, .. } => write!(f, "more than {} levels of records and lists", max_depth),
+            Self::NonFiniteInV1 { value, .. } => write!(f, "KDL v1 can't write {}", value),
+            Self::InvalidOrder { .. } => write!(f, "$order should be a list of keys"),
+            Self::InvalidPair { .. } => write!(f, "invalid [key, value] pair"),
+            Self::InvalidPatch { path, reason, .. } => write!(f, "cannot patch `{}`: {}", path, reason),
+            Self::SchemaMismatch { path, expected, .. } => write!(f, "`{}` should be {}", path, expected),
+            Self::MissingField { path, .. } => write!(f, "missing `{}`", path),
+            Self::InvalidSchema { .. } => write!(f, "invalid --schema"),
+            Self::UnknownAnnotation { name, .. } => write!(f, "unknown annotation `({})`", name),
+        }
+    }
+}
+
+impl KdlPluginError {
+    /// What the kdl crate says about a parse failure, one line per issue with where it is, and a
+    /// hint at the flags for v1 documents.
+    fn help(&self) -> Option<String> {
+        let Self::ParseFailed { version, error, .. } = self else { return None };
+
+        let mut lines: Vec<String> = error
+            .diagnostics
+            .iter()
+            .map(|diagnostic| {
+                let before = error.input.get(..diagnostic.span.offset()).unwrap_or_default();
+                let line = before.matches('\n').count() + 1;
+                let column = before.rsplit('\n').next().unwrap_or_default().chars().count() + 1;
+
+                let mut text = format!("line {}, column {}: {}", line, column, diagnostic.message.as_deref().unwrap_or("invalid KDL"));
+                if let Some(label) = &diagnostic.label {
+                    text.push_str(&format!(" ({})", label));
+                }
+                if let Some(help) = &diagnostic.help {
+                    text.push_str(&format!(", {}", help));
+                }
+                text
+            })
+            .collect();
+
+        if *version == "v2" && kdl::KdlDocument::parse_v1(&error.input).is_ok() {
+            lines.push("this looks like KDL v1, try --v1 or --v1-fallback".to_string());
+        }
+
+        (!lines.is_empty()).then(|| lines.join("\n"))
+    }
+
+    fn label(&self) -> (String, Span) {
+        match self {
+            Self::NotAString { ty, span } => (format!("expected a string, found {}", ty), *span),
+            Self::InvalidUtf8 { offset, span } => (format!("invalid byte at offset {}", offset), *span),
+            Self::UnsupportedValue { ty, span } => (format!("{} can't be converted to KDL", ty), *span),
+            Self::InvalidAnnotation { ty, span } => (format!("expected a string, found {}", ty), *span),
+            Self::InvalidProperty { len, span } => (format!("this record has {} keys", len), *span),
+            Self::ParseFailed { version, span, .. } => (format!("not a valid KDL {} document", version), *span),
+            Self::MissingFlag { name, span } => (format!("requires --{}", name), *span),
+            Self::ReadFailed { span, .. } => ("could not be read".to_string(), *span),
+            Self::InvalidQuery { span, .. } => ("not a valid query".to_string(), *span),
+            Self::NoMatch { span, .. } => ("matches no node".to_string(), *span),
+            Self::InvalidPattern { error, span } => (format!("error near character {}", error.pos), *span),
+            Self::InvalidRadix { span, .. } => ("expected hex, oct or bin".to_string(), *span),
+            Self::InvalidBignum { span, .. } => ("expected the digits of a KDL integer".to_string(), *span),
+            Self::UnresolvedPlaceholder { name, span } => (format!("no `{}` in the substitutions", name), *span),
+            Self::InvalidChoice { choices, span, .. } => (format!("expected {}", choices), *span),
+            Self::SpaceInIdentifier { span, .. } => ("not a valid name with --identifier-policy error".to_string(), *span),
+            Self::InvalidJson { span, .. } => ("not valid JSON".to_string(), *span),
+            Self::TooDeep { span, .. } => ("nested too deep, see --max-depth".to_string(), *span),
+            Self::NonFiniteInV1 { span, .. } => ("not a finite number, which only KDL v2 has".to_string(), *span),
+            Self::InvalidIdentifiers { names, span } => (format!("{} names with characters KDL doesn't allow", names.len()), *span),
+            Self::InvalidOrder { ty, span } => (format!("expected a string, found {}", ty), *span),
+            Self::InvalidPair { ty, span } => (format!("expected a list of a string and a value, found {}", ty), *span),
+            Self::InvalidPatch { span, .. } => ("see kdl flatten-paths for the paths of a document".to_string(), *span),
+            Self::SchemaMismatch { found, span, .. } => (format!("found {}", found), *span),
+            Self::MissingField { span, .. } => ("required by --schema".to_string(), *span),
+            Self::InvalidSchema { span } => {
+                ("expected int, float, string, bool, nothing, any, a record or a list of one type".to_string(), *span)
+            }
+            Self::UnknownAnnotation { span, .. } => ("not a known annotation, see --allowed-annotations".to_string(), *span),
+        }
+    }
+}
+
+impl From<KdlPluginError> for LabeledError {
+    fn from(error: KdlPluginError) -> Self {
+        let (text, span) = error.label();
+        let labeled = LabeledError::new(error.to_string()).with_label(text, span);
+        match error.help() {
+            Some(help) => labeled.with_help(help),
+            None => labeled,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labeled(error: KdlPluginError) -> LabeledError {
+        error.into()
+    }
+
+    #[test]
+    fn test_messages_and_labels() {
+        let span = Span::new(1, 2);
+        let string = |s: &str| s.to_string();
+        let cases = [
+            (KdlPluginError::NotAString { ty: Type::Int, span }, "input is not a string", "expected a string, found int"),
+            (KdlPluginError::InvalidUtf8 { offset: 3, span }, "input is not valid UTF-8", "invalid byte at offset 3"),
+            (
+                KdlPluginError::UnsupportedValue { ty: Type::Filesize, span },
+                "value not supported, expected string, int, float, bool, duration or null",
+                "filesize can't be converted to KDL",
+            ),
+            (KdlPluginError::InvalidAnnotation { ty: Type::Int, span }, "type annotation should be a string", "expected a string, found int"),
+            (KdlPluginError::InvalidProperty { len: 3, span }, "entry should be either a record with one key", "this record has 3 keys"),
+            (KdlPluginError::MissingFlag { name: "against", span }, "missing --against", "requires --against"),
+            (
+                KdlPluginError::ReadFailed { path: PathBuf::from("missing.kdl"), error: std::io::Error::new(std::io::ErrorKind::NotFound, "not found"), span },
+                "could not read missing.kdl: not found",
+                "could not be read",
+            ),
+            (
+                KdlPluginError::InvalidPattern { error: nu_glob::Pattern::new("[a").unwrap_err(), span },
+                "invalid glob pattern: invalid range pattern",
+                "error near character 0",
+            ),
+            (KdlPluginError::InvalidRadix { radix: string("dec"), span }, "invalid radix `dec`", "expected hex, oct or bin"),
+            (KdlPluginError::InvalidBignum { digits: string("12a"), span }, "invalid $bignum `12a`", "expected the digits of a KDL integer"),
+            (
+                KdlPluginError::InvalidChoice { flag: "identifier-policy", value: string("drop"), choices: "error, quote or replace", span },
+                "invalid --identifier-policy `drop`",
+                "expected error, quote or replace",
+            ),
+            (
+                KdlPluginError::InvalidJson { error: serde_json::from_str::<serde_json::Value>("[1,").unwrap_err(), span },
+                "invalid JSON: EOF while parsing a value at line 1 column 3",
+                "not valid JSON",
+            ),
+            (
+                KdlPluginError::InvalidIdentifiers { names: vec![string("a\u{1}"), string("b\u{feff}")], span },
+                "invalid identifiers: `a\\u{1}`, `b\\u{feff}`",
+                "2 names with characters KDL doesn't allow",
+            ),
+            (KdlPluginError::InvalidOrder { ty: Type::Int, span }, "$order should be a list of keys", "expected a string, found int"),
+            (KdlPluginError::NonFiniteInV1 { value: f64::NAN, span }, "KDL v1 can't write NaN", "not a finite number, which only KDL v2 has"),
+            (
+                KdlPluginError::InvalidSchema { span },
+                "invalid --schema",
+                "expected int, float, string, bool, nothing, any, a record or a list of one type",
+            ),
+            (
+                KdlPluginError::UnknownAnnotation { name: string("secret"), span },
+                "unknown annotation `(secret)`",
+                "not a known annotation, see --allowed-annotations",
+            ),
+        ];
+
+        for (error, msg, label) in cases {
+            let error = labeled(error);
+            assert_eq!(error.msg, msg);
+            assert_eq!(error.labels[0].text, label, "{}", msg);
+            assert_eq!(error.labels[0].span, span, "{}", msg);
+        }
+    }
+
+    #[test]
+    fn test_parse_failed() {
+        let error = "node {".parse::<kdl::KdlDocument>().unwrap_err();
+        let error = labeled(KdlPluginError::ParseFailed { version: "v2", error, span: Span::new(1, 2) });
+
+        assert_eq!(error.msg, "invalid KDL v2 format: Failed to parse KDL document");
+        assert_eq!(error.labels[0].text, "not a valid KDL v2 document");
+        assert_eq!(error.help.unwrap(), "line 1, column 6: No closing '}' for child block (not closed)");
+    }
+
+    #[test]
+    fn test_parse_failed_v1_hint() {
+        let error = "title \"KDL\"\nnode true null".parse::<kdl::KdlDocument>().unwrap_err();
+        let error = labeled(KdlPluginError::ParseFailed { version: "v2", error, span: Span::new(1, 2) });
+
+        assert_eq!(
+            error.help.unwrap(),
+            "line 2, column 6: Expected identifier string (not identifier string)\n\
+             line 2, column 11: Expected identifier string (not identifier string)\n\
+             this looks like KDL v1, try --v1 or --v1-fallback"
+        );
+    }
+}
@@ -0,0 +1,83 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{Category, LabeledError, PipelineData, Signature, Spanned, SyntaxShape, Type, Value};
+
+use crate::{
+    query::{self, Query},
+    KDL,
+};
+
+pub struct KdlExists;
+
+impl PluginCommand for KdlExists {
+    type Plugin = KDL;
+
+    fn name(&self) -> &str {
+        "kdl exists"
+    }
+
+    fn description(&self) -> &str {
+        "Tell whether any node matches a query, stopping at the first one"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .input_output_type(Type::String, Type::Bool)
+            .required("query", SyntaxShape::String, "The query of the nodes, e.g. \"pane[focus=#true]\"")
+            .named("case", SyntaxShape::String, "How to match the names of nodes and properties, sensitive (default) or insensitive", None)
+            .switch("v1", "Force parsing as KDL v1 only", Some('1'))
+            .switch("v1-fallback", "Try KDL v2, fall back to v1 if parsing fails", None)
+            .category(Category::Experimental)
+    }
+
+    fn run(
+        &self,
+        plugin: &KDL,
+        _engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let value = input.into_value(call.head)?;
+        let result = exists(plugin, call, &value)?;
+        Ok(PipelineData::Value(result, None))
+    }
+}
+
+/// Tells whether any node of the document matches the query given as the first positional
+/// argument.
+pub(crate) fn exists(plugin: &KDL, call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let at: Spanned<String> = call.req(0)?;
+
+    let query = Query::parse_arg(&at)?.with_case(query::case(call)?);
+    let (document, _) = plugin.parse(call, input)?;
+
+    Ok(Value::bool(query.matches_any(&document), call.head))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nu_protocol::Span;
+
+    fn exists_kdl(query: &str, input: &str) -> Result<Value, LabeledError> {
+        let call = EvaluatedCall::new(Span::test_data()).with_positional(Value::test_string(query));
+        exists(&KDL, &call, &Value::test_string(input))
+    }
+
+    const LAYOUT: &str = "tab {\n    pane\n    pane { pane focus=#true; }\n}\n";
+
+    #[test]
+    fn test_exists() {
+        assert_eq!(exists_kdl("pane[focus=#true]", LAYOUT).unwrap(), Value::test_bool(true));
+        assert_eq!(exists_kdl("tab > pane[focus]", LAYOUT).unwrap(), Value::test_bool(false));
+        assert_eq!(exists_kdl("window", LAYOUT).unwrap(), Value::test_bool(false));
+    }
+
+    #[test]
+    fn test_exists_invalid_query() {
+        let error = exists_kdl("tab >", LAYOUT).unwrap_err();
+
+        assert_eq!(error.msg, "invalid query: `>` should be between two nodes");
+        assert_eq!(error.labels[0].text, "not a valid query");
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{Category, LabeledError, PipelineData, Record, Signature, Span, Type, Value};
+
+use kdl::KdlNode;
+
+use crate::{from, KDL};
+
+pub struct KdlFlattenPaths;
+
+impl PluginCommand for KdlFlattenPaths {
+    type Plugin = KDL;
+
+    fn name(&self) -> &str {
+        "kdl flatten-paths"
+    }
+
+    fn description(&self) -> &str {
+        "Turn a KDL document into a flat record of dotted paths to values, e.g. for env vars"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .input_output_type(Type::String, Type::record())
+            .switch("v1", "Force parsing as KDL v1 only", Some('1'))
+            .switch("v1-fallback", "Try KDL v2, fall back to v1 if parsing fails", None)
+            .category(Category::Experimental)
+    }
+
+    fn run(
+        &self,
+        plugin: &KDL,
+        _engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let value = input.into_value(call.head)?;
+        let result = flatten_paths(plugin, call, &value)?;
+        Ok(PipelineData::Value(result, None))
+    }
+}
+
+/// Gives a record with the dotted path of every value of the document, in document order:
+/// `server.port` for the arguments of a `port` node in `server`, a list when it has several, and
+/// `server.tls` for its `tls` property, or `server.tls#1` when it also has a `tls` child. The nodes whose name is repeated among their siblings get
+/// their index in the path, e.g. `server.routes.0.path`, and the nodes without any entry nor
+/// children are null.
+pub(crate) fn flatten_paths(plugin: &KDL, call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let (document, _) = plugin.parse(call, input)?;
+
+    let mut record = Record::new();
+    collect(document.nodes(), "", call.head, &mut record);
+
+    Ok(Value::record(record, call.head))
+}
+
+fn collect(nodes: &[KdlNode], parent: &str, span: Span, record: &mut Record) {
+    let options = from::ParseOptions::default();
+
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for node in nodes {
+        *counts.entry(node.name().value()).or_default() += 1;
+    }
+
+    let mut ranks: HashMap<&str, usize> = HashMap::new();
+    for node in nodes {
+        let name = node.name().value();
+        let mut path = if parent.is_empty() { name.to_string() } else { format!("{}.{}", parent, name) };
+        if counts[name] > 1 {
+            let rank = ranks.entry(name).or_default();
+            path = format!("{}.{}", path, rank);
+            *rank += 1;
+        }
+
+        let children = node.children().map_or(&[][..], |children| children.nodes());
+        let mut args: Vec<Value> = Vec::new();
+        let mut props = Vec::new();
+        for entry in node.entries() {
+            match entry.name() {
+                Some(prop) => {
+                    // a property called like a child gets a `#1` suffix rather than being
+                    // overwritten by the paths of the child, which kdl patch picks first
+                    let name = prop.value();
+                    let clashes = children.iter().any(|child| child.name().value() == name);
+                    let key = if clashes { format!("{}.{}#1", path, name) } else { format!("{}.{}", path, name) };
+                    props.push((key, from::parse_value(entry, &options)));
+                }
+                None => args.push(from::parse_value(entry, &options)),
+            }
+        }
+
+        match args.len() {
+            0 if props.is_empty() && node.children().is_none() => record.insert(path.clone(), Value::nothing(span)),
+            0 => None,
+            1 => record.insert(path.clone(), args.pop().unwrap()),
+            _ => record.insert(path.clone(), Value::list(args, span)),
+        };
+        for (key, value) in props {
+            record.insert(key, value);
+        }
+        collect(children, &path, span, record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flatten_kdl(input: &str) -> Record {
+        let output = flatten_paths(&KDL, &EvaluatedCall::new(Span::test_data()), &Value::test_string(input)).unwrap();
+        output.into_record().unwrap()
+    }
+
+    #[test]
+    fn test_flatten_paths() {
+        let output = flatten_kdl("server main tls=#true {\n    host x\n    port 8080\n    features a b\n}\nenabled");
+
+        assert_eq!(output.get("server").unwrap(), &Value::test_string("main"));
+        assert_eq!(output.get("server.tls").unwrap(), &Value::test_bool(true));
+        assert_eq!(output.get("server.host").unwrap(), &Value::test_string("x"));
+        assert_eq!(output.get("server.port").unwrap(), &Value::test_int(8080));
+        assert_eq!(output.get("server.features").unwrap(), &Value::test_list(vec![Value::test_string("a"), Value::test_string("b")]));
+        assert_eq!(output.get("enabled").unwrap(), &Value::test_nothing());
+    }
+
+    #[test]
+    fn test_repeated_nodes() {
+        let output = flatten_kdl(r#"server { routes { path "/a" }; routes { path "/b" }; port 1 }"#);
+
+        assert_eq!(output.columns().collect::<Vec<_>>(), ["server.routes.0.path", "server.routes.1.path", "server.port"]);
+        assert_eq!(output.get("server.routes.1.path").unwrap(), &Value::test_string("/b"));
+    }
+
+    #[test]
+    fn test_property_called_like_a_child() {
+        let output = flatten_kdl("server tls=#true { tls cert }");
+
+        assert_eq!(output.columns().collect::<Vec<_>>(), ["server.tls#1", "server.tls"]);
+        assert_eq!(output.get("server.tls#1").unwrap(), &Value::test_bool(true));
+        assert_eq!(output.get("server.tls").unwrap(), &Value::test_string("cert"));
+    }
+}
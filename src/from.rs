@@ -1,86 +1,1443 @@
-use nu_protocol::{Record, Span, Value};
+use std::collections::{HashMap, HashSet};
 
-use kdl::{KdlDocument, KdlEntry, KdlNode, KdlValue};
+use nu_protocol::{record, Record, Span, Value};
 
-pub(crate) fn parse_document(document: &KdlDocument) -> Value {
-    let mut record = Record::new();
+use kdl::{KdlDocument, KdlEntry, KdlIdentifier, KdlNode, KdlValue};
+use miette::SourceSpan;
+use nu_glob::Pattern;
+
+use crate::diff;
+use crate::error::KdlPluginError;
+use crate::to::{BIGNUM_KEY, NODE_TYPE_KEY, REPR_KEY, TYPE_KEY, VALUE_KEY};
+
+/// The key holding the entries of a node which has both entries and children.
+pub(crate) const ENTRIES_KEY: &str = "entries";
+/// The key holding the children of a node which has both entries and children.
+pub(crate) const CHILDREN_KEY: &str = "children";
+
+/// The key of the positional arguments of a node in the `--flatten` shape.
+pub(crate) const ARGS_KEY: &str = "args";
+/// The key of the properties of a node in the `--flatten` shape.
+pub(crate) const PROPS_KEY: &str = "props";
+
+/// The columns of the entries of a node in the `--ordered-entries` shape, `name` being null for
+/// arguments.
+pub(crate) const ENTRY_NAME_KEY: &str = "name";
+pub(crate) const ENTRY_VALUE_KEY: &str = "value";
+
+/// The key of the shape of a node's value with `--tag-shape`: `scalar`, `list`, `record`, or
+/// `node` for `{entries, children}`.
+pub(crate) const SHAPE_KEY: &str = "__shape";
+
+/// What the children of a node deeper than `--depth` are replaced with.
+pub(crate) const TRUNCATED: &str = "…";
+
+/// Options of `from kdl` which change how a KDL document is turned into a nu value.
+pub(crate) struct ParseOptions {
+    /// Trim leading and trailing whitespace from string values, never from identifiers.
+    pub trim_whitespace: bool,
+    /// Keep numbers as the exact text they are written with, for consumers which need more
+    /// precision than nu's 64-bit integers and floats.
+    pub numbers_as_strings: bool,
+    /// The key of the entries of a node which has children too, to change when a child node is
+    /// itself called `entries`.
+    pub entries_key: String,
+    /// The key of the children of a node which has entries too, to change when a child node is
+    /// itself called `children`.
+    pub children_key: String,
+    /// Turn every node into `{args, props, children}` instead of collapsing it to the simplest
+    /// value.
+    pub flatten: bool,
+    /// Like `flatten`, but with `args`, `props` and `children` always there, empty when the node
+    /// has none, so every node has the same schema.
+    pub uniform: bool,
+    /// Keep the source text of numbers which `to kdl` would write differently, e.g. `1.50` or
+    /// `+5`, as `{$value: 1.5, $repr: "1.50"}`, and the digits of the integers too big for nu as
+    /// `{$bignum: "99999999999999999999"}`.
+    pub preserve_number_repr: bool,
+    /// Keep the type annotations of values as `{$type, $value}`, as `flatten` does, rather than
+    /// converting the values annotated with a nu type and dropping the others, and the ones of
+    /// nodes as a `$node_type` field.
+    pub keep_annotations: bool,
+    /// Turn every integer into a float, which is exact only up to 2^53.
+    pub ints_as_floats: bool,
+    /// Put the arguments of every node with entries in a list under this key of a record, next
+    /// to its properties, instead of collapsing them into a single value or list.
+    pub args_key: Option<String>,
+    /// Like `flatten`, but with the arguments and properties in a single `entries` table with the
+    /// columns `name` and `value`, in the order they are written in.
+    pub ordered_entries: bool,
+    /// Give the nodes with properties the `{args, props, children}` shape of `flatten`, `args`
+    /// being left out when empty, so a lone property can't be mistaken for a child node.
+    pub keep_props: bool,
+    /// Turn the properties of a node with children into keys of its children record, a child node
+    /// called like a property getting a `#1` suffix.
+    pub merge_props_into_children: bool,
+    /// Only keep the properties with one of these names, and the arguments.
+    pub fields: Option<Vec<String>>,
+    /// Only convert the top-level nodes whose name matches this glob.
+    pub node_filter: Option<Pattern>,
+    /// How many levels of children to convert below the top-level nodes, the deeper ones being
+    /// replaced with [`TRUNCATED`], all of them when `None`.
+    pub depth: Option<usize>,
+    /// The keys to give the nodes of these names instead, e.g. `panel` for `pane`, the nodes of
+    /// two names given the same key being put in a list.
+    pub rename: HashMap<String, String>,
+    /// Put every node and entry in a `{value, span: {start, end}}` record with the byte offsets
+    /// of its source.
+    pub with_spans: bool,
+    /// Prefix the keys of properties in the records of collapsed nodes, e.g. `@port` for `port`
+    /// with `@`, so they can't be mistaken for child nodes.
+    pub property_prefix: Option<String>,
+    /// Tag the value of every node with its shape under [`SHAPE_KEY`], the values which aren't
+    /// records being put in a `{__shape, value}` one.
+    pub tag_shape: bool,
+    /// Give the top-level nodes the key of their first argument rather than their name, e.g.
+    /// `{web: {...}}` for `resource web { ... }`, the nodes with the same one being put in a list.
+    pub key_by_first_arg: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            trim_whitespace: false,
+            numbers_as_strings: false,
+            entries_key: ENTRIES_KEY.to_string(),
+            children_key: CHILDREN_KEY.to_string(),
+            flatten: false,
+            uniform: false,
+            preserve_number_repr: false,
+            keep_annotations: false,
+            ints_as_floats: false,
+            args_key: None,
+            ordered_entries: false,
+            keep_props: false,
+            merge_props_into_children: false,
+            fields: None,
+            node_filter: None,
+            depth: None,
+            rename: HashMap::new(),
+            with_spans: false,
+            property_prefix: None,
+            tag_shape: false,
+            key_by_first_arg: false,
+        }
+    }
+}
+
+impl ParseOptions {
+    /// The key of a property in the record of a collapsed node.
+    fn property_key(&self, name: &str) -> String {
+        format!("{}{}", self.property_prefix.as_deref().unwrap_or_default(), name)
+    }
+
+    /// Whether annotated values keep their annotation, which is lossless.
+    fn keeps_annotations(&self) -> bool {
+        self.flatten || self.ordered_entries || self.keep_annotations
+    }
+}
+
+/// Both the kdl crate and Nushell count in bytes, so a span of the source maps as is. The lengths
+/// have to come from the span too, `len()` is the number of entries of a node and the length of
+/// the *formatted* document or entry, which isn't the source for e.g. v1 documents.
+fn span(source: SourceSpan) -> Span {
+    Span::new(source.offset(), source.offset() + source.len())
+}
+
+/// Converts a document parsed from a source of `source_len` bytes, which the span of the record is
+/// kept within: it's the span Nushell points at and kdl doesn't check it against the source.
+pub(crate) fn parse_document(document: &KdlDocument, source_len: usize, options: &ParseOptions) -> Value {
+    let value = parse_nodes(document, options, options.node_filter.as_ref(), options.key_by_first_arg, options.depth);
+
+    let Span { start, end } = value.span();
+    let start = start.min(source_len);
+    value.with_span(Span::new(start, end.clamp(start, source_len)))
+}
+
+pub(crate) fn parse_node(node: &KdlNode, options: &ParseOptions) -> Value {
+    parse_node_within(node, options, options.depth)
+}
+
+/// Converts the nodes of a document whose name matches `filter`, with `depth` levels of children
+/// left to convert below them, keyed by their first argument rather than their name when
+/// `by_first_arg` and they have one.
+fn parse_nodes(document: &KdlDocument, options: &ParseOptions, filter: Option<&Pattern>, by_first_arg: bool, depth: Option<usize>) -> Value {
+    let mut record = Record::with_capacity(document.nodes().len());
+    // the name of the nodes each key was given by, and the keys given by several names
+    let mut sources: HashMap<String, &str> = HashMap::new();
+    let mut collided: HashSet<String> = HashSet::new();
 
     for node in document.nodes() {
-        record.insert(node.name().to_string(), parse_node(node));
+        let name = node.name().value();
+        if filter.is_some_and(|pattern| !pattern.matches(name)) {
+            continue;
+        }
+        let (key, value) = match by_first_arg.then(|| node.entries().iter().position(|e| e.name().is_none())).flatten() {
+            Some(i) => {
+                // the rest of the node is converted as if the argument wasn't there
+                let mut rest = node.clone();
+                let arg = rest.entries_mut().remove(i);
+                let key = match arg.value() {
+                    KdlValue::String(string) => string.clone(),
+                    other => other.to_string(),
+                };
+                (key, parse_node_within(&rest, options, depth))
+            }
+            // the name without quotes, like the keys of properties
+            None => (options.rename.get(name).cloned().unwrap_or_else(|| name.to_string()), parse_node_within(node, options, depth)),
+        };
+
+        if collided.contains(&key) {
+            if let Some(Value::List { vals, .. }) = record.get_mut(&key) {
+                vals.push(value);
+            }
+        } else if sources.get(&key).is_some_and(|source| *source != name || by_first_arg) {
+            // in place, so the key keeps the place of its first node
+            let previous = record.get_mut(&key).unwrap();
+            let first = std::mem::replace(previous, Value::nothing(value.span()));
+            *previous = Value::list(vec![first, value], span(document.span()));
+            collided.insert(key);
+        } else {
+            sources.insert(key.clone(), name);
+            record.insert(key, value);
+        }
     }
 
-    let span = Span::new(
-        document.span().offset(),
-        document.span().offset() + document.len(),
-    );
+    let span = span(document.span());
 
     Value::record(record, span)
 }
 
-fn parse_node(node: &KdlNode) -> Value {
-    let entries: Vec<Value> = node.entries().iter().map(parse_entry).collect();
+fn parse_children(children: &KdlDocument, options: &ParseOptions, depth: Option<usize>) -> Value {
+    match depth {
+        Some(0) => Value::string(TRUNCATED, span(children.span())),
+        _ => parse_nodes(children, options, None, false, depth.map(|depth| depth - 1)),
+    }
+}
 
-    let span = Span::new(node.span().offset(), node.span().offset() + node.len());
+fn parse_node_within(node: &KdlNode, options: &ParseOptions, depth: Option<usize>) -> Value {
+    let value = tag_shape(node_value(node, options, depth), options);
+    let value = match node.ty() {
+        // like the annotations of values, e.g. `{$node_type: text, $value: hi}` for `(text)span hi`
+        Some(ty) if options.keeps_annotations() => {
+            let span = value.span();
+            match value {
+                Value::Record { val, .. } => {
+                    let mut record = Record::with_capacity(val.len() + 1);
+                    record.insert(NODE_TYPE_KEY, Value::string(ty.value(), span));
+                    record.extend(val.into_owned());
+                    Value::record(record, span)
+                }
+                value => Value::record(record! { NODE_TYPE_KEY => Value::string(ty.value(), span), VALUE_KEY => value }, span),
+            }
+        }
+        _ => value,
+    };
+    with_span(node.span(), value, options)
+}
+
+/// With `--tag-shape`, the value of a node with its shape, e.g. `{__shape: list, value: [1, 2]}`
+/// for `node 1 2` or `{__shape: record, port: 8080}` for `node port=8080`.
+fn tag_shape(value: Value, options: &ParseOptions) -> Value {
+    if !options.tag_shape {
+        return value;
+    }
+    let span = value.span();
+    let shape = match &value {
+        Value::Record { val, .. } if val.len() == 2 && val.contains(&options.entries_key) && val.contains(&options.children_key) => "node",
+        Value::Record { .. } => "record",
+        Value::List { .. } => "list",
+        _ => "scalar",
+    };
+
+    match value {
+        Value::Record { val, .. } => {
+            let mut record = Record::with_capacity(val.len() + 1);
+            record.insert(SHAPE_KEY, Value::string(shape, span));
+            record.extend(val.into_owned());
+            Value::record(record, span)
+        }
+        value => Value::record(record! { SHAPE_KEY => Value::string(shape, span), "value" => value }, span),
+    }
+}
+
+/// Gives the converted value of a document, or of children at `path`, the types of `--schema`:
+/// a record of the fields it must have, each one a type name (`int`, `float`, `string`, `bool`,
+/// `nothing` or `any`), a record for the children of a node, or a list of one type for the
+/// arguments of a node. Strings are parsed as the numbers and booleans they should be, and
+/// numbers and booleans written as the strings they should be.
+pub(crate) fn apply_schema(value: Value, schema: &Value, path: &str) -> Result<Value, KdlPluginError> {
+    let span = value.span();
+    let mismatch = |expected: &str, value: &Value| KdlPluginError::SchemaMismatch {
+        path: path.to_string(),
+        expected: expected.to_string(),
+        found: value.get_type(),
+        span: value.span(),
+    };
+
+    match schema {
+        Value::Record { val: fields, .. } => {
+            let Value::Record { val: record, .. } = value else { return Err(mismatch("record", &value)) };
+            let mut record = record.into_owned();
+            for (field, ty) in fields.iter() {
+                let field_path = if path.is_empty() { field.clone() } else { format!("{}.{}", path, field) };
+                let Some(field_value) = record.get_mut(field) else {
+                    return Err(KdlPluginError::MissingField { path: field_path, span });
+                };
+                let typed = apply_schema(std::mem::replace(field_value, Value::nothing(span)), ty, &field_path)?;
+                *field_value = typed;
+            }
+            Ok(Value::record(record, span))
+        }
+        Value::List { vals: types, .. } if types.len() == 1 => match value {
+            Value::List { vals, .. } => {
+                let vals = vals.into_iter().map(|val| apply_schema(val, &types[0], path)).collect::<Result<_, _>>()?;
+                Ok(Value::list(vals, span))
+            }
+            value => Err(mismatch("list", &value)),
+        },
+        Value::String { val: ty, .. } => match (ty.as_str(), &value) {
+            ("any", _) | ("int", Value::Int { .. }) | ("float", Value::Float { .. }) | ("string", Value::String { .. }) => Ok(value),
+            ("bool", Value::Bool { .. }) | ("nothing", Value::Nothing { .. }) => Ok(value),
+            ("int", Value::String { val, .. }) => val.parse().map(|i| Value::int(i, span)).map_err(|_| mismatch(ty, &value)),
+            ("float", Value::Int { val, .. }) => Ok(Value::float(*val as f64, span)),
+            ("float", Value::String { val, .. }) => val.parse().map(|f| Value::float(f, span)).map_err(|_| mismatch(ty, &value)),
+            ("bool", Value::String { val, .. }) => val.parse().map(|b| Value::bool(b, span)).map_err(|_| mismatch(ty, &value)),
+            ("string", Value::Int { .. } | Value::Float { .. } | Value::Bool { .. }) => {
+                Ok(Value::string(value.to_expanded_string("", &Default::default()), span))
+            }
+            ("int" | "float" | "string" | "bool" | "nothing", _) => Err(mismatch(ty, &value)),
+            _ => Err(KdlPluginError::InvalidSchema { span: schema.span() }),
+        },
+        _ => Err(KdlPluginError::InvalidSchema { span: schema.span() }),
+    }
+}
+
+/// With `--with-spans`, a value in a record with the byte offsets of its source, e.g.
+/// `{value: 8080, span: {start, end}}`, which stay there whatever the pipeline does.
+fn with_span(source: SourceSpan, value: Value, options: &ParseOptions) -> Value {
+    if !options.with_spans {
+        return value;
+    }
+    let span = value.span();
+    let offsets = record! {
+        "start" => Value::int(source.offset() as i64, span),
+        "end" => Value::int((source.offset() + source.len()) as i64, span),
+    };
+    Value::record(record! { "value" => value, "span" => Value::record(offsets, span) }, span)
+}
+
+fn node_value(node: &KdlNode, options: &ParseOptions, depth: Option<usize>) -> Value {
+    if options.flatten || options.uniform || options.ordered_entries || options.keep_props && kept_entries(node, options).any(|entry| entry.name().is_some()) {
+        return flatten_node(node, options, depth);
+    }
+
+    let mut entries: Vec<&KdlEntry> = kept_entries(node, options).collect();
+
+    let span = span(node.span());
 
     if let Some(children) = node.children() {
-        let children = parse_document(children);
+        let mut children = parse_children(children, options, depth);
 
-        if entries.is_empty() {
-            return children;
+        if options.merge_props_into_children && matches!(children, Value::Record { .. }) {
+            let children_span = children.span();
+            let mut record = Record::new();
+            entries.retain(|entry| match entry.name() {
+                Some(name) => {
+                    record.insert(options.property_key(name.value()), parse_value(entry, options));
+                    false
+                }
+                None => true,
+            });
+            for (name, child) in children.into_record().unwrap() {
+                // like the `#n` suffix of repeated names in `to kdl`, so the node keeps its name
+                let mut keys = (0..).map(|n| if n == 0 { name.clone() } else { format!("{}#{}", name, n) });
+                let key = keys.find(|key| !record.contains(key)).unwrap();
+                record.insert(key, child);
+            }
+            children = Value::record(record, children_span);
         }
 
-        let entries = if entries.len() == 1 {
-            entries[0].clone()
-        } else {
-            // FIXME: use a real span
-            Value::list(entries, Span::unknown())
-        };
+        if options.args_key.is_some() && !entries.is_empty() {
+            let mut record = Record::with_capacity(2);
+            record.insert(options.entries_key.clone(), args_and_props(&entries, options, span));
+            record.insert(options.children_key.clone(), children);
+            return Value::record(record, span);
+        }
+
+        // children which are just nodes called `entries` and `children` would be taken for the
+        // shape of a node with both, so they're nested in that shape with no entries
+        let structural = |children: &Record| children.len() == 2 && children.contains(&options.entries_key) && children.contains(&options.children_key);
+        if entries.is_empty() && !matches!(&children, Value::Record { val, .. } if structural(val)) {
+            return children;
+        }
 
-        let mut record = Record::new();
-        record.insert("entries".to_string(), entries);
-        record.insert("children".to_string(), children);
+        let mut record = Record::with_capacity(2);
+        record.insert(options.entries_key.clone(), entries_value(&entries, options, || Value::list(Vec::new(), span)));
+        record.insert(options.children_key.clone(), children);
         Value::record(record, span)
+    } else if options.args_key.is_some() && !entries.is_empty() {
+        args_and_props(&entries, options, span)
     } else {
-        if entries.is_empty() {
-            // FIXME: use a real span
-            Value::nothing(Span::unknown())
-        } else if entries.len() == 1 {
-            entries[0].clone()
-        } else {
-            // FIXME: use a real span
-            Value::list(entries, Span::unknown())
+        // FIXME: use a real span
+        entries_value(&entries, options, || Value::nothing(Span::unknown()))
+    }
+}
+
+/// The value of the entries of a node: `empty` when it has none, its lone entry, or a list of
+/// them. Most nodes have a single entry, which is converted without allocating a list.
+fn entries_value(entries: &[&KdlEntry], options: &ParseOptions, empty: impl FnOnce() -> Value) -> Value {
+    match entries {
+        [] => empty(),
+        [entry] => parse_entry(entry, options),
+        // FIXME: use a real span
+        _ => Value::list(entries.iter().map(|entry| parse_entry(entry, options)).collect(), Span::unknown()),
+    }
+}
+
+/// The entries of a node for `--args-key`, e.g. `{args: [1, 2], x: 3}` for `1 2 x=3`.
+fn args_and_props(entries: &[&KdlEntry], options: &ParseOptions, span: Span) -> Value {
+    let args = entries.iter().filter(|entry| entry.name().is_none()).map(|entry| parse_value(entry, options)).collect();
+
+    let args_key = options.args_key.clone().unwrap();
+    let mut record = Record::new();
+    record.insert(args_key.clone(), Value::list(args, span));
+    for entry in entries {
+        if let Some(name) = entry.name() {
+            // a property called like the key of the arguments gets a `#1` suffix rather than
+            // replacing them, like the children of `--merge-props-into-children`
+            let key = options.property_key(name.value());
+            let key = if key == args_key { format!("{}#1", key) } else { key };
+            record.insert(key, parse_value(entry, options));
         }
     }
+
+    Value::record(record, span)
 }
 
-fn parse_entry(entry: &KdlEntry) -> Value {
-    let span = Span::new(entry.span().offset(), entry.span().offset() + entry.len());
+/// Gives a node the `{args, props, children}` shape, or `{entries, children}` with
+/// `--ordered-entries`, without any guessing so `to kdl` can build the
+/// exact same node back. `args` and `props` are always there, `children` only when the node has a
+/// children block, even an empty one, and `args` only when it has some with `--keep-props`.
+fn flatten_node(node: &KdlNode, options: &ParseOptions, depth: Option<usize>) -> Value {
+    let span = span(node.span());
 
-    let value = match entry.value() {
-        KdlValue::String(val) => Value::string(val, span),
-        KdlValue::Bool(val) => Value::bool(*val, span),
-        KdlValue::Null => Value::nothing(span),
-        val => {
-            // Try to convert to string first, then parse as number
-            let s = val.to_string();
-            // Try parsing as int first
-            if let Ok(i) = s.parse::<i64>() {
-                Value::int(i, span)
-            } else if let Ok(f) = s.parse::<f64>() {
-                Value::float(f, span)
-            } else {
-                // Fallback to string representation
-                Value::string(s, span)
+    if options.ordered_entries {
+        let entries = kept_entries(node, options)
+            .map(|entry| {
+                let name = entry.name().map_or(Value::nothing(span), |name| Value::string(name.value(), span));
+                Value::record(record! { ENTRY_NAME_KEY => name, ENTRY_VALUE_KEY => parse_value(entry, options) }, span)
+            })
+            .collect();
+
+        let mut record = Record::with_capacity(2);
+        record.insert(ENTRIES_KEY, Value::list(entries, span));
+        if let Some(children) = node.children() {
+            record.insert(CHILDREN_KEY, parse_children(children, options, depth));
+        }
+        return Value::record(record, span);
+    }
+
+    let mut args = Vec::new();
+    let mut props = Record::new();
+    for entry in kept_entries(node, options) {
+        let value = parse_value(entry, options);
+        match entry.name() {
+            Some(name) => {
+                props.insert(name.value().to_string(), value);
             }
+            None => args.push(value),
         }
+    }
+
+    let mut record = Record::with_capacity(3);
+    if options.flatten || options.uniform || !args.is_empty() {
+        record.insert(ARGS_KEY, Value::list(args, span));
+    }
+    record.insert(PROPS_KEY, Value::record(props, span));
+    match node.children() {
+        Some(children) => record.insert(CHILDREN_KEY, parse_children(children, options, depth)),
+        None if options.uniform => record.insert(CHILDREN_KEY, Value::record(Record::new(), span)),
+        None => None,
     };
 
+    Value::record(record, span)
+}
+
+/// The entries of a node without the properties `--fields` leaves out.
+fn kept_entries<'a>(node: &'a KdlNode, options: &'a ParseOptions) -> impl Iterator<Item = &'a KdlEntry> {
+    node.entries().iter().filter(|entry| match (entry.name(), &options.fields) {
+        (Some(name), Some(fields)) => fields.iter().any(|field| field == name.value()),
+        _ => true,
+    })
+}
+
+fn parse_entry(entry: &KdlEntry, options: &ParseOptions) -> Value {
+    let span = span(entry.span());
+
+    let value = parse_value(entry, options);
+
     match entry.name() {
+        // the key is the name without quotes, `"my key"=1` is `{my key: 1}`
         Some(name) => {
             let mut record = Record::new();
-            record.insert(name.value().to_string(), value);
+            record.insert(options.property_key(name.value()), value);
             Value::record(record, span)
         }
         None => value,
     }
 }
+
+/// The `//` and `/* */` comments right before every node of a document, at any depth, as a table
+/// with the columns `node`, the path of the node as `kdl diff` and `kdl nodes` give it, and
+/// `comment`, the text of its comments without their markers.
+pub(crate) fn doc_comments(document: &KdlDocument, span: Span) -> Value {
+    fn collect(nodes: &[KdlNode], parent: &str, span: Span, rows: &mut Vec<Value>) {
+        for (path, node) in diff::keyed(nodes, parent) {
+            if let Some(comment) = node.format().and_then(|format| doc_comment(&format.leading)) {
+                rows.push(Value::record(record! { "node" => Value::string(&path, span), "comment" => Value::string(comment, span) }, span));
+            }
+            if let Some(children) = node.children() {
+                collect(children.nodes(), &path, span, rows);
+            }
+        }
+    }
+
+    let mut rows = Vec::new();
+    collect(document.nodes(), "", span, &mut rows);
+    Value::list(rows, span)
+}
+
+/// The text of the comments of the whitespace before a node, one line per line of comment. The
+/// comments before a slashdashed node, which kdl puts there too, are the ones of that node.
+fn doc_comment(leading: &str) -> Option<String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut rest = leading;
+
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+        if let Some(comment) = rest.strip_prefix("//") {
+            let (line, next) = comment.split_once('\n').unwrap_or((comment, ""));
+            lines.push(line.trim_start_matches('/').trim().to_string());
+            rest = next;
+        } else if let Some(comment) = rest.strip_prefix("/*") {
+            // block comments nest
+            let mut depth = 1;
+            let mut end = comment.len();
+            let mut i = 0;
+            while i < comment.len() {
+                if comment[i..].starts_with("/*") {
+                    depth += 1;
+                    i += 2;
+                } else if comment[i..].starts_with("*/") {
+                    depth -= 1;
+                    if depth == 0 {
+                        end = i;
+                        break;
+                    }
+                    i += 2;
+                } else {
+                    i += comment[i..].chars().next().unwrap().len_utf8();
+                }
+            }
+            let text = comment[..end].trim_start_matches('*');
+            lines.extend(text.lines().map(|line| line.trim().trim_start_matches('*').trim().to_string()).filter(|line| !line.is_empty()));
+            rest = comment.get(end + 2..).unwrap_or("");
+        } else {
+            // a slashdashed node, with its own comments before it
+            lines.clear();
+            rest = rest.split_once('\n').map_or("", |(_, next)| next);
+        }
+    }
+
+    (!lines.is_empty()).then(|| lines.join("\n"))
+}
+
+/// The value of an entry, without its name for properties.
+pub(crate) fn parse_value(entry: &KdlEntry, options: &ParseOptions) -> Value {
+    with_span(entry.span(), entry_value(entry, options), options)
+}
+
+fn entry_value(entry: &KdlEntry, options: &ParseOptions) -> Value {
+    let span = span(entry.span());
+
+    let value = match entry.value() {
+        KdlValue::String(val) if options.trim_whitespace => Value::string(val.trim(), span),
+        KdlValue::String(val) => Value::string(val, span),
+        KdlValue::Bool(val) => Value::bool(*val, span),
+        KdlValue::Null => Value::nothing(span),
+        KdlValue::Integer(_) | KdlValue::Float(_) if options.numbers_as_strings => {
+            Value::string(number_repr(entry), span)
+        }
+        // exact only up to 2^53, past which it is the closest float
+        KdlValue::Integer(val) if options.ints_as_floats => Value::float(*val as f64, span),
+        KdlValue::Integer(val) => match i64::try_from(*val) {
+            Ok(val) => Value::int(val, span),
+            // beyond nu integers, the digits as written for `to kdl` to write them back as a number
+            Err(_) if options.preserve_number_repr => {
+                return annotated(entry, Value::record(record! { BIGNUM_KEY => Value::string(number_repr(entry), span) }, span), options);
+            }
+            // nu integers are 64 bits, keep the exact digits rather than losing precision
+            Err(_) => Value::string(val.to_string(), span),
+        },
+        KdlValue::Float(val) => Value::float(*val, span),
+    };
+
+    let value = match entry.ty() {
+        // the lossless shapes keep the annotation as is instead, see below
+        Some(ty) if !options.keeps_annotations() => apply_type_annotation(ty.value(), value),
+        _ => value,
+    };
+
+    let number = matches!(entry.value(), KdlValue::Integer(_) | KdlValue::Float(_));
+    if options.preserve_number_repr && number && !options.numbers_as_strings {
+        let repr = number_repr(entry);
+        // only the numbers `to kdl` would write differently need their repr
+        if repr != entry.value().to_string() {
+            let mut record = Record::with_capacity(2);
+            record.insert(VALUE_KEY, value);
+            record.insert(REPR_KEY, Value::string(repr, span));
+            return annotated(entry, Value::record(record, span), options);
+        }
+    }
+
+    annotated(entry, value, options)
+}
+
+/// In the lossless shapes, the value of an annotated entry with its annotation, e.g.
+/// `{$type: ip, $value: "127.0.0.1"}` for `(ip)"127.0.0.1"`, so `to kdl` can write it back.
+fn annotated(entry: &KdlEntry, value: Value, options: &ParseOptions) -> Value {
+    match entry.ty() {
+        Some(ty) if options.keeps_annotations() => {
+            let span = value.span();
+            let mut record = Record::with_capacity(2);
+            record.insert(TYPE_KEY, Value::string(ty.value(), span));
+            record.insert(VALUE_KEY, value);
+            Value::record(record, span)
+        }
+        _ => value,
+    }
+}
+
+/// The number of an entry as written in the source, or formatted by KDL when the entry wasn't parsed.
+fn number_repr(entry: &KdlEntry) -> String {
+    match entry.format() {
+        Some(format) if !format.value_repr.is_empty() => format.value_repr.clone(),
+        _ => entry.value().to_string(),
+    }
+}
+
+/// The annotations `from kdl --error-on-unknown-annotation` allows without --allowed-annotations:
+/// the types `to kdl` writes and the number types of the KDL spec.
+const KNOWN_ANNOTATIONS: &[&str] = &[
+    "int", "float", "bool", "string", "date", "duration", "base64", "i8", "i16", "i32", "i64", "i128", "isize", "u8", "u16", "u32", "u64",
+    "u128", "usize", "f32", "f64", "decimal64", "decimal128",
+];
+
+/// Fails on the first annotation of a node or an entry which is neither known nor in `allowed`,
+/// before anything is converted.
+pub(crate) fn check_annotations(document: &KdlDocument, allowed: &[String]) -> Result<(), KdlPluginError> {
+    let known = |ty: &KdlIdentifier| KNOWN_ANNOTATIONS.contains(&ty.value()) || allowed.iter().any(|name| name == ty.value());
+    let unknown = |ty: &KdlIdentifier| KdlPluginError::UnknownAnnotation { name: ty.value().to_string(), span: span(ty.span()) };
+
+    for node in document.nodes() {
+        if let Some(ty) = node.ty().filter(|ty| !known(ty)) {
+            return Err(unknown(ty));
+        }
+        if let Some(ty) = node.entries().iter().filter_map(KdlEntry::ty).find(|ty| !known(ty)) {
+            return Err(unknown(ty));
+        }
+        if let Some(children) = node.children() {
+            check_annotations(children, allowed)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Gives back the nu type named by the annotations `to kdl --annotate-types` emits, e.g. `(float)2`
+/// is a float even though KDL would read it as an integer. Any other annotation, or a value that
+/// can't be converted, leaves the value untouched.
+fn apply_type_annotation(ty: &str, value: Value) -> Value {
+    let span = value.span();
+
+    match (ty, &value) {
+        ("int", Value::Float { val, .. }) if val.fract() == 0.0 => Value::int(*val as i64, span),
+        ("int", Value::String { val, .. }) => val.parse().map_or(value, |i| Value::int(i, span)),
+        ("float", Value::Int { val, .. }) => Value::float(*val as f64, span),
+        ("float", Value::String { val, .. }) => val.parse().map_or(value, |f| Value::float(f, span)),
+        ("bool", Value::String { val, .. }) => val.parse().map_or(value, |b| Value::bool(b, span)),
+        _ => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nu_plugin::EvaluatedCall;
+    use nu_protocol::IntoSpanned;
+
+    use super::*;
+
+    use crate::KDL;
+
+    fn from_kdl_with(call: EvaluatedCall, input: &str) -> Value {
+        KDL.from(None, &call, &Value::test_string(input)).unwrap()
+    }
+
+    fn from_kdl(input: &str) -> Value {
+        from_kdl_with(EvaluatedCall::new(Span::test_data()), input)
+    }
+
+    fn with_flag(name: &str) -> EvaluatedCall {
+        EvaluatedCall::new(Span::test_data()).with_flag(name.to_string().into_spanned(Span::test_data()))
+    }
+
+    #[test]
+    fn test_trim_whitespace() {
+        let output = from_kdl_with(with_flag("trim-whitespace"), r#"node "  x  " key=" y ""#);
+
+        assert_eq!(
+            output.get_data_by_key("node").unwrap(),
+            Value::test_list(vec![
+                Value::test_string("x"),
+                Value::test_record(Record::from_iter([("key".to_string(), Value::test_string("y"))])),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_whitespace_kept_by_default() {
+        let output = from_kdl(r#"node "  x  ""#);
+
+        assert_eq!(output.get_data_by_key("node").unwrap(), Value::test_string("  x  "));
+    }
+
+    #[test]
+    fn test_null_keyword_and_null_string() {
+        let output = from_kdl(r#"keyword #null; string "null""#);
+
+        assert_eq!(output.get_data_by_key("keyword").unwrap(), Value::test_nothing());
+        assert_eq!(output.get_data_by_key("string").unwrap(), Value::test_string("null"));
+    }
+
+    #[test]
+    fn test_numeric_string_stays_string() {
+        let output = from_kdl(r#"string "123"; number 123"#);
+
+        assert_eq!(output.get_data_by_key("string").unwrap(), Value::test_string("123"));
+        assert_eq!(output.get_data_by_key("number").unwrap(), Value::test_int(123));
+    }
+
+    #[test]
+    fn test_numbers_as_strings() {
+        let output = from_kdl_with(
+            with_flag("numbers-as-strings"),
+            "pi 3.141592653589793238; big 99999999999999999999; name \"x\"",
+        );
+
+        assert_eq!(output.get_data_by_key("pi").unwrap(), Value::test_string("3.141592653589793238"));
+        assert_eq!(output.get_data_by_key("big").unwrap(), Value::test_string("99999999999999999999"));
+        assert_eq!(output.get_data_by_key("name").unwrap(), Value::test_string("x"));
+    }
+
+    #[test]
+    fn test_numbers_without_numbers_as_strings() {
+        let output = from_kdl("pi 3.141592653589793238");
+
+        assert_eq!(output.get_data_by_key("pi").unwrap(), Value::test_float(std::f64::consts::PI));
+    }
+
+    #[test]
+    fn test_custom_entries_and_children_keys() {
+        let call = EvaluatedCall::new(Span::test_data())
+            .with_named("entries-key".to_string().into_spanned(Span::test_data()), Value::test_string("$entries"))
+            .with_named("children-key".to_string().into_spanned(Span::test_data()), Value::test_string("$children"));
+
+        let output = from_kdl_with(call, r#"parent "arg" { children "x" }"#);
+
+        let parent = output.get_data_by_key("parent").unwrap();
+        assert_eq!(parent.get_data_by_key("$entries").unwrap(), Value::test_string("arg"));
+        assert_eq!(
+            parent.get_data_by_key("$children").unwrap().get_data_by_key("children").unwrap(),
+            Value::test_string("x")
+        );
+    }
+
+    #[test]
+    fn test_default_entries_and_children_keys() {
+        let output = from_kdl(r#"parent "arg" { child "x" }"#);
+
+        let parent = output.get_data_by_key("parent").unwrap();
+        assert_eq!(parent.get_data_by_key("entries").unwrap(), Value::test_string("arg"));
+        assert!(parent.get_data_by_key("children").is_some());
+    }
+
+    #[test]
+    fn test_large_document_of_every_shape() {
+        // 0, 1 or 2 entries, with or without children, like the shapes `entries_value` picks from
+        let input: String = (0..6_000)
+            .map(|i| {
+                let entries = [String::new(), format!(" {}", i), format!(" {} p={}", i, i)][i % 3].clone();
+                let children = if i % 2 == 0 { "" } else { " { child 1; }" };
+                format!("node{}{}{}\n", i, entries, children)
+            })
+            .collect();
+
+        let output = from_kdl(&input);
+
+        let record = output.as_record().unwrap();
+        assert_eq!(record.len(), 6_000);
+        for (i, (name, value)) in record.iter().enumerate() {
+            assert_eq!(name, &format!("node{}", i));
+            let entries = match i % 3 {
+                0 => None,
+                1 => Some(Value::test_int(i as i64)),
+                _ => Some(Value::test_list(vec![Value::test_int(i as i64), Value::test_record(record! { "p" => Value::test_int(i as i64) })])),
+            };
+            let children = (i % 2 == 1).then(|| Value::test_record(record! { "child" => Value::test_int(1) }));
+            let expected = match (entries, children) {
+                (None, None) => Value::test_nothing(),
+                (Some(entries), None) => entries,
+                (None, Some(children)) => children,
+                (Some(entries), Some(children)) => Value::test_record(record! { "entries" => entries, "children" => children }),
+            };
+            assert_eq!(value, &expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_large_document() {
+        let input: String = (0..2_000)
+            .map(|i| format!("node{} {} name=\"n{}\" {{ child {}; other 1.5 }}\n", i, i, i, i))
+            .collect();
+
+        let output = from_kdl(&input);
+
+        let record = output.as_record().unwrap();
+        assert_eq!(record.len(), 2_000);
+        let node = record.get("node1999").unwrap();
+        assert_eq!(
+            node.get_data_by_key("entries").unwrap(),
+            Value::test_list(vec![
+                Value::test_int(1999),
+                Value::test_record(Record::from_iter([("name".to_string(), Value::test_string("n1999"))])),
+            ])
+        );
+        assert_eq!(
+            node.get_data_by_key("children").unwrap(),
+            Value::test_record(Record::from_iter([
+                ("child".to_string(), Value::test_int(1999)),
+                ("other".to_string(), Value::test_float(1.5)),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_flatten() {
+        let output = from_kdl_with(with_flag("flatten"), r#"node "a" 1 key=#true { child "x" }; bare"#);
+
+        let node = output.get_data_by_key("node").unwrap();
+        assert_eq!(
+            node.get_data_by_key("args").unwrap(),
+            Value::test_list(vec![Value::test_string("a"), Value::test_int(1)])
+        );
+        assert_eq!(
+            node.get_data_by_key("props").unwrap(),
+            Value::test_record(Record::from_iter([("key".to_string(), Value::test_bool(true))]))
+        );
+        let child = node.get_data_by_key("children").unwrap().get_data_by_key("child").unwrap();
+        assert_eq!(child.get_data_by_key("args").unwrap(), Value::test_list(vec![Value::test_string("x")]));
+        assert!(child.get_data_by_key("children").is_none());
+
+        let bare = output.get_data_by_key("bare").unwrap();
+        assert_eq!(bare.get_data_by_key("args").unwrap(), Value::test_list(vec![]));
+        assert_eq!(bare.get_data_by_key("props").unwrap(), Value::test_record(Record::new()));
+    }
+
+    /// Collects the spans of the values of a `from kdl` output, nested ones included.
+    fn collect_spans(value: &Value, spans: &mut Vec<Span>) {
+        spans.push(value.span());
+        match value {
+            Value::Record { val, .. } => val.values().for_each(|v| collect_spans(v, spans)),
+            Value::List { vals, .. } => vals.iter().for_each(|v| collect_spans(v, spans)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_spans_of_non_ascii_document() {
+        let input = "café \"crème brûlée\" größe=3 {\n    日本 \"東京\"\n    ünïcödé 1 2\n}\nemoji \"🦀\"\n";
+
+        let output = from_kdl(input);
+
+        let mut all = Vec::new();
+        collect_spans(&output, &mut all);
+        for span in all {
+            assert!(span.end <= input.len(), "{:?} is past the end of the input", span);
+            assert!(input.is_char_boundary(span.start), "{:?} starts inside a character", span);
+            assert!(input.is_char_boundary(span.end), "{:?} ends inside a character", span);
+        }
+
+        let node = output.get_data_by_key("café").unwrap().span();
+        assert!(input[node.start..node.end].starts_with("café \"crème brûlée\""));
+        let city = output.get_data_by_key("café").unwrap().get_data_by_key("children").unwrap().get_data_by_key("日本").unwrap().span();
+        assert_eq!(&input[city.start..city.end], "\"東京\"");
+    }
+
+    fn with_depth(depth: i64) -> EvaluatedCall {
+        EvaluatedCall::new(Span::test_data()).with_named("depth".to_string().into_spanned(Span::test_data()), Value::test_int(depth))
+    }
+
+    const NESTED: &str = "top 1 { middle { bottom 2 } }\nflat 3";
+
+    #[test]
+    fn test_depth_zero() {
+        let output = from_kdl_with(with_depth(0), NESTED);
+
+        let top = output.get_data_by_key("top").unwrap();
+        assert_eq!(top.get_data_by_key("children").unwrap(), Value::test_string(TRUNCATED));
+        assert_eq!(output.get_data_by_key("flat").unwrap(), Value::test_int(3));
+    }
+
+    #[test]
+    fn test_depth_one() {
+        let output = from_kdl_with(with_depth(1), NESTED);
+
+        let middle = output.get_data_by_key("top").unwrap().get_data_by_key("children").unwrap().get_data_by_key("middle").unwrap();
+        assert_eq!(middle, Value::test_string(TRUNCATED));
+    }
+
+    #[test]
+    fn test_depth_unlimited() {
+        let output = from_kdl(NESTED);
+
+        let middle = output.get_data_by_key("top").unwrap().get_data_by_key("children").unwrap().get_data_by_key("middle").unwrap();
+        assert_eq!(middle.get_data_by_key("bottom").unwrap(), Value::test_int(2));
+    }
+
+    #[test]
+    fn test_empty_children_block() {
+        let output = from_kdl("parent {}\nbare\nwith arg\nboth arg {}");
+
+        assert_eq!(output.get_data_by_key("parent").unwrap(), Value::test_record(Record::new()));
+        assert_eq!(output.get_data_by_key("bare").unwrap(), Value::test_nothing());
+        assert_eq!(output.get_data_by_key("with").unwrap(), Value::test_string("arg"));
+
+        let both = output.get_data_by_key("both").unwrap();
+        assert_eq!(both.get_data_by_key("entries").unwrap(), Value::test_string("arg"));
+        assert_eq!(both.get_data_by_key("children").unwrap(), Value::test_record(Record::new()));
+    }
+
+    #[test]
+    fn test_preserve_number_repr() {
+        let output = from_kdl_with(with_flag("preserve-number-repr"), "a 1.50\nb 1e3\nc +5\nd 5\ne 2.5");
+
+        let repr = |key: &str| output.get_data_by_key(key).unwrap();
+        assert_eq!(repr("a").get_data_by_key("$value").unwrap(), Value::test_float(1.5));
+        assert_eq!(repr("a").get_data_by_key("$repr").unwrap(), Value::test_string("1.50"));
+        assert_eq!(repr("b").get_data_by_key("$value").unwrap(), Value::test_float(1000.0));
+        assert_eq!(repr("b").get_data_by_key("$repr").unwrap(), Value::test_string("1e3"));
+        assert_eq!(repr("c").get_data_by_key("$value").unwrap(), Value::test_int(5));
+        assert_eq!(repr("c").get_data_by_key("$repr").unwrap(), Value::test_string("+5"));
+        assert_eq!(repr("d"), Value::test_int(5));
+        assert_eq!(repr("e"), Value::test_float(2.5));
+    }
+
+    fn with_node_filter(glob: &str) -> EvaluatedCall {
+        EvaluatedCall::new(Span::test_data()).with_named("node-filter".to_string().into_spanned(Span::test_data()), Value::test_string(glob))
+    }
+
+    const PLUGINS: &str = "plugin kdl { plugin nested }\nplugin-manager on\ntheme dark";
+
+    #[test]
+    fn test_node_filter_exact() {
+        let output = from_kdl_with(with_node_filter("plugin"), PLUGINS);
+
+        assert_eq!(output.as_record().unwrap().columns().collect::<Vec<_>>(), ["plugin"]);
+        assert!(output.get_data_by_key("plugin").unwrap().get_data_by_key("children").unwrap().get_data_by_key("plugin").is_some());
+    }
+
+    #[test]
+    fn test_node_filter_wildcard() {
+        let output = from_kdl_with(with_node_filter("plugin*"), PLUGINS);
+
+        assert_eq!(output.as_record().unwrap().columns().collect::<Vec<_>>(), ["plugin", "plugin-manager"]);
+    }
+
+    #[test]
+    fn test_node_filter_no_match() {
+        assert_eq!(from_kdl_with(with_node_filter("window"), PLUGINS), Value::test_record(Record::new()));
+    }
+
+    #[test]
+    fn test_integers_and_floats() {
+        let output = from_kdl("int 5\nfloat 5.0\nnegative -5.0\nexponent 5e0");
+
+        assert_eq!(output.get_data_by_key("int").unwrap(), Value::test_int(5));
+        assert_eq!(output.get_data_by_key("float").unwrap(), Value::test_float(5.0));
+        assert_eq!(output.get_data_by_key("negative").unwrap(), Value::test_float(-5.0));
+        assert_eq!(output.get_data_by_key("exponent").unwrap(), Value::test_float(5.0));
+        assert!(matches!(output.get_data_by_key("float").unwrap(), Value::Float { .. }));
+        assert!(matches!(output.get_data_by_key("exponent").unwrap(), Value::Float { .. }));
+    }
+
+    #[test]
+    fn test_float_keywords() {
+        let output = from_kdl("infinity #inf\nminus #-inf\nnot-a-number #nan");
+
+        assert_eq!(output.get_data_by_key("infinity").unwrap(), Value::test_float(f64::INFINITY));
+        assert_eq!(output.get_data_by_key("minus").unwrap(), Value::test_float(f64::NEG_INFINITY));
+        assert!(output.get_data_by_key("not-a-number").unwrap().as_float().unwrap().is_nan());
+    }
+
+    #[test]
+    fn test_digit_separators() {
+        let output = from_kdl("int 1_000_000\nfloat 1_000.5");
+
+        assert_eq!(output.get_data_by_key("int").unwrap(), Value::test_int(1_000_000));
+        assert_eq!(output.get_data_by_key("float").unwrap(), Value::test_float(1000.5));
+    }
+
+    #[test]
+    fn test_fields() {
+        let fields = Value::test_list(vec![Value::test_string("host"), Value::test_string("port")]);
+        let call = EvaluatedCall::new(Span::test_data()).with_named("fields".to_string().into_spanned(Span::test_data()), fields);
+
+        let output = from_kdl_with(call, "db main host=x port=5432 user=admin timeout=5\nother user=nobody");
+
+        let db = output.get_data_by_key("db").unwrap().into_list().unwrap();
+        assert_eq!(db.len(), 3);
+        assert_eq!(db[0], Value::test_string("main"));
+        assert_eq!(db[1].get_data_by_key("host").unwrap(), Value::test_string("x"));
+        assert_eq!(db[2].get_data_by_key("port").unwrap(), Value::test_int(5432));
+        assert_eq!(output.get_data_by_key("other").unwrap(), Value::test_nothing());
+    }
+
+    #[test]
+    fn test_merge_props_into_children() {
+        let output = from_kdl_with(with_flag("merge-props-into-children"), "db \"main\" host=x { timeout 5; host y }");
+
+        let db = output.get_data_by_key("db").unwrap();
+        assert_eq!(db.get_data_by_key("entries").unwrap(), Value::test_string("main"));
+        let children = db.get_data_by_key("children").unwrap();
+        assert_eq!(children.as_record().unwrap().columns().collect::<Vec<_>>(), ["host", "timeout", "host#1"]);
+        assert_eq!(children.get_data_by_key("host").unwrap(), Value::test_string("x"));
+        assert_eq!(children.get_data_by_key("host#1").unwrap(), Value::test_string("y"));
+
+        let output = from_kdl_with(with_flag("merge-props-into-children"), "db host=x { timeout 5 }");
+        assert_eq!(
+            output.get_data_by_key("db").unwrap(),
+            Value::test_record(nu_protocol::record! { "host" => Value::test_string("x"), "timeout" => Value::test_int(5) })
+        );
+    }
+
+    #[test]
+    fn test_child_called_entries() {
+        let output = from_kdl(r#"parent arg { entries "real" }"#);
+        let parent = output.get_data_by_key("parent").unwrap();
+
+        assert_eq!(parent.get_data_by_key("entries").unwrap(), Value::test_string("arg"));
+        assert_eq!(parent.get_data_by_key("children").unwrap().get_data_by_key("entries").unwrap(), Value::test_string("real"));
+
+        let input = "parent {\n    entries real\n    children {\n        x 1\n    }\n}\n";
+        let output = from_kdl(input);
+        let parent = output.get_data_by_key("parent").unwrap();
+
+        assert_eq!(parent.get_data_by_key("entries").unwrap(), Value::test_list(vec![]));
+        assert_eq!(parent.get_data_by_key("children").unwrap().get_data_by_key("entries").unwrap(), Value::test_string("real"));
+        assert_eq!(KDL.to(None, &EvaluatedCall::new(Span::test_data()), &output).unwrap().as_str().unwrap(), input);
+    }
+
+    fn with_rename(rename: Record) -> EvaluatedCall {
+        EvaluatedCall::new(Span::test_data()).with_named("rename".to_string().into_spanned(Span::test_data()), Value::test_record(rename))
+    }
+
+    #[test]
+    fn test_rename() {
+        let output = from_kdl_with(with_rename(record! { "pane" => Value::test_string("panel") }), "tab { pane 1 }\npane 2\nother 3");
+
+        assert_eq!(output.get_data_by_key("tab").unwrap(), Value::test_record(record! { "panel" => Value::test_int(1) }));
+        assert_eq!(output.get_data_by_key("panel").unwrap(), Value::test_int(2));
+        assert_eq!(output.get_data_by_key("other").unwrap(), Value::test_int(3));
+        assert!(output.get_data_by_key("pane").is_none());
+    }
+
+    #[test]
+    fn test_colliding_rename() {
+        let rename = record! { "pane" => Value::test_string("view"), "window" => Value::test_string("view") };
+
+        let output = from_kdl_with(with_rename(rename), "pane 1\nwindow 2\nview 3\nother 4");
+
+        let views = [Value::test_int(1), Value::test_int(2), Value::test_int(3)];
+        assert_eq!(output.get_data_by_key("view").unwrap().into_list().unwrap(), views);
+        assert_eq!(output.as_record().unwrap().columns().collect::<Vec<_>>(), ["view", "other"]);
+    }
+
+    #[test]
+    fn test_node_order() {
+        let output = from_kdl("zeta 1\nalpha 2\nmid { b 1; a 2 }\nalpha 3\n");
+
+        // a repeated name keeps the place of its first node, with the value of its last one
+        let record = output.as_record().unwrap();
+        assert_eq!(record.columns().collect::<Vec<_>>(), ["zeta", "alpha", "mid"]);
+        assert_eq!(record.get("alpha").unwrap(), &Value::test_int(3));
+        assert_eq!(record.get("mid").unwrap().as_record().unwrap().columns().collect::<Vec<_>>(), ["b", "a"]);
+
+        // the nodes put in a list by --rename are in the order of the document
+        let output = from_kdl_with(with_rename(record! { "pane" => Value::test_string("view") }), "view 1\nother 2\npane 3\nview 4\n");
+        let record = output.as_record().unwrap();
+        assert_eq!(record.columns().collect::<Vec<_>>(), ["view", "other"]);
+        assert_eq!(record.get("view").unwrap(), &Value::test_list(vec![Value::test_int(1), Value::test_int(3), Value::test_int(4)]));
+    }
+
+    fn with_schema(schema: Value) -> EvaluatedCall {
+        EvaluatedCall::new(Span::test_data()).with_named("schema".to_string().into_spanned(Span::test_data()), schema)
+    }
+
+    #[test]
+    fn test_schema() {
+        let schema = Value::test_record(record! {
+            "server" => Value::test_record(record! {
+                "port" => Value::test_string("int"),
+                "ratio" => Value::test_string("float"),
+                "name" => Value::test_string("string"),
+                "tags" => Value::test_list(vec![Value::test_string("string")]),
+            }),
+            "debug" => Value::test_string("bool"),
+        });
+
+        let output = from_kdl_with(with_schema(schema), "server {\n    port \"8080\"\n    ratio 1\n    name 42\n    tags a 1\n}\ndebug \"true\"\nother x\n");
+
+        let server = output.get_data_by_key("server").unwrap();
+        assert_eq!(server.get_data_by_key("port").unwrap(), Value::test_int(8080));
+        assert_eq!(server.get_data_by_key("ratio").unwrap(), Value::test_float(1.0));
+        assert_eq!(server.get_data_by_key("name").unwrap(), Value::test_string("42"));
+        assert_eq!(server.get_data_by_key("tags").unwrap(), Value::test_list(vec![Value::test_string("a"), Value::test_string("1")]));
+        assert_eq!(output.get_data_by_key("debug").unwrap(), Value::test_bool(true));
+        // the nodes it doesn't list are left as they are
+        assert_eq!(output.get_data_by_key("other").unwrap(), Value::test_string("x"));
+    }
+
+    #[test]
+    fn test_schema_errors() {
+        let from_schema = |schema: Value, input: &str| KDL.from(None, &with_schema(schema), &Value::test_string(input)).unwrap_err();
+        let port = |ty: &str| Value::test_record(record! { "server" => Value::test_record(record! { "port" => Value::test_string(ty) }) });
+
+        let error = from_schema(port("int"), "server { port \"http\"; }");
+        assert_eq!(error.msg, "`server.port` should be int");
+        assert_eq!(error.labels[0].text, "found string");
+        assert_eq!(error.labels[0].span, Span::new(14, 20));
+
+        let error = from_schema(port("int"), "server { host x; }");
+        assert_eq!(error.msg, "missing `server.port`");
+
+        let error = from_schema(port("port number"), "server { port 1; }");
+        assert_eq!(error.msg, "invalid --schema");
+    }
+
+    #[test]
+    fn test_error_on_unknown_annotation() {
+        let strict = || with_flag("error-on-unknown-annotation");
+        let from_strict = |call: EvaluatedCall, input: &str| KDL.from(None, &call, &Value::test_string(input));
+        let input = "server {\n    port (u16)8080\n    (secret)token \"x\"\n}\n";
+
+        assert!(from_strict(strict(), "created (date)\"2024-01-01\"; size (u64)1 (float)2").is_ok());
+
+        let error = from_strict(strict(), input).unwrap_err();
+        assert_eq!(error.msg, "unknown annotation `(secret)`");
+        assert_eq!(error.labels[0].span, Span::new(33, 39));
+        assert_eq!(&input[33..39], "secret");
+
+        let error = from_strict(strict(), "a (vendor)1").unwrap_err();
+        assert_eq!(error.msg, "unknown annotation `(vendor)`");
+
+        let allowed = strict().with_named("allowed-annotations".to_string().into_spanned(Span::test_data()), Value::test_list(vec![Value::test_string("secret")]));
+        let output = from_strict(allowed, input).unwrap();
+        assert_eq!(output.get_data_by_key("server").unwrap().get_data_by_key("token").unwrap(), Value::test_string("x"));
+        // without the switch, any annotation is read
+        assert!(from_strict(EvaluatedCall::new(Span::test_data()), input).is_ok());
+    }
+
+    #[test]
+    fn test_key_by_first_arg() {
+        let input = "resource web size=1 {\n    port 80\n}\nresource db \"extra\"\nresource web { port 81; }\nversion\n";
+
+        let output = from_kdl_with(with_flag("key-by-first-arg"), input);
+
+        let record = output.as_record().unwrap();
+        assert_eq!(record.columns().collect::<Vec<_>>(), ["web", "db", "version"]);
+        // the nodes with the same first argument are put in a list, in order
+        assert_eq!(
+            record.get("web").unwrap(),
+            &Value::test_list(vec![
+                Value::test_record(record! {
+                    "entries" => Value::test_record(record! { "size" => Value::test_int(1) }),
+                    "children" => Value::test_record(record! { "port" => Value::test_int(80) }),
+                }),
+                Value::test_record(record! { "port" => Value::test_int(81) }),
+            ])
+        );
+        assert_eq!(record.get("db").unwrap(), &Value::test_string("extra"));
+        // a node without arguments keeps its name, and the children their names
+        assert_eq!(record.get("version").unwrap(), &Value::test_nothing());
+    }
+
+    #[test]
+    fn test_tag_shape() {
+        let output = from_kdl_with(with_flag("tag-shape"), "scalar 1\nlist 1 2\nrecord port=8080\nnode 1 { child 2 }\nbare");
+
+        let shape = |name: &str| output.get_data_by_key(name).unwrap().get_data_by_key("__shape").unwrap().into_string().unwrap();
+        assert_eq!(["scalar", "list", "record", "node", "bare"].map(shape), ["scalar", "list", "record", "node", "scalar"]);
+
+        assert_eq!(
+            output.get_data_by_key("list").unwrap(),
+            Value::test_record(record! {
+                "__shape" => Value::test_string("list"),
+                "value" => Value::test_list(vec![Value::test_int(1), Value::test_int(2)]),
+            })
+        );
+        assert_eq!(
+            output.get_data_by_key("record").unwrap(),
+            Value::test_record(record! { "__shape" => Value::test_string("record"), "port" => Value::test_int(8080) })
+        );
+        // the children are tagged too
+        let child = output.get_data_by_key("node").unwrap().get_data_by_key("children").unwrap().get_data_by_key("child").unwrap();
+        assert_eq!(child, Value::test_record(record! { "__shape" => Value::test_string("scalar"), "value" => Value::test_int(2) }));
+    }
+
+    #[test]
+    fn test_property_prefix() {
+        let call = EvaluatedCall::new(Span::test_data()).with_named("property-prefix".to_string().into_spanned(Span::test_data()), Value::test_string("@"));
+
+        let output = from_kdl_with(call, "node arg port=8080\nlone port=8080\nchild { port 8080; }");
+
+        assert_eq!(
+            output.get_data_by_key("node").unwrap(),
+            Value::test_list(vec![Value::test_string("arg"), Value::test_record(record! { "@port" => Value::test_int(8080) })])
+        );
+        assert_eq!(output.get_data_by_key("lone").unwrap(), Value::test_record(record! { "@port" => Value::test_int(8080) }));
+        assert_eq!(output.get_data_by_key("child").unwrap(), Value::test_record(record! { "port" => Value::test_int(8080) }));
+    }
+
+    #[test]
+    fn test_with_spans() {
+        let input = "title KDL\nserver main port=8080";
+
+        let output = from_kdl_with(with_flag("with-spans"), input);
+
+        let offsets = |value: &Value| {
+            let span = value.get_data_by_key("span").unwrap();
+            let offset = |key| span.get_data_by_key(key).unwrap().as_int().unwrap() as usize;
+            &input[offset("start")..offset("end")]
+        };
+        let server = output.get_data_by_key("server").unwrap();
+        assert_eq!(offsets(&server).trim(), "server main port=8080");
+        let entries = server.get_data_by_key("value").unwrap().into_list().unwrap();
+        assert_eq!(offsets(&entries[0]).trim(), "main");
+        assert_eq!(entries[0].get_data_by_key("value").unwrap(), Value::test_string("main"));
+        let port = entries[1].get_data_by_key("port").unwrap();
+        assert_eq!(offsets(&port).trim(), "port=8080");
+        assert_eq!(port.get_data_by_key("value").unwrap(), Value::test_int(8080));
+    }
+
+    #[test]
+    fn test_doc_comments() {
+        let input = "// the title\n// of the page\ntitle KDL\nplain 1\n/-old 2\n/**\n * the server\n */\nserver { /* its port */ port 8080; host x }";
+
+        let output = from_kdl_with(with_flag("doc-comments"), input);
+
+        assert_eq!(
+            output,
+            Value::test_list(vec![
+                Value::test_record(record! { "node" => Value::test_string("title"), "comment" => Value::test_string("the title\nof the page") }),
+                Value::test_record(record! { "node" => Value::test_string("server"), "comment" => Value::test_string("the server") }),
+                Value::test_record(record! { "node" => Value::test_string("server.port"), "comment" => Value::test_string("its port") }),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_slashdashed_node_comments() {
+        assert_eq!(doc_comment("// for old\n/-old 1\n"), None);
+        assert_eq!(doc_comment("/-old 1\n// for new\n"), Some("for new".to_string()));
+        assert_eq!(doc_comment("/* outer /* inner */ still */ "), Some("outer /* inner */ still".to_string()));
+    }
+
+    #[test]
+    fn test_uniform() {
+        let output = from_kdl_with(with_flag("uniform"), r#"bare; args "a" 1; props key=#true; children { child "x" }"#);
+        let shape = |name: &str, args: Vec<Value>, props: Record, children: Record| {
+            assert_eq!(
+                output.get_data_by_key(name).unwrap(),
+                Value::test_record(record! {
+                    "args" => Value::test_list(args),
+                    "props" => Value::test_record(props),
+                    "children" => Value::test_record(children),
+                }),
+                "{}",
+                name
+            );
+        };
+
+        shape("bare", vec![], Record::new(), Record::new());
+        shape("args", vec![Value::test_string("a"), Value::test_int(1)], Record::new(), Record::new());
+        shape("props", vec![], record! { "key" => Value::test_bool(true) }, Record::new());
+        let child = record! {
+            "args" => Value::test_list(vec![Value::test_string("x")]),
+            "props" => Value::test_record(Record::new()),
+            "children" => Value::test_record(Record::new()),
+        };
+        shape("children", vec![], Record::new(), record! { "child" => Value::test_record(child) });
+    }
+
+    #[test]
+    fn test_flatten_annotated_arguments() {
+        let output = from_kdl_with(with_flag("flatten"), "server (ip)\"127.0.0.1\" 8080 (float)1");
+
+        let args = output.get_data_by_key("server").unwrap().get_data_by_key("args").unwrap();
+        assert_eq!(
+            args.into_list().unwrap(),
+            [
+                Value::test_record(nu_protocol::record! { "$type" => Value::test_string("ip"), "$value" => Value::test_string("127.0.0.1") }),
+                Value::test_int(8080),
+                Value::test_record(nu_protocol::record! { "$type" => Value::test_string("float"), "$value" => Value::test_int(1) }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keep_props() {
+        let input = "lone enabled=#true\nchild { enabled #true; }\nboth 1 a=1 { b 2; }\nargs 1 2\n";
+
+        let output = from_kdl(input);
+        assert_eq!(output.get_data_by_key("lone"), output.get_data_by_key("child"));
+
+        let output = from_kdl_with(with_flag("keep-props"), input);
+        assert_eq!(
+            output.get_data_by_key("lone").unwrap(),
+            Value::test_record(nu_protocol::record! { "props" => Value::test_record(nu_protocol::record! { "enabled" => Value::test_bool(true) }) })
+        );
+        assert_eq!(
+            output.get_data_by_key("child").unwrap(),
+            Value::test_record(nu_protocol::record! { "enabled" => Value::test_bool(true) })
+        );
+        let both = output.get_data_by_key("both").unwrap();
+        assert_eq!(both.as_record().unwrap().columns().collect::<Vec<_>>(), ["args", "props", "children"]);
+        assert_eq!(output.get_data_by_key("args").unwrap(), Value::test_list(vec![Value::test_int(1), Value::test_int(2)]));
+
+        let output = KDL.to(None, &EvaluatedCall::new(Span::test_data()), &output).unwrap().into_string().unwrap();
+        assert_eq!(output, "lone enabled=#true\nchild {\n    enabled #true\n}\nboth 1 a=1 {\n    b 2\n}\nargs 1 2\n");
+    }
+
+    #[test]
+    fn test_document_span_within_source() {
+        let v1 = EvaluatedCall::new(Span::test_data()).with_flag("v1-fallback".to_string().into_spanned(Span::test_data()));
+
+        for (call, input) in [
+            (EvaluatedCall::new(Span::test_data()), "node 1 {\n    child \"a\"\n}\n"),
+            (EvaluatedCall::new(Span::test_data()), ""),
+            (EvaluatedCall::new(Span::test_data()), "  \n// only a comment\n"),
+            (v1, "node \"a\" true\nother null\n"),
+        ] {
+            let span = from_kdl_with(call, input).span();
+
+            assert!(span.start <= span.end && span.end <= input.len(), "{:?} is out of {:?}", span, input);
+        }
+    }
+
+    #[test]
+    fn test_comment_only_documents() {
+        let v1 = || EvaluatedCall::new(Span::test_data()).with_flag("v1".to_string().into_spanned(Span::test_data()));
+
+        for (call, input) in [
+            (EvaluatedCall::new(Span::test_data()), "// only a comment"),
+            (EvaluatedCall::new(Span::test_data()), "// only a comment\n\n   \t\n"),
+            (EvaluatedCall::new(Span::test_data()), "/* a block\n   comment */\n/- disabled 1\n"),
+            (v1(), "// a v1 comment\n/* and another */\n"),
+            (v1(), "// a v1 comment followed by whitespace\n  \n"),
+        ] {
+            let output = from_kdl_with(call, input);
+
+            assert_eq!(output, Value::test_record(Record::new()), "{:?}", input);
+            let span = output.span();
+            assert!(span.start <= span.end && span.end <= input.len(), "{:?} is out of {:?}", span, input);
+        }
+    }
+
+    #[test]
+    fn test_clamped_document_span() {
+        let document: KdlDocument = "node 1\n".parse().unwrap();
+
+        assert_eq!(parse_document(&document, 3, &ParseOptions::default()).span(), Span::new(0, 3));
+        assert_eq!(parse_document(&document, 0, &ParseOptions::default()).span(), Span::new(0, 0));
+    }
+
+    #[test]
+    fn test_args_key() {
+        let call = EvaluatedCall::new(Span::test_data()).with_named("args-key".to_string().into_spanned(Span::test_data()), Value::test_string("_args"));
+
+        let output = from_kdl_with(call, "point 1 2 label=origin\nname \"a\"\nlabel x=1\ngroup 3 { child 4 }\nbare\n");
+
+        assert_eq!(
+            output.get_data_by_key("point").unwrap(),
+            Value::test_record(nu_protocol::record! {
+                "_args" => Value::test_list(vec![Value::test_int(1), Value::test_int(2)]),
+                "label" => Value::test_string("origin"),
+            })
+        );
+        assert_eq!(output.get_data_by_key("name").unwrap().get_data_by_key("_args").unwrap(), Value::test_list(vec![Value::test_string("a")]));
+        assert_eq!(output.get_data_by_key("label").unwrap().get_data_by_key("_args").unwrap(), Value::test_list(vec![]));
+        let group = output.get_data_by_key("group").unwrap();
+        assert_eq!(group.get_data_by_key("entries").unwrap().get_data_by_key("_args").unwrap(), Value::test_list(vec![Value::test_int(3)]));
+        assert_eq!(group.get_data_by_key("children").unwrap().get_data_by_key("child").unwrap().get_data_by_key("_args").unwrap(), Value::test_list(vec![Value::test_int(4)]));
+        assert_eq!(output.get_data_by_key("bare").unwrap(), Value::test_nothing());
+    }
+
+    #[test]
+    fn test_property_called_like_args_key() {
+        let call = EvaluatedCall::new(Span::test_data()).with_named("args-key".to_string().into_spanned(Span::test_data()), Value::test_string("args"));
+
+        let output = from_kdl_with(call, "widget 1 args=5");
+
+        assert_eq!(
+            output.get_data_by_key("widget").unwrap(),
+            Value::test_record(record! { "args" => Value::test_list(vec![Value::test_int(1)]), "args#1" => Value::test_int(5) })
+        );
+    }
+
+    #[test]
+    fn test_ints_as_floats() {
+        let input = "answer 42\nratio 0.5\nhuge 9007199254740993";
+
+        let output = from_kdl(input);
+        assert!(matches!(output.get_data_by_key("answer").unwrap(), Value::Int { val: 42, .. }));
+
+        let output = from_kdl_with(with_flag("ints-as-floats"), input);
+        assert!(matches!(output.get_data_by_key("answer").unwrap(), Value::Float { val, .. } if val == 42.0));
+        assert!(matches!(output.get_data_by_key("ratio").unwrap(), Value::Float { val, .. } if val == 0.5));
+        // the closest float, 2^53 + 1 has none of its own
+        assert!(matches!(output.get_data_by_key("huge").unwrap(), Value::Float { val, .. } if val == 9007199254740992.0));
+    }
+
+    #[test]
+    fn test_keep_annotations() {
+        let input = "byte (u8)255\npoint (float)1 y=(i32)2\n";
+
+        let output = from_kdl(input);
+        assert_eq!(output.get_data_by_key("byte").unwrap(), Value::test_int(255));
+
+        let keep = with_flag("keep-annotations");
+        let output = from_kdl_with(keep.clone(), input);
+        assert_eq!(
+            output.get_data_by_key("byte").unwrap(),
+            Value::test_record(nu_protocol::record! { "$type" => Value::test_string("u8"), "$value" => Value::test_int(255) })
+        );
+
+        let kdl = KDL.to(None, &EvaluatedCall::new(Span::test_data()), &output).unwrap();
+        assert_eq!(kdl.as_str().unwrap(), input);
+        assert_eq!(from_kdl_with(keep, kdl.as_str().unwrap()), output);
+    }
+}
@@ -0,0 +1,91 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{ast::CellPath, Category, LabeledError, PipelineData, Signature, SyntaxShape, Type, Value};
+
+use crate::KDL;
+
+pub struct KdlGet;
+
+impl PluginCommand for KdlGet {
+    type Plugin = KDL;
+
+    fn name(&self) -> &str {
+        "kdl get"
+    }
+
+    fn description(&self) -> &str {
+        "Get a single value out of a KDL document with a cell path"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .input_output_type(Type::String, Type::Any)
+            .required("path", SyntaxShape::CellPath, "The cell path to the value, e.g. workspace.tab.pane")
+            .switch("v1", "Force parsing as KDL v1 only", Some('1'))
+            .switch("v1-fallback", "Try KDL v2, fall back to v1 with a warning if parsing fails", None)
+            .category(Category::Experimental)
+    }
+
+    fn run(
+        &self,
+        plugin: &KDL,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let value = input.into_value(call.head)?;
+        let result = get(plugin, Some(engine), call, &value)?;
+        Ok(PipelineData::Value(result, None))
+    }
+}
+
+/// Converts the document like `from kdl` would and follows the cell path given as the first
+/// positional argument, so `kdl get a.b` is the same as `from kdl | get a.b`.
+pub(crate) fn get(plugin: &KDL, engine: Option<&EngineInterface>, call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let path: CellPath = call.req(0)?;
+    let value = plugin.from(engine, call, input)?;
+
+    let result = value.follow_cell_path(&path.members)?;
+
+    Ok(result.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nu_protocol::{ast::PathMember, casing::Casing, Span};
+
+    fn call_with_path(members: Vec<PathMember>) -> EvaluatedCall {
+        EvaluatedCall::new(Span::test_data()).with_positional(Value::test_cell_path(CellPath { members }))
+    }
+
+    fn key(name: &str) -> PathMember {
+        PathMember::test_string(name.to_string(), false, Casing::Sensitive)
+    }
+
+    #[test]
+    fn test_get_nested_value() {
+        let input = Value::test_string(r#"workspace { tab { pane "main" } }"#);
+        let call = call_with_path(vec![key("workspace"), key("tab"), key("pane")]);
+
+        let result = get(&KDL, None, &call, &input).unwrap();
+        assert_eq!(result.as_str().unwrap(), "main");
+    }
+
+    #[test]
+    fn test_get_missing_path() {
+        let input = Value::test_string(r#"workspace { tab { pane "main" } }"#);
+        let call = call_with_path(vec![key("workspace"), key("window")]);
+
+        assert!(get(&KDL, None, &call, &input).is_err());
+    }
+
+    #[test]
+    fn test_get_into_list() {
+        let input = Value::test_string("bookmarks 12 15 188 1234");
+        let call = call_with_path(vec![key("bookmarks"), PathMember::test_int(2, false)]);
+
+        let result = get(&KDL, None, &call, &input).unwrap();
+        assert_eq!(result.as_int().unwrap(), 188);
+    }
+}
@@ -0,0 +1,243 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{Category, LabeledError, PipelineData, Record, Signature, Span, SyntaxShape, Type, Value};
+
+use serde_json::{Map, Number, Value as Json};
+
+use crate::{error::KdlPluginError, to, KDL};
+
+pub struct KdlToJson;
+pub struct KdlFromJson;
+
+impl PluginCommand for KdlToJson {
+    type Plugin = KDL;
+
+    fn name(&self) -> &str {
+        "kdl to-json"
+    }
+
+    fn description(&self) -> &str {
+        "Convert a KDL document to JSON in one go, like `from kdl | to json`"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .input_output_type(Type::String, Type::String)
+            .switch("raw", "Remove all the whitespace of the output", Some('r'))
+            .switch("v1", "Force parsing as KDL v1 only", Some('1'))
+            .switch("v1-fallback", "Try KDL v2, fall back to v1 with a warning if parsing fails", None)
+            .switch("flatten", "Turn every node into {args, props, children} for a lossless round trip", None)
+            .category(Category::Experimental)
+    }
+
+    fn run(
+        &self,
+        plugin: &KDL,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let value = input.into_value(call.head)?;
+        let result = to_json(plugin, Some(engine), call, &value)?;
+        Ok(PipelineData::Value(result, None))
+    }
+}
+
+impl PluginCommand for KdlFromJson {
+    type Plugin = KDL;
+
+    fn name(&self) -> &str {
+        "kdl from-json"
+    }
+
+    fn description(&self) -> &str {
+        "Convert JSON to a KDL document in one go, arrays of objects being repeated nodes"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .input_output_type(Type::String, Type::String)
+            .named("node-name", SyntaxShape::String, "The name of the node of JSON which isn't an object", Some('n'))
+            .category(Category::Experimental)
+    }
+
+    fn run(
+        &self,
+        plugin: &KDL,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let value = input.into_value(call.head)?;
+        let result = from_json(plugin, Some(engine), call, &value)?;
+        Ok(PipelineData::Value(result, None))
+    }
+}
+
+/// Converts the document like `from kdl` would, with the same flags, and serializes the result.
+///
+/// Nodes map to JSON exactly like they map to records, so a node with both entries and children
+/// is an object with the `entries` and `children` keys, or `args`, `props` and `children` with
+/// `--flatten`. Type annotations are applied before converting, `(int)"1"` is the number `1`.
+pub(crate) fn to_json(plugin: &KDL, engine: Option<&EngineInterface>, call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let value = plugin.from(engine, call, input)?;
+    let json = json(&value);
+
+    let output = if call.has_flag("raw")? {
+        json.to_string()
+    } else {
+        serde_json::to_string_pretty(&json).map_err(|e| LabeledError::new(e.to_string()))?
+    };
+
+    Ok(Value::string(output, call.head))
+}
+
+/// Converts JSON like `from json | to kdl` would, except for the arrays with objects in them: they
+/// are repeated nodes of the name of their key, so `{"tab": [{"name": "a"}, {"name": "b"}]}` is
+/// `tab { name a }` then `tab { name b }`. Objects are nodes with children, and arrays of anything
+/// else are the arguments of a single node.
+pub(crate) fn from_json(plugin: &KDL, engine: Option<&EngineInterface>, call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let text = input.as_str().map_err(|_| KdlPluginError::NotAString { ty: input.get_type(), span: input.span() })?;
+    let json: Json = serde_json::from_str(text).map_err(|error| KdlPluginError::InvalidJson { error, span: input.span() })?;
+
+    let value = match json {
+        Json::Array(array) if array.iter().any(Json::is_object) => {
+            let name = call.get_flag("node-name")?.unwrap_or_else(|| to::DEFAULT_NODE_NAME.to_string());
+            let mut record = Record::new();
+            push_repeated(&mut record, &name, &array, input.span());
+            Value::record(record, input.span())
+        }
+        json => value(&json, input.span()),
+    };
+
+    plugin.to(engine, call, &value)
+}
+
+fn value(json: &Json, span: Span) -> Value {
+    match json {
+        Json::Null => Value::nothing(span),
+        Json::Bool(val) => Value::bool(*val, span),
+        // the numbers which don't fit in a nu integer are floats, like with `from json`
+        Json::Number(val) => val.as_i64().map_or_else(|| Value::float(val.as_f64().unwrap_or(f64::NAN), span), |i| Value::int(i, span)),
+        Json::String(val) => Value::string(val, span),
+        Json::Array(array) => Value::list(array.iter().map(|json| value(json, span)).collect(), span),
+        Json::Object(object) => {
+            let mut record = Record::with_capacity(object.len());
+            for (key, json) in object {
+                match json {
+                    Json::Array(array) if array.iter().any(Json::is_object) => push_repeated(&mut record, key, array, span),
+                    _ => record.push(key, value(json, span)),
+                }
+            }
+            Value::record(record, span)
+        }
+    }
+}
+
+/// Adds the elements of an array as nodes of the same name, with the `#n` suffix `to kdl` strips.
+fn push_repeated(record: &mut Record, name: &str, array: &[Json], span: Span) {
+    for (i, json) in array.iter().enumerate() {
+        let key = if i == 0 { name.to_string() } else { format!("{}#{}", name, i) };
+        record.push(key, value(json, span));
+    }
+}
+
+fn json(value: &Value) -> Json {
+    match value {
+        Value::String { val, .. } => Json::String(val.clone()),
+        Value::Int { val, .. } => Json::Number((*val).into()),
+        // JSON has no infinity nor NaN, the same as `to json` does
+        Value::Float { val, .. } => Number::from_f64(*val).map_or(Json::Null, Json::Number),
+        Value::Bool { val, .. } => Json::Bool(*val),
+        Value::List { vals, .. } => Json::Array(vals.iter().map(json).collect()),
+        Value::Record { val, .. } => {
+            let mut object = Map::with_capacity(val.len());
+            for (key, val) in val.iter() {
+                object.insert(key.clone(), json(val));
+            }
+            Json::Object(object)
+        }
+        // `from kdl` gives nothing else than the values above and null
+        _ => Json::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nu_protocol::IntoSpanned;
+
+    fn to_json_with(call: EvaluatedCall, input: &str) -> String {
+        let output = to_json(&KDL, None, &call, &Value::test_string(input)).unwrap();
+        output.into_string().unwrap()
+    }
+
+    fn flag(name: &str) -> EvaluatedCall {
+        EvaluatedCall::new(Span::test_data()).with_flag(name.to_string().into_spanned(Span::test_data()))
+    }
+
+    /// The way back, what `from json` would do, unlike `value` which makes arrays of objects
+    /// repeated nodes.
+    fn nu_json(json: &Json) -> Value {
+        match json {
+            Json::Null => Value::test_nothing(),
+            Json::Bool(b) => Value::test_bool(*b),
+            Json::Number(n) => n.as_i64().map_or_else(|| Value::test_float(n.as_f64().unwrap()), Value::test_int),
+            Json::String(s) => Value::test_string(s),
+            Json::Array(a) => Value::test_list(a.iter().map(nu_json).collect()),
+            Json::Object(o) => Value::test_record(o.iter().map(|(k, v)| (k.clone(), nu_json(v))).collect::<Record>()),
+        }
+    }
+
+    #[test]
+    fn test_to_json() {
+        let output = to_json_with(flag("raw"), r#"title "KDL"; server "main" port=8080 { tls #false }; empty #null"#);
+
+        assert_eq!(
+            output,
+            r#"{"title":"KDL","server":{"entries":["main",{"port":8080}],"children":{"tls":false}},"empty":null}"#
+        );
+    }
+
+    #[test]
+    fn test_to_json_pretty() {
+        assert_eq!(to_json_with(EvaluatedCall::new(Span::test_data()), "a 1"), "{\n  \"a\": 1\n}");
+    }
+
+    #[test]
+    fn test_to_json_and_back() {
+        let input = "title KDL\nserver main port=8080 {\n    tls #false\n    weight 0.5\n}\n";
+        let output = to_json_with(flag("raw"), input);
+
+        let value = nu_json(&serde_json::from_str(&output).unwrap());
+        let kdl = KDL.to(None, &EvaluatedCall::new(Span::test_data()), &value).unwrap();
+
+        assert_eq!(kdl.as_str().unwrap(), input);
+    }
+
+    fn from_json_kdl(json: &str) -> Result<String, LabeledError> {
+        from_json(&KDL, None, &EvaluatedCall::new(Span::test_data()), &Value::test_string(json)).map(|v| v.into_string().unwrap())
+    }
+
+    #[test]
+    fn test_from_json_objects() {
+        let output = from_json_kdl(r#"{"title": "KDL", "server": {"host": "x", "tls": {"enabled": false}}, "empty": null}"#).unwrap();
+
+        assert_eq!(output, "title KDL\nserver {\n    host x\n    tls {\n        enabled #false\n    }\n}\nempty\n");
+    }
+
+    #[test]
+    fn test_from_json_arrays() {
+        let output = from_json_kdl(r#"{"features": ["search", 2, true], "tab": [{"name": "a"}, {"name": "b", "focus": true}]}"#).unwrap();
+
+        assert_eq!(output, "features search 2 #true\ntab {\n    name a\n}\ntab {\n    name b\n    focus #true\n}\n");
+        assert_eq!(from_json_kdl(r#"[{"a": 1}, {"a": 2}]"#).unwrap(), "node {\n    a 1\n}\nnode {\n    a 2\n}\n");
+    }
+
+    #[test]
+    fn test_from_invalid_json() {
+        let error = from_json_kdl("{\"a\": ").unwrap_err();
+
+        assert!(error.msg.starts_with("invalid JSON: "), "{}", error.msg);
+    }
+}
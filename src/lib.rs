@@ -1,47 +1,396 @@
+mod append;
+mod count;
+mod diff;
+mod error;
+mod exists;
+mod flatten;
 mod from;
+mod get;
+mod interop;
+mod map_values;
+mod nodes;
+mod normalize;
+mod patch;
+mod pluck;
+mod query;
+mod set;
+mod stats;
+mod template;
 mod to;
+mod wrap;
+
+use std::{collections::HashMap, path::Path};
 
 use nu_plugin::{EngineInterface, EvaluatedCall, Plugin, PluginCommand};
-use nu_protocol::{Category, LabeledError, PipelineData, Signature, Type, Value};
+use nu_protocol::{
+    Category, IntoSpanned, LabeledError, ListStream, PipelineData, PipelineMetadata, Record, Signature, Span, Spanned, SyntaxShape,
+    Type, Value,
+};
 
 use kdl::KdlDocument;
 
+use error::KdlPluginError;
+
 pub struct KDL;
 
+/// The warning printed on stderr when `--v1-fallback` had to parse the input as KDL v1.
+pub(crate) const V1_FALLBACK_WARNING: &str = "warning: input is not valid KDL v2, parsed it as KDL v1 instead";
+
+/// The key of the custom pipeline metadata of `from kdl` holding the version, `v1` or `v2`, the
+/// document was parsed as.
+pub(crate) const VERSION_METADATA_KEY: &str = "kdl_version";
+
 impl KDL {
-    pub fn from(&self, call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
-        let input_str = input
-            .as_str()
-            .map_err(|e| LabeledError::new(format!("input is not a string: {}", e)))?;
-
-        // Check for version flags
-        let v1_fallback = call.has_flag("v1-fallback")?;
-        let force_v1 = call.has_flag("v1")?;
-
-        let doc = if force_v1 {
-            // Explicitly parse as KDL v1
-            KdlDocument::parse_v1(input_str)
-                .map_err(|e| LabeledError::new(format!("invalid KDL v1 format: {}", e)))?
-        } else if v1_fallback {
-            // Try v2, if that fails, try v1
-            match input_str.parse::<KdlDocument>() {
-                Ok(doc) => doc,
-                Err(_) => KdlDocument::parse_v1(input_str)
-                    .map_err(|e| LabeledError::new(format!("invalid KDL format (tried v2 and v1): {}", e)))?
+    /// Converts a KDL document to a record, warning through `engine` if given when the document
+    /// had to be parsed as KDL v1.
+    pub fn from(&self, engine: Option<&EngineInterface>, call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+        self.from_versioned(engine, call, input).map(|(value, _)| value)
+    }
+
+    /// Same as [`KDL::from`], also giving the version the document was parsed as, `v1` or `v2`.
+    pub fn from_versioned(
+        &self,
+        engine: Option<&EngineInterface>,
+        call: &EvaluatedCall,
+        input: &Value,
+    ) -> Result<(Value, &'static str), LabeledError> {
+        let (doc, fell_back) = match self.parse(call, input) {
+            Ok(parsed) => parsed,
+            Err(error) if call.has_flag("best-effort")? => {
+                let (parsed, lines) = self.parse_prefix(call, input).ok_or(error)?;
+                if let Some(engine) = engine {
+                    let warning = format!("warning: input is not a valid KDL document, only its first {} lines were parsed", lines);
+                    warn(engine, &warning, input.span());
+                }
+                parsed
             }
-        } else {
-            // Default: strict v2 only
-            input_str.parse::<KdlDocument>()
-                .map_err(|e| LabeledError::new(format!("invalid KDL v2 format: {}", e)))?
+            Err(error) => return Err(error),
+        };
+        if let (Some(engine), true) = (engine, fell_back) {
+            warn(engine, V1_FALLBACK_WARNING, input.span());
+        }
+        let version = if fell_back || call.has_flag("v1")? { "v1" } else { "v2" };
+
+        if call.has_flag("doc-comments")? {
+            return Ok((from::doc_comments(&doc, input.span()), version));
+        }
+
+        if call.has_flag("error-on-unknown-annotation")? {
+            let allowed: Vec<String> = call.get_flag("allowed-annotations")?.unwrap_or_default();
+            from::check_annotations(&doc, &allowed)?;
+        }
+
+        let options = from::ParseOptions {
+            trim_whitespace: call.has_flag("trim-whitespace")?,
+            numbers_as_strings: call.has_flag("numbers-as-strings")?,
+            entries_key: call.get_flag("entries-key")?.unwrap_or_else(|| from::ENTRIES_KEY.to_string()),
+            children_key: call.get_flag("children-key")?.unwrap_or_else(|| from::CHILDREN_KEY.to_string()),
+            flatten: call.has_flag("flatten")?,
+            uniform: call.has_flag("uniform")?,
+            preserve_number_repr: call.has_flag("preserve-number-repr")?,
+            keep_annotations: call.has_flag("keep-annotations")?,
+            ints_as_floats: call.has_flag("ints-as-floats")?,
+            args_key: call.get_flag("args-key")?,
+            ordered_entries: call.has_flag("ordered-entries")?,
+            keep_props: call.has_flag("keep-props")?,
+            merge_props_into_children: call.has_flag("merge-props-into-children")?,
+            fields: call.get_flag("fields")?,
+            node_filter: match call.get_flag::<Spanned<String>>("node-filter")? {
+                Some(glob) => Some(
+                    nu_glob::Pattern::new(&glob.item)
+                        .map_err(|error| KdlPluginError::InvalidPattern { error, span: glob.span })?,
+                ),
+                None => None,
+            },
+            depth: call.get_flag("depth")?,
+            with_spans: call.has_flag("with-spans")?,
+            property_prefix: call.get_flag("property-prefix")?,
+            tag_shape: call.has_flag("tag-shape")?,
+            key_by_first_arg: call.has_flag("key-by-first-arg")?,
+            rename: call
+                .get_flag::<Record>("rename")?
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(name, key)| Ok((name, key.into_string()?)))
+                .collect::<Result<_, LabeledError>>()?,
+        };
+
+        let source_len = text(input).map_or(0, str::len);
+        let value = from::parse_document(&doc, source_len, &options);
+        let value = match call.get_flag::<Value>("schema")? {
+            Some(schema) => from::apply_schema(value, &schema, "")?,
+            None => value,
         };
+        Ok((value, version))
+    }
+
+    /// Parses a KDL document, as v2 unless `--v1` or `--v1-fallback` are given, and tells whether
+    /// `--v1-fallback` had to fall back to v1.
+    pub fn parse(&self, call: &EvaluatedCall, input: &Value) -> Result<(KdlDocument, bool), LabeledError> {
+        let input_str = text(input)?;
+        let (force_v1, v1_fallback) = (call.has_flag("v1")?, call.has_flag("v1-fallback")?);
+
+        parse_text(input_str, force_v1, v1_fallback)
+            .map_err(|(version, error)| KdlPluginError::ParseFailed { version, error, span: input.span() }.into())
+    }
+
+    /// Parses the longest valid prefix of whole lines of a document, giving the number of lines it
+    /// has, for `--best-effort` to recover what comes before stray text or a truncated node. The
+    /// prefix ends before the line of the first error, a few lines more being given up when what
+    /// comes before still doesn't parse, e.g. a block the error left open.
+    fn parse_prefix(&self, call: &EvaluatedCall, input: &Value) -> Option<((KdlDocument, bool), usize)> {
+        const ATTEMPTS: usize = 4;
+
+        let input_str = text(input).ok()?;
+        let (force_v1, v1_fallback) = (call.has_flag("v1").ok()?, call.has_flag("v1-fallback").ok()?);
+
+        let mut end = input_str.len();
+        for _ in 0..ATTEMPTS {
+            let prefix = &input_str[..end];
+            let error = match parse_text(prefix, force_v1, v1_fallback) {
+                Ok(parsed) => return Some((parsed, prefix.matches('\n').count())),
+                Err((_, error)) => error,
+            };
+            let offset = error.diagnostics.iter().map(|diagnostic| diagnostic.span.offset()).min()?.min(end);
+            // the start of the line of the error, or of the line before when the prefix ends there
+            let line_start = |at: usize| input_str[..at].rfind('\n').map_or(0, |i| i + 1);
+            end = match line_start(offset) {
+                start if start < end => start,
+                _ => line_start(end.saturating_sub(1)),
+            };
+            if end == 0 {
+                return None;
+            }
+        }
+
+        None
+    }
+
+    /// Lazily converts every KDL document of a stream, turning the elements that fail to convert
+    /// into error values rather than failing the whole stream.
+    pub fn from_stream(&self, engine: Option<&EngineInterface>, call: &EvaluatedCall, stream: ListStream) -> ListStream {
+        let engine = engine.cloned();
+        let call = call.clone();
+        stream.map(move |value| {
+            let span = value.span();
+            KDL.from(engine.as_ref(), &call, &value).unwrap_or_else(|e| Value::error(e.into(), span))
+        })
+    }
+
+    pub fn to(&self, engine: Option<&EngineInterface>, call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+        let wrap: Option<String> = call.get_flag("wrap")?;
+        let on_error = choice(call, "on-error", &[("skip", true), ("fail", false)], "skip or fail")?;
+        // only the rows of --wrap are converted one by one, any other value failing as a whole
+        if on_error.is_some() && wrap.is_none() {
+            return Err(KdlPluginError::MissingFlag { name: "wrap", span: call.head }.into());
+        }
+        let skip = on_error.unwrap_or(false);
+
+        let mut type_map = HashMap::new();
+        for (ty, annotation) in call.get_flag::<Record>("type-map")?.unwrap_or_default() {
+            let value = annotation.as_str().map_err(|_| KdlPluginError::InvalidAnnotation {
+                ty: annotation.get_type(),
+                span: annotation.span(),
+            })?;
+            type_map.insert(ty, value.to_string());
+        }
+
+        let options = to::BuildOptions {
+            annotate_types: call.has_flag("annotate-types")?,
+            node_name: call.get_flag("node-name")?.unwrap_or_else(|| to::DEFAULT_NODE_NAME.to_string()),
+            escape_nonascii: call.has_flag("escape-nonascii")?,
+            quote_all_strings: call.has_flag("quote-all-strings")?,
+            identifier_policy: choice(
+                call,
+                "identifier-policy",
+                &[("error", to::IdentifierPolicy::Error), ("quote", to::IdentifierPolicy::Quote), ("replace", to::IdentifierPolicy::Replace)],
+                "error, quote or replace",
+            )?
+            .unwrap_or_default(),
+            type_map,
+            dedent: call.has_flag("dedent")?,
+            record_as: choice(call, "record-as", &[("props", to::RecordAs::Props), ("children", to::RecordAs::Children)], "props or children")?
+                .unwrap_or_default(),
+            table_as: choice(
+                call,
+                "table-as",
+                &[("entries", to::TableAs::Entries), ("nodes", to::TableAs::Nodes), ("singular-nodes", to::TableAs::SingularNodes)],
+                "entries, nodes or singular-nodes",
+            )?
+            .unwrap_or_default(),
+            values_as_args: call.has_flag("values-as-args")?,
+            bool_as_int: call.has_flag("bool-as-int")?,
+            root_nodes_from_list: call.has_flag("root-nodes-from-list")?,
+            allow_duplicate_keys: call.has_flag("allow-duplicate-keys")?,
+            preserve_empty_children: call.has_flag("preserve-empty-children")?,
+            null_omit: call.has_flag("null-omit")?,
+            arg_order_from_key_suffix: call.has_flag("arg-order-from-key-suffix")?,
+            duration_format: choice(
+                call,
+                "duration-format",
+                &[("nanos", to::DurationFormat::Nanos), ("iso", to::DurationFormat::Iso), ("human", to::DurationFormat::Human)],
+                "nanos, iso or human",
+            )?
+            .unwrap_or_default(),
+            child_node_name: call
+                .get_flag::<Record>("child-node-name")?
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(field, name)| Ok((field, name.into_string()?)))
+                .collect::<Result<_, LabeledError>>()?,
+            property_prefix: call.get_flag("property-prefix")?,
+            epoch_fields: call.get_flag("epoch-fields")?.unwrap_or_default(),
+            max_depth: match call.get_flag::<i64>("max-depth")? {
+                Some(depth) => depth.max(0) as usize,
+                None => to::DEFAULT_MAX_DEPTH,
+            },
+        };
+
+        to::check_depth(input, options.max_depth)?;
+        if call.has_flag("strict-identifiers")? {
+            let mut names = Vec::new();
+            to::invalid_identifiers(input, &mut names);
+            if !names.is_empty() {
+                return Err(KdlPluginError::InvalidIdentifiers { names, span: input.span() }.into());
+            }
+        }
+
+        let mut document = match (wrap, input) {
+            // a table is a list of rows, each of them becomes a node of the same name
+            (Some(name), Value::List { vals, .. }) => {
+                let mut document = KdlDocument::new();
+                for (i, row) in vals.iter().enumerate() {
+                    match to::build_node(&name, row, &options) {
+                        Ok(node) => document.nodes_mut().push(node),
+                        Err(error) if skip => {
+                            if let Some(engine) = engine {
+                                warn(engine, &format!("warning: skipped row {}: {}", i, error.msg), row.span());
+                            }
+                        }
+                        Err(error) => return Err(error),
+                    }
+                }
+                document
+            }
+            _ => to::build_document(input, &options)?,
+        };
+        document.autoformat();
+        to::close_empty_blocks(&mut document);
+        to::quote_strings(&mut document, &options);
+        to::slashdash_disabled(&mut document);
+        let threshold = match call.get_flag::<i64>("multiline-threshold")? {
+            Some(threshold) => Some(threshold.max(0) as usize),
+            None if call.has_flag("inline-children")? => Some(usize::MAX),
+            None => None,
+        };
+        if let Some(threshold) = threshold {
+            to::inline_children(&mut document, threshold);
+        }
+        let oneline = call.has_flag("oneline")?;
+        if oneline {
+            to::oneline(&mut document);
+        }
+        let v1 = call.has_flag("v1")?;
+        if v1 {
+            // ensure_v1 would write the closest finite numbers instead, e.g. 0.0 for NaN
+            if let Some(Value::Float { val, internal_span, .. }) = to::non_finite_float(input) {
+                return Err(KdlPluginError::NonFiniteInV1 { value: *val, span: *internal_span }.into());
+            }
+            // e.g. `null` and `true` rather than `#null` and `#true`
+            document.ensure_v1();
+        }
+
+        let mut output = final_newline(document.to_string(), !oneline && !call.has_flag("no-final-newline")?);
+        if call.has_flag("emit-version-comment")? {
+            // a comment, so it's read back like the document without it, whatever the version
+            let version = if v1 { "1" } else { "2" };
+            let comment = if oneline { format!("/* kdl-version: {} */ ", version) } else { format!("// kdl-version: {}\n", version) };
+            output.insert_str(0, &comment);
+        }
+        Ok(Value::string(output, call.head))
+    }
+}
+
+/// Prints a warning on stderr through Nushell's own `print --stderr`, so it shows up in the
+/// terminal without failing the pipeline. The plugin protocol has no warnings of its own, and a
+/// warning that can't be printed is not worth an error.
+fn warn(engine: &EngineInterface, message: &str, span: Span) {
+    if let Ok(Some(print)) = engine.find_decl("print") {
+        let call = EvaluatedCall::new(span)
+            .with_positional(Value::string(message, span))
+            .with_flag("stderr".to_string().into_spanned(span));
+        let _ = engine.call_decl(print, call, PipelineData::empty(), false, false);
+    }
+}
+
+/// The metadata of a `from kdl` output, which tells the version the document was parsed as
+/// without changing the shape of the value.
+fn version_metadata(version: &str, span: Span) -> PipelineMetadata {
+    let mut metadata = PipelineMetadata::default();
+    metadata.custom.insert(VERSION_METADATA_KEY, Value::string(version, span));
+    metadata
+}
+
+/// The value of a flag which takes one of a few names, `expected` listing them for the error.
+fn choice<T: Copy>(call: &EvaluatedCall, flag: &'static str, choices: &[(&str, T)], expected: &'static str) -> Result<Option<T>, LabeledError> {
+    let Some(name) = call.get_flag::<Spanned<String>>(flag)? else { return Ok(None) };
 
-        Ok(from::parse_document(&doc))
+    match choices.iter().find(|(choice, _)| *choice == name.item) {
+        Some((_, value)) => Ok(Some(*value)),
+        None => Err(KdlPluginError::InvalidChoice { flag, value: name.item, choices: expected, span: name.span }.into()),
     }
+}
+
+/// Parses a document as v2 unless `force_v1` or `v1_fallback`, telling whether it fell back to
+/// v1, the error being the version it failed as and why.
+fn parse_text(input: &str, force_v1: bool, v1_fallback: bool) -> Result<(KdlDocument, bool), (&'static str, kdl::KdlError)> {
+    if force_v1 {
+        // Explicitly parse as KDL v1
+        KdlDocument::parse_v1(input).map(|doc| (doc, false)).map_err(|e| ("v1", e))
+    } else if v1_fallback {
+        // Try v2, if that fails, try v1
+        match input.parse::<KdlDocument>() {
+            Ok(doc) => Ok((doc, false)),
+            Err(_) => KdlDocument::parse_v1(input).map(|doc| (doc, true)).map_err(|e| ("v2 and v1", e)),
+        }
+    } else {
+        // Default: strict v2 only
+        input.parse::<KdlDocument>().map(|doc| (doc, false)).map_err(|e| ("v2", e))
+    }
+}
+
+/// The text of a document, which is binary rather than a string when read with e.g. `open --raw`.
+fn text(input: &Value) -> Result<&str, KdlPluginError> {
+    match input {
+        Value::Binary { val, .. } => std::str::from_utf8(val).map_err(|error| KdlPluginError::InvalidUtf8 {
+            offset: error.valid_up_to(),
+            span: input.span(),
+        }),
+        _ => input.as_str().map_err(|_| KdlPluginError::NotAString {
+            ty: input.get_type(),
+            span: input.span(),
+        }),
+    }
+}
+
+/// Reads a file given to a command, relative paths being relative to the working directory of
+/// the caller, which `cwd` is, not to the one of the plugin process.
+pub(crate) fn read_file(cwd: &Path, path: &Spanned<String>) -> Result<String, KdlPluginError> {
+    let full = cwd.join(&path.item);
+    std::fs::read_to_string(&full).map_err(|error| KdlPluginError::ReadFailed {
+        path: full,
+        error,
+        span: path.span,
+    })
+}
 
-    pub fn to(&self, call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
-        let document = to::build_document(input)?;
-        Ok(Value::string(document.to_string(), call.head))
+/// Ends a non-empty output with exactly one newline, or with none at all.
+pub(crate) fn final_newline(mut output: String, final_newline: bool) -> String {
+    output.truncate(output.trim_end_matches('\n').len());
+    if final_newline && !output.is_empty() {
+        output.push('\n');
     }
+    output
 }
 
 pub struct FromKdl;
@@ -53,7 +402,27 @@ impl Plugin for KDL {
     }
 
     fn commands(&self) -> Vec<Box<dyn PluginCommand<Plugin = Self>>> {
-        vec![Box::new(FromKdl), Box::new(ToKdl)]
+        vec![
+            Box::new(FromKdl),
+            Box::new(ToKdl),
+            Box::new(get::KdlGet),
+            Box::new(diff::KdlDiff),
+            Box::new(interop::KdlToJson),
+            Box::new(interop::KdlFromJson),
+            Box::new(append::KdlAppend),
+            Box::new(template::KdlTemplate),
+            Box::new(nodes::KdlNodes),
+            Box::new(set::KdlSet),
+            Box::new(wrap::KdlWrap),
+            Box::new(stats::KdlStats),
+            Box::new(pluck::KdlPluck),
+            Box::new(flatten::KdlFlattenPaths),
+            Box::new(normalize::KdlNormalize),
+            Box::new(patch::KdlPatch),
+            Box::new(count::KdlCount),
+            Box::new(exists::KdlExists),
+            Box::new(map_values::KdlMapValues),
+        ]
     }
 }
 
@@ -70,22 +439,122 @@ impl PluginCommand for FromKdl {
 
     fn signature(&self) -> Signature {
         Signature::build(PluginCommand::name(self))
-            .input_output_type(Type::String, Type::Record(vec![].into()))
+            .input_output_types(vec![
+                (Type::String, Type::Record(vec![].into())),
+                (Type::Binary, Type::Record(vec![].into())),
+                (Type::List(Box::new(Type::String)), Type::List(Box::new(Type::Record(vec![].into())))),
+            ])
             .switch("v1", "Force parsing as KDL v1 only", Some('1'))
-            .switch("v1-fallback", "Try KDL v2, fall back to v1 if parsing fails", None)
+            .switch("v1-fallback", "Try KDL v2, fall back to v1 with a warning if parsing fails", None)
+            .switch("trim-whitespace", "Trim leading and trailing whitespace from string values", None)
+            .switch("numbers-as-strings", "Keep numbers as the exact text they are written with", None)
+            .named(
+                "entries-key",
+                SyntaxShape::String,
+                "Key of the entries of nodes with both entries and children (default: entries)",
+                None,
+            )
+            .named(
+                "children-key",
+                SyntaxShape::String,
+                "Key of the children of nodes with both entries and children (default: children)",
+                None,
+            )
+            .switch("flatten", "Turn every node into {args, props, children} for a lossless round trip", None)
+            .switch("uniform", "Turn every node into {args, props, children}, all three always there even when empty", None)
+            .switch(
+                "preserve-number-repr",
+                "Keep the source text of numbers like 1.50, +5 or -0 as {$value, $repr}, and integers too big for nu as {$bignum}, for to kdl to write them back as is",
+                None,
+            )
+            .switch(
+                "keep-annotations",
+                "Keep type annotations like (u8)255 as {$type, $value} and the ones of nodes as $node_type, for to kdl to write them back",
+                None,
+            )
+            .switch(
+                "ints-as-floats",
+                "Turn every integer into a float, only exact up to 2^53, e.g. 9007199254740993 isn't",
+                None,
+            )
+            .named(
+                "args-key",
+                SyntaxShape::String,
+                "Put the arguments of every node in a list under this key, e.g. args, next to its properties",
+                None,
+            )
+            .switch(
+                "ordered-entries",
+                "Like --flatten, with the arguments and properties in a single entries table, in their order",
+                None,
+            )
+            .switch(
+                "keep-props",
+                "Keep the properties of a node in a props record, even a lone one, instead of collapsing them like children",
+                None,
+            )
+            .switch(
+                "merge-props-into-children",
+                "Put the properties of nodes with children in the children record, a child called like one getting a #1 suffix",
+                None,
+            )
+            .named(
+                "fields",
+                SyntaxShape::List(Box::new(SyntaxShape::String)),
+                "Only keep the properties with one of these names, and the arguments",
+                None,
+            )
+            .named(
+                "property-prefix",
+                SyntaxShape::String,
+                "Prefix the keys of properties, e.g. @ for {@port: 8080}, so they can't be taken for children",
+                None,
+            )
+            .named(
+                "schema",
+                SyntaxShape::Any,
+                "The types of the nodes, e.g. {server: {port: int}}, to convert them to or fail on a mismatch or a missing one",
+                None,
+            )
+            .named(
+                "allowed-annotations",
+                SyntaxShape::List(Box::new(SyntaxShape::String)),
+                "The annotations --error-on-unknown-annotation allows besides the types and the number types of KDL",
+                None,
+            )
+            .switch("error-on-unknown-annotation", "Fail on a type annotation which isn't a known one, e.g. (secret)\"x\"", None)
+            .switch("key-by-first-arg", "Give the top-level nodes the key of their first argument, e.g. {web: ...} for resource web { ... }", None)
+            .switch("tag-shape", "Tag the value of every node with its __shape: scalar, list, record or node", None)
+            .switch("doc-comments", "Give a table of the comments before the nodes, {node, comment}, rather than their values", None)
+            .switch("with-spans", "Put every node and entry in a {value, span: {start, end}} record with its byte offsets", None)
+            .named("rename", SyntaxShape::Record(vec![]), "The keys to give the nodes of some names instead, e.g. {pane: panel}", None)
+            .named("node-filter", SyntaxShape::String, "Only convert the top-level nodes whose name matches this glob", None)
+            .switch("best-effort", "Convert the valid beginning of a broken document with a warning, instead of failing", None)
+            .named(
+                "depth",
+                SyntaxShape::Int,
+                "Only convert this many levels of children, replacing the deeper ones with \"…\"",
+                None,
+            )
             .category(Category::Experimental)
     }
 
     fn run(
         &self,
         plugin: &KDL,
-        _engine: &EngineInterface,
+        engine: &EngineInterface,
         call: &EvaluatedCall,
         input: PipelineData,
     ) -> Result<PipelineData, LabeledError> {
+        if let PipelineData::ListStream(stream, metadata) = input {
+            return Ok(PipelineData::ListStream(plugin.from_stream(Some(engine), call, stream), metadata));
+        }
+
+        // the elements of a stream could each have their own version, so only a single document
+        // has it in its metadata
         let value = input.into_value(call.head)?;
-        let result = plugin.from(call, &value)?;
-        Ok(PipelineData::Value(result, None))
+        let (result, version) = plugin.from_versioned(Some(engine), call, &value)?;
+        Ok(PipelineData::Value(result, Some(version_metadata(version, call.head))))
     }
 }
 
@@ -102,19 +571,108 @@ impl PluginCommand for ToKdl {
 
     fn signature(&self) -> Signature {
         Signature::build(PluginCommand::name(self))
-            .input_output_type(Type::Record(vec![].into()), Type::String)
+            .input_output_type(Type::Any, Type::String)
+            .switch(
+                "annotate-types",
+                "Annotate non-string values with their type, e.g. (int)1, for a lossless round trip",
+                None,
+            )
+            .named(
+                "type-map",
+                SyntaxShape::Record(vec![]),
+                "The annotations of --annotate-types for nu types, e.g. {int: i32}, the type name by default",
+                None,
+            )
+            .named(
+                "node-name",
+                SyntaxShape::String,
+                "Name of the node to put the input in when it isn't a record (default: node)",
+                Some('n'),
+            )
+            .switch("no-final-newline", "Do not end the output with a newline", None)
+            .switch("quote-all-strings", "Quote every string, even those which could be bare words", None)
+            .switch("inline-children", "Put every children block on a single line, e.g. node { a 1; b 2 }", None)
+            .named(
+                "multiline-threshold",
+                SyntaxShape::Int,
+                "Put the children blocks with at most this many nodes on a single line, the others on several",
+                None,
+            )
+            .switch("v1", "Write KDL v1 rather than v2, e.g. null rather than #null", Some('1'))
+            .named("max-depth", SyntaxShape::Int, "Fail on values with more levels of records and lists than this (default: 128)", None)
+            .named(
+                "epoch-fields",
+                SyntaxShape::List(Box::new(SyntaxShape::String)),
+                "Write the integers of these keys, seconds since the unix epoch, as (date) strings",
+                None,
+            )
+            .named("property-prefix", SyntaxShape::String, "Write the keys with this prefix, e.g. @ for {@port: 8080}, as properties", None)
+            .named(
+                "duration-format",
+                SyntaxShape::String,
+                "How to write durations: nanos (the default), iso for (duration)PT5M or human for (duration)\"5min\"",
+                None,
+            )
+            .named(
+                "child-node-name",
+                SyntaxShape::Record(vec![]),
+                "Write the rows of the tables of some fields as children of these names, e.g. {routes: route}",
+                None,
+            )
+            .switch("arg-order-from-key-suffix", "Write the records with the keys arg0, arg1, ... as the arguments of their node, in order", None)
+            .switch("oneline", "Write the whole document on a single line, its nodes separated by ; and their children inline", None)
+            .switch("null-omit", "Leave out the fields which are null, rather than writing them as bare nodes", None)
+            .switch("emit-version-comment", "Start the output with a // kdl-version: 2 comment, or 1 with --v1", None)
+            .switch("preserve-empty-children", "Write the fields which are empty records as `node {}` rather than a bare `node`", None)
+            .switch("allow-duplicate-keys", "Write a list of [key, value] pairs like [[pane a] [pane b]] as top-level nodes", None)
+            .switch("root-nodes-from-list", "Write a list of single-key records like [{pane: a}, {pane: b}] as top-level nodes", None)
+            .switch("bool-as-int", "Write booleans as 1 and 0 rather than #true and #false", None)
+            .switch("values-as-args", "Write the records with the keys 0, 1, ... as the arguments of their node", None)
+            .switch("strict-identifiers", "Fail listing all the keys KDL can't have as names, before converting anything", None)
+            .switch("dedent", "Remove the indentation the lines of multi-line strings have in common", None)
+            .switch("escape-nonascii", "Write non-ASCII characters of strings as \\u{...} escapes", None)
+            .named(
+                "identifier-policy",
+                SyntaxShape::String,
+                "What to do with names which have spaces: error, quote (the default) or replace them with _",
+                None,
+            )
+            .named(
+                "wrap",
+                SyntaxShape::String,
+                "Turn each element of a list into a node of this name, e.g. each row of a table",
+                None,
+            )
+            .named(
+                "record-as",
+                SyntaxShape::String,
+                "Write the scalar fields of a record as properties of its node with props, or as children (the default)",
+                None,
+            )
+            .named(
+                "table-as",
+                SyntaxShape::String,
+                "Write the fields which are tables as entries (the default), nodes per row named after the field, or singular-nodes",
+                None,
+            )
+            .named(
+                "on-error",
+                SyntaxShape::String,
+                "With --wrap, skip the elements which can't be converted with a warning, or fail (the default)",
+                None,
+            )
             .category(Category::Experimental)
     }
 
     fn run(
         &self,
         plugin: &KDL,
-        _engine: &EngineInterface,
+        engine: &EngineInterface,
         call: &EvaluatedCall,
         input: PipelineData,
     ) -> Result<PipelineData, LabeledError> {
         let value = input.into_value(call.head)?;
-        let result = plugin.to(call, &value)?;
+        let result = plugin.to(Some(engine), call, &value)?;
         Ok(PipelineData::Value(result, None))
     }
 }
@@ -123,6 +681,24 @@ impl PluginCommand for ToKdl {
 mod tests {
     use super::*;
 
+    use nu_protocol::Signals;
+
+    #[test]
+    fn test_read_file_relative_to_cwd() {
+        let cwd = std::env::temp_dir().join(format!("nu_plugin_kdl-{}", std::process::id()));
+        std::fs::create_dir_all(cwd.join("conf")).unwrap();
+        std::fs::write(cwd.join("conf/a.kdl"), "a 1").unwrap();
+
+        let relative = read_file(&cwd, &"conf/a.kdl".to_string().into_spanned(Span::test_data()));
+        let absolute = read_file(Path::new("/"), &cwd.join("conf/a.kdl").display().to_string().into_spanned(Span::test_data()));
+        let missing = read_file(&cwd, &"a.kdl".to_string().into_spanned(Span::test_data()));
+        std::fs::remove_dir_all(&cwd).unwrap();
+
+        assert_eq!(relative.unwrap(), "a 1");
+        assert_eq!(absolute.unwrap(), "a 1");
+        assert!(matches!(missing, Err(KdlPluginError::ReadFailed { path, .. }) if path == cwd.join("a.kdl")));
+    }
+
     #[test]
     fn test_parse_zellij_layout_v1() {
         // Zellij layout files use KDL v1 syntax, so we need to use parse_v1
@@ -144,6 +720,36 @@ mod tests {
         assert!(result.is_ok(), "Failed to parse zellij-layout.kdl: {:?}", result.err());
     }
 
+    #[test]
+    fn test_zellij_layout_shape() {
+        let call = EvaluatedCall::new(Span::test_data()).with_flag("v1".to_string().into_spanned(Span::test_data()));
+        let value = KDL.from(None, &call, &Value::test_string(include_str!("../zellij-layout.kdl"))).unwrap();
+
+        let layout = value.get_data_by_key("layout").unwrap();
+        assert_eq!(layout.as_record().unwrap().columns().collect::<Vec<_>>(), ["default_tab_template", "tab"]);
+
+        // the second `pane` replaces the first one, and `children` is a node without entries
+        let template = layout.get_data_by_key("default_tab_template").unwrap();
+        assert_eq!(template.as_record().unwrap().columns().collect::<Vec<_>>(), ["pane", "children"]);
+        assert_eq!(template.get_data_by_key("children").unwrap(), Value::test_nothing());
+        let pane = template.get_data_by_key("pane").unwrap();
+        assert_eq!(
+            pane.get_data_by_key("entries").unwrap(),
+            Value::test_list(vec![
+                Value::test_record(nu_protocol::record! { "size" => Value::test_int(2) }),
+                Value::test_record(nu_protocol::record! { "borderless" => Value::test_bool(true) }),
+            ])
+        );
+        let plugin = pane.get_data_by_key("children").unwrap().get_data_by_key("plugin").unwrap();
+        assert_eq!(plugin, Value::test_record(nu_protocol::record! { "location" => Value::test_string("zellij:status-bar") }));
+
+        let tab = layout.get_data_by_key("tab").unwrap();
+        assert_eq!(tab.get_data_by_key("entries").unwrap().into_list().unwrap()[0], Value::test_record(nu_protocol::record! { "name" => Value::test_string("logs") }));
+        let pane = tab.get_data_by_key("children").unwrap().get_data_by_key("pane").unwrap();
+        assert_eq!(pane.get_data_by_key("entries").unwrap(), Value::test_record(nu_protocol::record! { "split_direction" => Value::test_string("horizontal") }));
+        assert_eq!(pane.get_data_by_key("children").unwrap(), Value::test_record(Record::new()));
+    }
+
     #[test]
     fn test_parse_simple_kdl() {
         let input = r#"node1 "value1"
@@ -217,6 +823,273 @@ node2 123"#;
         assert!(fallback_result.is_ok(), "Fallback should work: {:?}", fallback_result.err());
     }
 
+    #[test]
+    fn test_from_list_stream() {
+        let call = EvaluatedCall::new(Span::test_data());
+        let documents = vec![
+            Value::test_string(r#"first "a""#),
+            Value::test_string(r#"second "b""#),
+            Value::test_int(1),
+        ];
+        let stream = ListStream::new(documents.into_iter(), Span::test_data(), Signals::empty());
+
+        let results: Vec<Value> = KDL.from_stream(None, &call, stream).into_iter().collect();
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].get_data_by_key("first").unwrap().as_str().unwrap(), "a");
+        assert_eq!(results[1].get_data_by_key("second").unwrap().as_str().unwrap(), "b");
+        assert!(results[2].is_error(), "non-string element should become an error value");
+    }
+
+    #[test]
+    fn test_v1_fallback_is_reported() {
+        let call = EvaluatedCall::new(Span::test_data()).with_flag("v1-fallback".to_string().into_spanned(Span::test_data()));
+
+        let (_, fell_back) = KDL.parse(&call, &Value::test_string("node size=1 enabled=true")).unwrap();
+        assert!(fell_back, "a v1 only document should be reported as a fallback");
+
+        let (_, fell_back) = KDL.parse(&call, &Value::test_string("node size=1 enabled=#true")).unwrap();
+        assert!(!fell_back, "a v2 document should not be reported as a fallback");
+    }
+
+    #[test]
+    fn test_version_metadata() {
+        let call = EvaluatedCall::new(Span::test_data()).with_flag("v1-fallback".to_string().into_spanned(Span::test_data()));
+
+        let (value, version) = KDL.from_versioned(None, &call, &Value::test_string("node enabled=true")).unwrap();
+        let metadata = version_metadata(version, Span::test_data());
+
+        assert_eq!(value.get_data_by_key("node").unwrap().get_data_by_key("enabled").unwrap(), Value::test_bool(true));
+        assert_eq!(metadata.custom.get(VERSION_METADATA_KEY), Some(&Value::test_string("v1")));
+
+        let (_, version) = KDL.from_versioned(None, &call, &Value::test_string("node enabled=#true")).unwrap();
+        assert_eq!(version, "v2");
+    }
+
+    #[test]
+    fn test_slashdashed_first_node() {
+        // kdl drops slashdashed nodes from the document, in both versions, so they never show up
+        for (flag, input) in [(None, "/- disabled 1 { child 2 }\nlive 3\n"), (Some("v1"), "/- disabled 1 { child 2; }\nlive 3\n")] {
+            let mut call = EvaluatedCall::new(Span::test_data());
+            if let Some(flag) = flag {
+                call = call.with_flag(flag.to_string().into_spanned(Span::test_data()));
+            }
+
+            let value = KDL.from(None, &call, &Value::test_string(input)).unwrap();
+
+            assert_eq!(value.as_record().unwrap().columns().collect::<Vec<_>>(), ["live"]);
+            assert_eq!(value.get_data_by_key("live").unwrap(), Value::test_int(3));
+        }
+    }
+
+    #[test]
+    fn test_v1_document_hint() {
+        let error = KDL.from(None, &EvaluatedCall::new(Span::test_data()), &Value::test_string("node true")).unwrap_err();
+
+        assert!(error.help.unwrap().ends_with("this looks like KDL v1, try --v1 or --v1-fallback"));
+    }
+
+    #[test]
+    fn test_byte_order_mark() {
+        // both parsers of kdl already skip a leading BOM, keeping the offsets of the input
+        let input = "\u{feff}title \"KDL\"\nport 8080";
+
+        for flag in [None, Some("v1")] {
+            let mut call = EvaluatedCall::new(Span::test_data());
+            if let Some(flag) = flag {
+                call = call.with_flag(flag.to_string().into_spanned(Span::test_data()));
+            }
+
+            let value = KDL.from(None, &call, &Value::test_string(input)).unwrap();
+
+            assert_eq!(value.get_data_by_key("title").unwrap().as_str().unwrap(), "KDL");
+            let port = value.get_data_by_key("port").unwrap();
+            assert_eq!(port.as_int().unwrap(), 8080);
+            assert_eq!(&input[port.span().start..port.span().end], "8080");
+        }
+    }
+
+    #[test]
+    fn test_crlf() {
+        // both parsers of kdl take `\r\n` as a newline, keeping the offsets of the input
+        let documents = [(None, "node1 \"value1\"\nnode2 123\nparent {\n    child value\n}\n"), (Some("v1"), "pane size=1 borderless=true\npane size=2 {\n    child \"x\"\n}\n")];
+
+        for (flag, lf) in documents {
+            let mut call = EvaluatedCall::new(Span::test_data());
+            if let Some(flag) = flag {
+                call = call.with_flag(flag.to_string().into_spanned(Span::test_data()));
+            }
+            let crlf = lf.replace('\n', "\r\n");
+
+            let expected = KDL.from(None, &call, &Value::test_string(lf)).unwrap();
+            let value = KDL.from(None, &call, &Value::test_string(&crlf)).unwrap();
+
+            assert_eq!(value, expected);
+            let (_, last) = value.as_record().unwrap().iter().next_back().unwrap();
+            let child = last.get_data_by_key("children").unwrap_or_else(|| last.clone());
+            let child = child.get_data_by_key("child").unwrap();
+            assert_eq!(&crlf[child.span().start..child.span().end].trim_matches('"'), &child.as_str().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_best_effort() {
+        let input = Value::test_string("title \"KDL\"\nserver {\n    port 8080\n}\n}} garbage {\nlast 1\n");
+
+        assert!(KDL.from(None, &EvaluatedCall::new(Span::test_data()), &input).is_err());
+
+        let call = EvaluatedCall::new(Span::test_data()).with_flag("best-effort".to_string().into_spanned(Span::test_data()));
+        let value = KDL.from(None, &call, &input).unwrap();
+
+        assert_eq!(value.get_data_by_key("title").unwrap().as_str().unwrap(), "KDL");
+        assert_eq!(value.get_data_by_key("server").unwrap().get_data_by_key("port").unwrap().as_int().unwrap(), 8080);
+        assert!(value.get_data_by_key("last").is_none());
+    }
+
+    #[test]
+    fn test_best_effort_truncated_document() {
+        let call = EvaluatedCall::new(Span::test_data()).with_flag("best-effort".to_string().into_spanned(Span::test_data()));
+        let long: String = (0..1000).map(|i| format!("node{} {}\n", i, i)).collect();
+
+        // a block left open, which the error is at the end of
+        let value = KDL.from(None, &call, &Value::test_string("a 1\nb 2\nc {\n    d 3\n")).unwrap();
+        assert_eq!(value.as_record().unwrap().columns().collect::<Vec<_>>(), ["a", "b"]);
+
+        let value = KDL.from(None, &call, &Value::test_string(format!("{}\"unterminated\n", long))).unwrap();
+        assert_eq!(value.as_record().unwrap().len(), 1000);
+        // an error on the first line leaves nothing to parse, rather than trying every line
+        assert!(KDL.from(None, &call, &Value::test_string(format!("}}\n{}", long))).is_err());
+    }
+
+    #[test]
+    fn test_best_effort_without_valid_prefix() {
+        let call = EvaluatedCall::new(Span::test_data()).with_flag("best-effort".to_string().into_spanned(Span::test_data()));
+
+        assert!(KDL.from(None, &call, &Value::test_string("}} garbage\n")).is_err());
+    }
+
+    #[test]
+    fn test_final_newline() {
+        assert_eq!(final_newline("node 1".to_string(), true), "node 1\n");
+        assert_eq!(final_newline("node 1\n".to_string(), true), "node 1\n");
+        assert_eq!(final_newline("node 1\n\n".to_string(), true), "node 1\n");
+        assert_eq!(final_newline("".to_string(), true), "");
+    }
+
+    #[test]
+    fn test_no_final_newline() {
+        assert_eq!(final_newline("node 1\n".to_string(), false), "node 1");
+        assert_eq!(final_newline("node 1\n\n".to_string(), false), "node 1");
+
+        let call = EvaluatedCall::new(Span::test_data()).with_flag("no-final-newline".to_string().into_spanned(Span::test_data()));
+        let output = KDL.to(None, &call, &Value::test_int(1)).unwrap();
+        assert_eq!(output.as_str().unwrap(), "node 1");
+    }
+
+    #[test]
+    fn test_wrap_table() {
+        let row = |name: &str, size: i64| {
+            Value::test_record(nu_protocol::record! {
+                "name" => Value::test_string(name),
+                "size" => Value::test_int(size),
+            })
+        };
+        let table = Value::test_list(vec![row("a.txt", 1), row("b.txt", 2)]);
+        let call = EvaluatedCall::new(Span::test_data()).with_named("wrap".to_string().into_spanned(Span::test_data()), Value::test_string("row"));
+
+        let output = KDL.to(None, &call, &table).unwrap();
+
+        assert_eq!(
+            output.as_str().unwrap(),
+            "row {\n    name a.txt\n    size 1\n}\nrow {\n    name b.txt\n    size 2\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_null_in_both_versions() {
+        let value = Value::test_record(nu_protocol::record! {
+            "x" => Value::test_nothing(),
+            "y" => Value::test_list(vec![Value::test_nothing(), Value::test_bool(true), Value::test_string("a b")]),
+        });
+
+        // a null node is a node without entries, so the null entries are the ones of lists
+        for (flag, output) in [(None, "x\ny #null #true \"a b\"\n"), (Some("v1"), "x\ny null true \"a b\"\n")] {
+            let mut call = EvaluatedCall::new(Span::test_data());
+            if let Some(flag) = flag {
+                call = call.with_flag(flag.to_string().into_spanned(Span::test_data()));
+            }
+
+            let kdl = KDL.to(None, &call, &value).unwrap();
+            assert_eq!(kdl.as_str().unwrap(), output);
+            assert_eq!(KDL.from(None, &call, &kdl).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn test_non_finite_floats_in_v1() {
+        let v1 = EvaluatedCall::new(Span::test_data()).with_flag("v1".to_string().into_spanned(Span::test_data()));
+
+        for (float, msg) in [(f64::NAN, "KDL v1 can't write NaN"), (f64::INFINITY, "KDL v1 can't write inf"), (f64::NEG_INFINITY, "KDL v1 can't write -inf")] {
+            let value = Value::test_record(nu_protocol::record! {
+                "a" => Value::test_float(1.5),
+                "b" => Value::test_list(vec![Value::float(float, Span::new(3, 6))]),
+            });
+
+            let error = KDL.to(None, &v1, &value).unwrap_err();
+            assert_eq!(error.msg, msg);
+            assert_eq!(error.labels[0].span, Span::new(3, 6));
+            // v2 has keywords for them
+            assert!(KDL.to(None, &EvaluatedCall::new(Span::test_data()), &value).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_keyword_arguments_in_both_versions() {
+        let v1 = EvaluatedCall::new(Span::test_data()).with_flag("v1".to_string().into_spanned(Span::test_data()));
+        let v2 = EvaluatedCall::new(Span::test_data());
+
+        for (call, keywords) in [(v1, ["true", "false", "null"]), (v2, ["#true", "#false", "#null"])] {
+            let values = keywords.map(|keyword| {
+                let output = KDL.from(None, &call, &Value::test_string(format!("node {}", keyword))).unwrap();
+                output.get_data_by_key("node").unwrap()
+            });
+            assert_eq!(values, [Value::test_bool(true), Value::test_bool(false), Value::test_nothing()]);
+        }
+
+        // quoted, they are strings in both versions
+        let output = KDL.from(None, &EvaluatedCall::new(Span::test_data()), &Value::test_string("node \"true\"")).unwrap();
+        assert_eq!(output.get_data_by_key("node").unwrap(), Value::test_string("true"));
+    }
+
+    #[test]
+    fn test_wrap_on_error() {
+        let row = |size: Value| Value::test_record(nu_protocol::record! { "size" => size });
+        let table = Value::test_list(vec![row(Value::test_int(1)), row(Value::test_filesize(2)), row(Value::test_int(3))]);
+        let call = |on_error: Option<&str>| {
+            let call = EvaluatedCall::new(Span::test_data()).with_named("wrap".to_string().into_spanned(Span::test_data()), Value::test_string("row"));
+            match on_error {
+                Some(mode) => call.with_named("on-error".to_string().into_spanned(Span::test_data()), Value::test_string(mode)),
+                None => call,
+            }
+        };
+
+        let output = KDL.to(None, &call(Some("skip")), &table).unwrap();
+        assert_eq!(output.as_str().unwrap(), "row {\n    size 1\n}\nrow {\n    size 3\n}\n");
+
+        for on_error in [None, Some("fail")] {
+            let error = KDL.to(None, &call(on_error), &table).unwrap_err();
+            assert_eq!(error.msg, "value not supported, expected string, int, float, bool, duration or null");
+        }
+        assert_eq!(KDL.to(None, &call(Some("ignore")), &table).unwrap_err().msg, "invalid --on-error `ignore`");
+
+        // without --wrap, the flag is checked rather than ignored
+        for on_error in ["skip", "bogus"] {
+            let call = EvaluatedCall::new(Span::test_data()).with_named("on-error".to_string().into_spanned(Span::test_data()), Value::test_string(on_error));
+            let error = KDL.to(None, &call, &table).unwrap_err();
+            assert_eq!(error.msg, if on_error == "skip" { "missing --wrap" } else { "invalid --on-error `bogus`" });
+        }
+    }
+
     #[test]
     fn test_different_kdl_versions() {
         // Both versions should handle basic nodes fine
@@ -228,4 +1101,16 @@ node2 123"#;
         assert!(v1_result.is_ok(), "V1 should parse simple node");
         assert!(v2_result.is_ok(), "V2 should parse simple node");
     }
+
+    #[test]
+    fn test_binary_input() {
+        let input = Value::test_binary("title \"KDL\"".as_bytes());
+        let value = KDL.from(None, &EvaluatedCall::new(Span::test_data()), &input).unwrap();
+        assert_eq!(value.get_data_by_key("title").unwrap(), Value::test_string("KDL"));
+
+        let input = Value::test_binary(b"title \"\xff\"".to_vec());
+        let error = KDL.from(None, &EvaluatedCall::new(Span::test_data()), &input).unwrap_err();
+        assert_eq!(error.msg, "input is not valid UTF-8");
+        assert_eq!(error.labels[0].text, "invalid byte at offset 7");
+    }
 }
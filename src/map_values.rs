@@ -0,0 +1,144 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{engine::Closure, Category, LabeledError, PipelineData, Signature, Spanned, SyntaxShape, Type, Value};
+
+use kdl::KdlDocument;
+
+use crate::{from, set, to, KDL};
+
+pub struct KdlMapValues;
+
+impl PluginCommand for KdlMapValues {
+    type Plugin = KDL;
+
+    fn name(&self) -> &str {
+        "kdl map-values"
+    }
+
+    fn description(&self) -> &str {
+        "Replace every argument and property value by what a closure gives for it, keeping the rest of the document as is"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .input_output_type(Type::String, Type::String)
+            .required(
+                "closure",
+                SyntaxShape::Closure(Some(vec![SyntaxShape::Any])),
+                "The closure given each value, e.g. {|v| if ($v | describe) == string { '***' } else { $v }}",
+            )
+            .switch("v1", "Force parsing as KDL v1 only", Some('1'))
+            .switch("v1-fallback", "Try KDL v2, fall back to v1 if parsing fails", None)
+            .category(Category::Experimental)
+    }
+
+    fn run(
+        &self,
+        plugin: &KDL,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let closure: Spanned<Closure> = call.req(0)?;
+        let value = input.into_value(call.head)?;
+        let result = map_values(plugin, call, &value, |value| {
+            Ok(engine.eval_closure(&closure, vec![value.clone()], Some(value))?)
+        })?;
+        Ok(PipelineData::Value(result, None))
+    }
+}
+
+/// Replaces the value of every entry of the document by what `f` gives for it, in the formatting
+/// of the entry, and in the syntax of the version it was parsed as. The entries `f` gives back
+/// unchanged aren't rewritten, so e.g. `0x1F` stays hex.
+pub(crate) fn map_values(
+    plugin: &KDL,
+    call: &EvaluatedCall,
+    input: &Value,
+    mut f: impl FnMut(Value) -> Result<Value, LabeledError>,
+) -> Result<Value, LabeledError> {
+    let (mut document, fell_back) = plugin.parse(call, input)?;
+    let v1 = fell_back || call.has_flag("v1")?;
+
+    map_document(&mut document, v1, &mut f)?;
+
+    Ok(Value::string(document.to_string(), call.head))
+}
+
+fn map_document(document: &mut KdlDocument, v1: bool, f: &mut impl FnMut(Value) -> Result<Value, LabeledError>) -> Result<(), LabeledError> {
+    let parse_options = from::ParseOptions::default();
+    let build_options = to::BuildOptions::default();
+
+    for node in document.nodes_mut() {
+        for entry in node.entries_mut() {
+            let value = from::parse_value(entry, &parse_options);
+            let mapped = f(value.clone())?;
+            if mapped != value {
+                set::replace_entry(entry, &to::build_argument(&mapped, &build_options)?);
+                if v1 {
+                    // e.g. `"***"` and `true` rather than `***` and `#true`
+                    entry.ensure_v1();
+                }
+            }
+        }
+        if let Some(children) = node.children_mut() {
+            map_document(children, v1, f)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nu_protocol::{IntoSpanned, Span};
+
+    fn redact(value: Value) -> Result<Value, LabeledError> {
+        Ok(match value {
+            Value::String { internal_span, .. } => Value::string("***", internal_span),
+            value => value,
+        })
+    }
+
+    fn map_kdl(input: &str, f: impl FnMut(Value) -> Result<Value, LabeledError>) -> Result<String, LabeledError> {
+        let call = EvaluatedCall::new(Span::test_data());
+        map_values(&KDL, &call, &Value::test_string(input), f).map(|v| v.into_string().unwrap())
+    }
+
+    #[test]
+    fn test_map_values_redacts_strings() {
+        let input = "// the database\ndb \"main\" password=(secret)hunter2 port=0x1F90 {\n    user admin   // the user\n    retries 3\n    tls #true\n}\n";
+
+        let output = map_kdl(input, redact).unwrap();
+
+        assert_eq!(output, "// the database\ndb *** password=(secret)*** port=0x1F90 {\n    user ***   // the user\n    retries 3\n    tls #true\n}\n");
+    }
+
+    #[test]
+    fn test_map_values_v1() {
+        let input = "db \"main\" enabled=true port=8080 none=null\n";
+        let flip = |value: Value| match value {
+            Value::Bool { val, internal_span, .. } => Ok(Value::bool(!val, internal_span)),
+            Value::Int { internal_span, .. } => Ok(Value::nothing(internal_span)),
+            Value::Nothing { internal_span, .. } => Ok(Value::int(1, internal_span)),
+            value => redact(value),
+        };
+
+        for flag in ["v1", "v1-fallback"] {
+            let call = EvaluatedCall::new(Span::test_data()).with_flag(flag.to_string().into_spanned(Span::test_data()));
+            let output = map_values(&KDL, &call, &Value::test_string(input), flip).unwrap().into_string().unwrap();
+
+            assert_eq!(output, "db \"***\" enabled=false port=null none=1\n");
+            let v1 = EvaluatedCall::new(Span::test_data()).with_flag("v1".to_string().into_spanned(Span::test_data()));
+            assert!(KDL.parse(&v1, &Value::test_string(output)).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_map_values_error() {
+        let error = map_kdl("a 1", |_| Err(LabeledError::new("closure failed"))).unwrap_err();
+
+        assert_eq!(error.msg, "closure failed");
+    }
+}
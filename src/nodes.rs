@@ -0,0 +1,126 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{record, Category, LabeledError, PipelineData, Record, Signature, Span, Type, Value};
+
+use kdl::KdlNode;
+
+use crate::{diff, from, KDL};
+
+pub struct KdlNodes;
+
+impl PluginCommand for KdlNodes {
+    type Plugin = KDL;
+
+    fn name(&self) -> &str {
+        "kdl nodes"
+    }
+
+    fn description(&self) -> &str {
+        "List every node of a KDL document, at any depth, as a table to filter with e.g. where"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .input_output_type(Type::String, Type::table())
+            .switch("v1", "Force parsing as KDL v1 only", Some('1'))
+            .switch("v1-fallback", "Try KDL v2, fall back to v1 if parsing fails", None)
+            .category(Category::Experimental)
+    }
+
+    fn run(
+        &self,
+        plugin: &KDL,
+        _engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let value = input.into_value(call.head)?;
+        let result = nodes(plugin, call, &value)?;
+        Ok(PipelineData::Value(result, None))
+    }
+}
+
+/// Lists the nodes of the document in document order, as a table with the columns `path`, `name`,
+/// `args`, `props` and `has_children`, the paths being the ones of `kdl diff`.
+pub(crate) fn nodes(plugin: &KDL, call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let (document, _) = plugin.parse(call, input)?;
+
+    let mut rows = Vec::new();
+    collect(document.nodes(), "", call.head, &mut rows);
+
+    Ok(Value::list(rows, call.head))
+}
+
+fn collect(nodes: &[KdlNode], parent: &str, span: Span, rows: &mut Vec<Value>) {
+    for (path, node) in diff::keyed(nodes, parent) {
+        rows.push(row(&path, node, span));
+        if let Some(children) = node.children() {
+            collect(children.nodes(), &path, span, rows);
+        }
+    }
+}
+
+fn row(path: &str, node: &KdlNode, span: Span) -> Value {
+    let options = from::ParseOptions::default();
+
+    let mut args = Vec::new();
+    let mut props = Record::new();
+    for entry in node.entries() {
+        let value = from::parse_value(entry, &options);
+        match entry.name() {
+            Some(name) => {
+                props.insert(name.value(), value);
+            }
+            None => args.push(value),
+        }
+    }
+
+    Value::record(
+        record! {
+            "path" => Value::string(path, span),
+            "name" => Value::string(node.name().value(), span),
+            "args" => Value::list(args, span),
+            "props" => Value::record(props, span),
+            "has_children" => Value::bool(node.children().is_some(), span),
+        },
+        span,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_nodes() -> Vec<Value> {
+        let input = Value::test_string(include_str!("../sample.kdl"));
+        nodes(&KDL, &EvaluatedCall::new(Span::test_data()), &input).unwrap().into_list().unwrap()
+    }
+
+    #[test]
+    fn test_nodes_of_sample() {
+        let rows = sample_nodes();
+
+        assert_eq!(rows.len(), 16);
+        let paths: Vec<_> = rows.iter().map(|row| row.get_data_by_key("path").unwrap().into_string().unwrap()).collect();
+        assert_eq!(paths[..6], ["title", "version", "server", "server.tls", "server.routes", "server.routes.index"]);
+    }
+
+    #[test]
+    fn test_deep_node() {
+        let rows = sample_nodes();
+        let api = rows.iter().find(|row| row.get_data_by_key("name").unwrap().as_str().unwrap() == "api").unwrap();
+
+        assert_eq!(api.get_data_by_key("path").unwrap(), Value::test_string("server.routes.api"));
+        assert_eq!(api.get_data_by_key("args").unwrap(), Value::test_list(vec![Value::test_string("/api")]));
+        assert_eq!(api.get_data_by_key("props").unwrap().get_data_by_key("timeout").unwrap(), Value::test_float(2.5));
+        assert_eq!(api.get_data_by_key("has_children").unwrap(), Value::test_bool(false));
+    }
+
+    #[test]
+    fn test_repeated_names() {
+        let input = Value::test_string("tab { pane; pane { pane } }");
+        let rows = nodes(&KDL, &EvaluatedCall::new(Span::test_data()), &input).unwrap().into_list().unwrap();
+        let paths: Vec<_> = rows.iter().map(|row| row.get_data_by_key("path").unwrap().into_string().unwrap()).collect();
+
+        assert_eq!(paths, ["tab", "tab.pane", "tab.pane#1", "tab.pane#1.pane"]);
+    }
+}
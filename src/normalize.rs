@@ -0,0 +1,115 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{Category, LabeledError, PipelineData, Signature, Type, Value};
+
+use kdl::{KdlDocument, KdlEntry, KdlNode};
+
+use crate::KDL;
+
+pub struct KdlNormalize;
+
+impl PluginCommand for KdlNormalize {
+    type Plugin = KDL;
+
+    fn name(&self) -> &str {
+        "kdl normalize"
+    }
+
+    fn description(&self) -> &str {
+        "Write a KDL document in a canonical form, the same for documents which only differ in formatting"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .input_output_type(Type::String, Type::String)
+            .switch("v1", "Force parsing as KDL v1 only", Some('1'))
+            .switch("v1-fallback", "Try KDL v2, fall back to v1 if parsing fails", None)
+            .category(Category::Experimental)
+    }
+
+    fn run(
+        &self,
+        plugin: &KDL,
+        _engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let value = input.into_value(call.head)?;
+        let result = normalize(plugin, call, &value)?;
+        Ok(PipelineData::Value(result, None))
+    }
+}
+
+/// Writes the document back as KDL v2 with the nodes sorted by name, the nodes of the same name
+/// keeping their order, the arguments then the properties sorted by name, only the last of a
+/// repeated property which is the one that counts, no comments, no empty children blocks, and
+/// the numbers, strings and whitespace as kdl formats them.
+pub(crate) fn normalize(plugin: &KDL, call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let (mut document, _) = plugin.parse(call, input)?;
+
+    canonicalize(&mut document);
+    document.autoformat_no_comments();
+
+    Ok(Value::string(crate::final_newline(document.to_string(), true), call.head))
+}
+
+fn canonicalize(document: &mut KdlDocument) {
+    for node in document.nodes_mut() {
+        canonicalize_node(node);
+    }
+    document.nodes_mut().sort_by(|a, b| a.name().value().cmp(b.name().value()));
+}
+
+fn canonicalize_node(node: &mut KdlNode) {
+    let entries = std::mem::take(node.entries_mut());
+    let (args, props): (Vec<KdlEntry>, Vec<KdlEntry>) = entries.into_iter().partition(|entry| entry.name().is_none());
+
+    let mut kept: Vec<KdlEntry> = Vec::with_capacity(props.len());
+    for prop in props.into_iter().rev() {
+        if !kept.iter().any(|p| p.name().map(|n| n.value()) == prop.name().map(|n| n.value())) {
+            kept.push(prop);
+        }
+    }
+    kept.sort_by(|a, b| a.name().map(|n| n.value()).cmp(&b.name().map(|n| n.value())));
+
+    *node.entries_mut() = args.into_iter().chain(kept).collect();
+
+    match node.children_mut() {
+        Some(children) if children.nodes().is_empty() => node.clear_children(),
+        Some(children) => canonicalize(children),
+        None => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nu_protocol::{IntoSpanned, Span};
+
+    fn normalize_kdl(input: &str) -> String {
+        normalize(&KDL, &EvaluatedCall::new(Span::test_data()), &Value::test_string(input)).unwrap().into_string().unwrap()
+    }
+
+    #[test]
+    fn test_equal_documents() {
+        let a = "// the server\nserver \"main\" port=0x50 host=x {\n  tls #true\n  route \"/a\"; route \"/b\"\n}\ntitle #\"KDL\"#\nempty {}\n";
+        let b = "title KDL\nempty\nserver main host=\"x\" port=1 port=80 { route \"/a\"; tls #true; route \"/b\" /* last */ }";
+
+        assert_eq!(normalize_kdl(a), normalize_kdl(b));
+        assert_eq!(normalize_kdl(a), "empty\nserver main host=x port=80 {\n    route \"/a\"\n    route \"/b\"\n    tls #true\n}\ntitle KDL\n");
+    }
+
+    #[test]
+    fn test_different_documents() {
+        assert_ne!(normalize_kdl("route a; route b"), normalize_kdl("route b; route a"));
+        assert_ne!(normalize_kdl("node 1 2"), normalize_kdl("node 2 1"));
+    }
+
+    #[test]
+    fn test_v1_document() {
+        let call = EvaluatedCall::new(Span::test_data()).with_flag("v1".to_string().into_spanned(Span::test_data()));
+        let output = normalize(&KDL, &call, &Value::test_string("node true null b=r\"x\" a=1")).unwrap();
+
+        assert_eq!(output.as_str().unwrap(), normalize_kdl("node #true #null a=1 b=x"));
+    }
+}
@@ -0,0 +1,227 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{Category, LabeledError, PipelineData, Signature, SyntaxShape, Type, Value};
+
+use kdl::{KdlDocument, KdlEntry, KdlNode};
+
+use crate::{
+    append,
+    error::KdlPluginError,
+    query::{self, NodePath},
+    set, to, KDL,
+};
+
+pub struct KdlPatch;
+
+impl PluginCommand for KdlPatch {
+    type Plugin = KDL;
+
+    fn name(&self) -> &str {
+        "kdl patch"
+    }
+
+    fn description(&self) -> &str {
+        "Set the values of dotted paths, e.g. {server.port: 9090}, keeping the rest of the document as is"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .input_output_type(Type::String, Type::String)
+            .required("edits", SyntaxShape::Record(vec![]), "The new values of the paths, as given by kdl flatten-paths")
+            .switch("v1", "Force parsing as KDL v1 only", Some('1'))
+            .switch("v1-fallback", "Try KDL v2, fall back to v1 if parsing fails", None)
+            .category(Category::Experimental)
+    }
+
+    fn run(
+        &self,
+        plugin: &KDL,
+        _engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let value = input.into_value(call.head)?;
+        let result = patch(plugin, call, &value)?;
+        Ok(PipelineData::Value(result, None))
+    }
+}
+
+/// Sets the value of every dotted path of the record given as the first positional argument, in
+/// the paths of `kdl flatten-paths`: `server.port` is the first argument of the `port` node in
+/// `server`, or its `port` property when it has no such child, and repeated nodes are picked by
+/// index, e.g. `server.routes.1.path`. The missing nodes are created on the way, and only the
+/// values change otherwise, so whitespace and comments stay where they are, and they're written in
+/// the syntax of the version the document was parsed as.
+pub(crate) fn patch(plugin: &KDL, call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let edits: Value = call.req(0)?;
+    let (mut document, fell_back) = plugin.parse(call, input)?;
+    let v1 = fell_back || call.has_flag("v1")?;
+
+    for (path, value) in edits.as_record()?.iter() {
+        let invalid = |reason| KdlPluginError::InvalidPatch { path: path.clone(), reason, span: value.span() };
+        let entry = to::build_argument(value, &to::BuildOptions::default()).map_err(|error| invalid(error.msg))?;
+        patch_path(&mut document, path, &entry, v1).map_err(invalid)?;
+    }
+
+    Ok(Value::string(document.to_string(), call.head))
+}
+
+/// Sets `path` of `document` to `entry`, the error being the reason it can't.
+fn patch_path(document: &mut KdlDocument, path: &str, entry: &KdlEntry, v1: bool) -> Result<(), String> {
+    let mut segments = path.split('.').peekable();
+    let mut at: NodePath = Vec::new();
+    let mut prefix = String::new();
+
+    while let Some(name) = segments.next() {
+        if !prefix.is_empty() {
+            prefix.push('.');
+        }
+        prefix.push_str(name);
+
+        let siblings = match at.is_empty() {
+            true => Some(&*document),
+            false => query::node(document, &at).children(),
+        };
+        let matching: Vec<usize> = siblings.map_or(Vec::new(), |siblings| {
+            let nodes = siblings.nodes().iter().enumerate();
+            nodes.filter(|(_, node)| node.name().value() == name).map(|(i, _)| i).collect()
+        });
+
+        let found = match matching.as_slice() {
+            [] => None,
+            [i] => Some(*i),
+            _ => {
+                let Some((index, parsed)) = segments.next().and_then(|index| Some((index, index.parse::<usize>().ok()?))) else {
+                    return Err(format!("`{}` is repeated, expected the index of one, e.g. `{}.0`", prefix, prefix));
+                };
+                let Some(i) = matching.get(parsed) else {
+                    return Err(format!("`{}.{}` is out of range, `{}` has {} nodes", prefix, index, prefix, matching.len()));
+                };
+                prefix = format!("{}.{}", prefix, index);
+                Some(*i)
+            }
+        };
+        let last = segments.peek().is_none();
+
+        match found {
+            Some(i) => {
+                at.push(i);
+                if last {
+                    set::set_entry(query::node_mut(document, &at), None, entry, v1);
+                }
+            }
+            None if !at.is_empty() && has_property(query::node(document, &at), name) => {
+                if !last {
+                    return Err(format!("`{}` is a property, which has no children", prefix));
+                }
+                set::set_entry(query::node_mut(document, &at), Some(name), entry, v1);
+                return Ok(());
+            }
+            None => {
+                let mut node = KdlNode::new(name);
+                if last {
+                    node.entries_mut().push(entry.clone());
+                }
+                at.push(add_node(document, &at, node));
+                if v1 {
+                    // a new node, which has no children yet
+                    query::node_mut(document, &at).ensure_v1();
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn has_property(node: &KdlNode, name: &str) -> bool {
+    node.entries().iter().any(|entry| entry.name().is_some_and(|n| n.value() == name))
+}
+
+/// Adds `node` as the last child of the node at `parent`, or of the document when it's empty,
+/// giving back its index.
+fn add_node(document: &mut KdlDocument, parent: &NodePath, mut node: KdlNode) -> usize {
+    if !parent.is_empty() {
+        let target = query::node_mut(document, parent);
+        append::append_child(target, node, parent.len());
+        return target.children().unwrap().nodes().len() - 1;
+    }
+
+    node.autoformat();
+    // the last node of a document without a final newline would run into the new one
+    if let Some(format) = document.nodes_mut().last_mut().and_then(|last| last.format_mut()) {
+        if format.terminator.is_empty() {
+            format.terminator = "\n".to_string();
+        }
+    }
+    document.nodes_mut().push(node);
+    document.nodes().len() - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nu_protocol::{record, IntoSpanned, Span};
+
+    fn patch_kdl(input: &str, edits: Value) -> Result<String, LabeledError> {
+        let call = EvaluatedCall::new(Span::test_data()).with_positional(edits);
+        patch(&KDL, &call, &Value::test_string(input)).map(|v| v.into_string().unwrap())
+    }
+
+    const CONFIG: &str = "// the server\nserver main tls=#false {\n    port   8080 // the port\n    routes { path \"/a\"; }\n    routes { path \"/b\"; }\n}\n";
+
+    #[test]
+    fn test_patch_existing_values() {
+        let edits = record! {
+            "server.port" => Value::test_int(9090),
+            "server.tls" => Value::test_bool(true),
+            "server" => Value::test_string("backup"),
+            "server.routes.1.path" => Value::test_string("/c"),
+        };
+
+        let output = patch_kdl(CONFIG, Value::test_record(edits)).unwrap();
+
+        let expected = CONFIG.replace("8080", "9090").replace("#false", "#true").replace("main", "backup").replace("/b", "/c");
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_patch_missing_paths() {
+        let edits = record! { "server.host" => Value::test_string("x"), "logging.level" => Value::test_string("debug") };
+
+        let output = patch_kdl("server {\n    port 8080\n}", Value::test_record(edits)).unwrap();
+
+        assert_eq!(output, "server {\n    port 8080\n    host x\n}\nlogging {\n    level debug\n}\n");
+    }
+
+    #[test]
+    fn test_patch_v1_document() {
+        let edits = record! { "server.tls" => Value::test_bool(true), "server.mode" => Value::test_nothing(), "logging.level" => Value::test_string("debug") };
+        let call = EvaluatedCall::new(Span::test_data())
+            .with_positional(Value::test_record(edits))
+            .with_flag("v1".to_string().into_spanned(Span::test_data()));
+
+        let output = patch(&KDL, &call, &Value::test_string("server tls=false {\n    port 8080\n}\n")).unwrap().into_string().unwrap();
+
+        assert_eq!(output, "server tls=true {\n    port 8080\n    mode null\n}\nlogging {\n    level \"debug\"\n}\n");
+        assert!(KdlDocument::parse_v1(&output).is_ok());
+    }
+
+    #[test]
+    fn test_patch_conflicts() {
+        let error = patch_kdl(CONFIG, Value::test_record(record! { "server.tls.enabled" => Value::test_bool(true) })).unwrap_err();
+        assert_eq!(error.msg, "cannot patch `server.tls.enabled`: `server.tls` is a property, which has no children");
+
+        let error = patch_kdl(CONFIG, Value::test_record(record! { "server.routes.path" => Value::test_string("/c") })).unwrap_err();
+        assert_eq!(error.msg, "cannot patch `server.routes.path`: `server.routes` is repeated, expected the index of one, e.g. `server.routes.0`");
+
+        let error = patch_kdl(CONFIG, Value::test_record(record! { "server.port" => Value::test_record(record! {}) })).unwrap_err();
+        assert_eq!(
+            error.msg,
+            "cannot patch `server.port`: value not supported, expected string, int, float, bool, duration or null"
+        );
+
+        let error = patch_kdl(CONFIG, Value::test_record(record! { "server.routes.5.path" => Value::test_string("/c") })).unwrap_err();
+        assert_eq!(error.msg, "cannot patch `server.routes.5.path`: `server.routes.5` is out of range, `server.routes` has 2 nodes");
+    }
+}
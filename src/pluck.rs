@@ -0,0 +1,117 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{Category, LabeledError, PipelineData, Signature, Spanned, SyntaxShape, Type, Value};
+
+use crate::{
+    from,
+    query::{self, Query},
+    KDL,
+};
+
+pub struct KdlPluck;
+
+impl PluginCommand for KdlPluck {
+    type Plugin = KDL;
+
+    fn name(&self) -> &str {
+        "kdl pluck"
+    }
+
+    fn description(&self) -> &str {
+        "List the first argument of every node matching a query, e.g. to sum them with math sum"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .input_output_type(Type::String, Type::List(Box::new(Type::Any)))
+            .required("query", SyntaxShape::String, "The query of the nodes, e.g. \"item > price\"")
+            .switch("v1", "Force parsing as KDL v1 only", Some('1'))
+            .named("case", SyntaxShape::String, "How to match the names of nodes and properties, sensitive (default) or insensitive", None)
+            .switch("v1-fallback", "Try KDL v2, fall back to v1 if parsing fails", None)
+            .category(Category::Experimental)
+    }
+
+    fn run(
+        &self,
+        plugin: &KDL,
+        _engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let value = input.into_value(call.head)?;
+        let result = pluck(plugin, call, &value)?;
+        Ok(PipelineData::Value(result, None))
+    }
+}
+
+/// Lists the value of every node matching the query given as the first positional argument, in
+/// document order: its first argument, or the whole node converted like `from kdl` would when it
+/// has none. No match is an empty list.
+pub(crate) fn pluck(plugin: &KDL, call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let at: Spanned<String> = call.req(0)?;
+
+    let query = Query::parse_arg(&at)?.with_case(query::case(call)?);
+    let (document, _) = plugin.parse(call, input)?;
+    let options = from::ParseOptions::default();
+
+    let values = query
+        .paths(&document)
+        .iter()
+        .map(|path| {
+            let node = query::node(&document, path);
+            match node.entries().iter().find(|entry| entry.name().is_none()) {
+                Some(arg) => from::parse_value(arg, &options),
+                None => from::parse_node(node, &options),
+            }
+        })
+        .collect();
+
+    Ok(Value::list(values, call.head))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nu_protocol::{record, Span};
+
+    fn pluck_kdl(query: &str, input: &str) -> Value {
+        let call = EvaluatedCall::new(Span::test_data()).with_positional(Value::test_string(query));
+        pluck(&KDL, &call, &Value::test_string(input)).unwrap()
+    }
+
+    #[test]
+    fn test_pluck() {
+        let input = "item { price 1.5; name a }\nitem { price 2 unit=eur }\nother { price 9 }";
+
+        assert_eq!(pluck_kdl("item > price", input), Value::test_list(vec![Value::test_float(1.5), Value::test_int(2)]));
+    }
+
+    #[test]
+    fn test_pluck_whole_node() {
+        let output = pluck_kdl("item", "item { price 2 }");
+
+        assert_eq!(output, Value::test_list(vec![Value::test_record(record! { "price" => Value::test_int(2) })]));
+    }
+
+    #[test]
+    fn test_pluck_case() {
+        let call = |case: &str| {
+            EvaluatedCall::new(Span::test_data())
+                .with_positional(Value::test_string("Pane"))
+                .with_named(Spanned { item: "case".to_string(), span: Span::test_data() }, Value::test_string(case))
+        };
+        let input = Value::test_string("pane 1\npane 2");
+
+        assert_eq!(pluck(&KDL, &call("sensitive"), &input).unwrap(), Value::test_list(vec![]));
+        assert_eq!(
+            pluck(&KDL, &call("insensitive"), &input).unwrap(),
+            Value::test_list(vec![Value::test_int(1), Value::test_int(2)])
+        );
+        assert!(pluck(&KDL, &call("upper"), &input).is_err());
+    }
+
+    #[test]
+    fn test_pluck_no_match() {
+        assert_eq!(pluck_kdl("missing", "item 1"), Value::test_list(vec![]));
+    }
+}
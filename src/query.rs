@@ -0,0 +1,368 @@
+use nu_plugin::EvaluatedCall;
+use nu_protocol::{LabeledError, Spanned};
+
+use kdl::{KdlDocument, KdlNode, KdlValue};
+
+use crate::error::KdlPluginError;
+
+/// A query selecting nodes of a KDL document, in a subset of KQL, the KDL query language, as the
+/// kdl crate doesn't ship a query engine:
+/// - `pane` selects the nodes called `pane`, and `*` all of them
+/// - `pane[size]` the ones having a `size` property, and `pane[size=1]` the ones for which it's `1`
+/// - `pane[val()=main]` or `pane[val(1)=main]` the ones whose first or second argument is `main`
+/// - `tab pane` the `pane` nodes somewhere below a `tab` one, and `tab > pane` right below it
+#[derive(Debug)]
+pub(crate) struct Query {
+    steps: Vec<Step>,
+    case: Case,
+}
+
+/// How the names of nodes and properties are compared, case-sensitively by default as in KQL.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) enum Case {
+    #[default]
+    Sensitive,
+    /// Both names are lowercased, for documents with inconsistent casing.
+    Insensitive,
+}
+
+#[derive(Debug)]
+struct Step {
+    /// How the step relates to the previous one, ignored for the first step.
+    combinator: Combinator,
+    /// `None` for `*`.
+    name: Option<String>,
+    filters: Vec<Filter>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Combinator {
+    Descendant,
+    Child,
+}
+
+#[derive(Debug)]
+struct Filter {
+    key: Key,
+    value: Option<KdlValue>,
+}
+
+#[derive(Debug)]
+enum Key {
+    Property(String),
+    Argument(usize),
+}
+
+/// The index of a node in its document, then of its children and so on, to find a node again in a
+/// document which has to be borrowed mutably.
+pub(crate) type NodePath = Vec<usize>;
+
+impl Query {
+    /// Parses a query, the error being the reason it's invalid.
+    pub(crate) fn parse(query: &str) -> Result<Self, String> {
+        let mut chars = query.chars().peekable();
+        let mut steps = Vec::new();
+        let mut combinator = Combinator::Descendant;
+
+        loop {
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            match chars.peek() {
+                None => break,
+                Some('>') => {
+                    if steps.is_empty() || combinator == Combinator::Child {
+                        return Err("`>` should be between two nodes".to_string());
+                    }
+                    chars.next();
+                    combinator = Combinator::Child;
+                    continue;
+                }
+                Some(_) => {}
+            }
+
+            let name = if chars.next_if_eq(&'*').is_some() {
+                None
+            } else {
+                let mut name = String::new();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace() && !matches!(c, '[' | ']' | '>' | '*')) {
+                    name.push(c);
+                }
+                if name.is_empty() {
+                    return Err(format!("expected a node name or `*`, found `{}`", chars.peek().unwrap()));
+                }
+                Some(name)
+            };
+
+            let mut filters = Vec::new();
+            while chars.next_if_eq(&'[').is_some() {
+                let mut filter = String::new();
+                let mut quoted = false;
+                loop {
+                    match chars.next() {
+                        None => return Err("unclosed `[`".to_string()),
+                        Some(']') if !quoted => break,
+                        Some(c) => {
+                            quoted ^= c == '"';
+                            filter.push(c);
+                        }
+                    }
+                }
+                filters.push(Filter::parse(&filter)?);
+            }
+
+            steps.push(Step { combinator, name, filters });
+            combinator = Combinator::Descendant;
+        }
+
+        if steps.is_empty() {
+            return Err("the query is empty".to_string());
+        }
+        if combinator == Combinator::Child {
+            return Err("`>` should be between two nodes".to_string());
+        }
+
+        Ok(Self { steps, case: Case::default() })
+    }
+
+    pub(crate) fn with_case(self, case: Case) -> Self {
+        Self { case, ..self }
+    }
+
+    /// Parses the query given to a command, pointing at it when it's invalid.
+    pub(crate) fn parse_arg(query: &Spanned<String>) -> Result<Self, KdlPluginError> {
+        Self::parse(&query.item).map_err(|reason| KdlPluginError::InvalidQuery { reason, span: query.span })
+    }
+
+    /// The paths of the nodes matching the query, in document order.
+    pub(crate) fn paths(&self, document: &KdlDocument) -> Vec<NodePath> {
+        let mut paths = Vec::new();
+        self.collect(document, &mut Vec::new(), &mut Vec::new(), &mut paths);
+        paths
+    }
+
+    /// Whether any node matches the query, stopping at the first one.
+    pub(crate) fn matches_any(&self, document: &KdlDocument) -> bool {
+        self.any(document, &mut Vec::new())
+    }
+
+    fn any<'a>(&self, document: &'a KdlDocument, chain: &mut Vec<&'a KdlNode>) -> bool {
+        document.nodes().iter().any(|node| {
+            chain.push(node);
+            let found = matches(&self.steps, chain, self.case) || node.children().is_some_and(|children| self.any(children, chain));
+            chain.pop();
+            found
+        })
+    }
+
+    fn collect<'a>(&self, document: &'a KdlDocument, chain: &mut Vec<&'a KdlNode>, path: &mut NodePath, paths: &mut Vec<NodePath>) {
+        for (i, node) in document.nodes().iter().enumerate() {
+            chain.push(node);
+            path.push(i);
+
+            if matches(&self.steps, chain, self.case) {
+                paths.push(path.clone());
+            }
+            if let Some(children) = node.children() {
+                self.collect(children, chain, path, paths);
+            }
+
+            chain.pop();
+            path.pop();
+        }
+    }
+}
+
+impl Filter {
+    fn parse(filter: &str) -> Result<Self, String> {
+        let (key, value) = match filter.split_once('=') {
+            Some((key, value)) => (key.trim(), Some(literal(value.trim()))),
+            None => (filter.trim(), None),
+        };
+
+        let key = match key.strip_prefix("val(").and_then(|k| k.strip_suffix(')')) {
+            Some("") => Key::Argument(0),
+            Some(index) => Key::Argument(index.parse().map_err(|_| format!("`{}` is not an argument index", index))?),
+            None if key.is_empty() => return Err("a filter should have a property name or `val()`".to_string()),
+            None => Key::Property(key.trim_matches('"').to_string()),
+        };
+
+        Ok(Self { key, value })
+    }
+
+    fn matches(&self, node: &KdlNode, case: Case) -> bool {
+        // the last value of a property is the one which counts
+        let value = match &self.key {
+            Key::Property(name) => node.entries().iter().rev().find(|e| e.name().is_some_and(|n| case.eq(n.value(), name))),
+            Key::Argument(index) => node.entries().iter().filter(|e| e.name().is_none()).nth(*index),
+        };
+
+        match (value, &self.value) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(entry), Some(expected)) => entry.value() == expected,
+        }
+    }
+}
+
+impl Step {
+    fn matches(&self, node: &KdlNode, case: Case) -> bool {
+        self.name.as_ref().is_none_or(|name| case.eq(node.name().value(), name)) && self.filters.iter().all(|f| f.matches(node, case))
+    }
+}
+
+impl Case {
+    fn eq(self, a: &str, b: &str) -> bool {
+        match self {
+            Case::Sensitive => a == b,
+            Case::Insensitive => a.to_lowercase() == b.to_lowercase(),
+        }
+    }
+}
+
+/// Whether the last node of `chain`, the others being its ancestors, matches all the steps.
+fn matches(steps: &[Step], chain: &[&KdlNode], case: Case) -> bool {
+    let (Some((step, steps)), Some((node, ancestors))) = (steps.split_last(), chain.split_last()) else {
+        return false;
+    };
+
+    if !step.matches(node, case) {
+        return false;
+    }
+    if steps.is_empty() {
+        return true;
+    }
+
+    match step.combinator {
+        Combinator::Child => matches(steps, ancestors, case),
+        Combinator::Descendant => (1..=ancestors.len()).rev().any(|n| matches(steps, &ancestors[..n], case)),
+    }
+}
+
+/// The value of a filter, `"a b"`, `1`, `#true`... anything else being a string.
+fn literal(value: &str) -> KdlValue {
+    if let Some(string) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        return KdlValue::String(string.to_string());
+    }
+
+    match value {
+        "#true" => KdlValue::Bool(true),
+        "#false" => KdlValue::Bool(false),
+        "#null" => KdlValue::Null,
+        _ => value
+            .parse::<i128>()
+            .map(KdlValue::Integer)
+            .or_else(|_| value.parse::<f64>().map(KdlValue::Float))
+            .unwrap_or_else(|_| KdlValue::String(value.to_string())),
+    }
+}
+
+/// The case given with the `--case` flag of the commands taking a query.
+pub(crate) fn case(call: &EvaluatedCall) -> Result<Case, LabeledError> {
+    let choices = [("sensitive", Case::Sensitive), ("insensitive", Case::Insensitive)];
+    Ok(crate::choice(call, "case", &choices, "sensitive or insensitive")?.unwrap_or_default())
+}
+
+pub(crate) fn node<'a>(document: &'a KdlDocument, path: &[usize]) -> &'a KdlNode {
+    let (first, rest) = path.split_first().expect("a node path is never empty");
+    rest.iter().fold(&document.nodes()[*first], |node, i| &node.children().unwrap().nodes()[*i])
+}
+
+pub(crate) fn node_mut<'a>(document: &'a mut KdlDocument, path: &[usize]) -> &'a mut KdlNode {
+    let (first, rest) = path.split_first().expect("a node path is never empty");
+    rest.iter().fold(&mut document.nodes_mut()[*first], |node, i| {
+        &mut node.children_mut().as_mut().unwrap().nodes_mut()[*i]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LAYOUT: &str = r#"
+layout {
+    tab name=main {
+        pane size=1 "top"
+        pane { pane focus=#true "nested" }
+    }
+    tab name=logs {
+        pane "bottom"
+    }
+}
+"#;
+
+    /// The first argument of the matching nodes, or their name when they have none.
+    fn names(query: &str) -> Vec<String> {
+        let mut document: KdlDocument = LAYOUT.parse().unwrap();
+        let paths = Query::parse(query).unwrap().paths(&document);
+
+        paths
+            .iter()
+            .map(|path| {
+                let node = node_mut(&mut document, path);
+                let argument = node.entries().iter().find(|e| e.name().is_none());
+                argument.map_or(node.name().value().to_string(), |e| e.value().as_string().unwrap().to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_name_and_descendants() {
+        assert_eq!(names("pane"), ["top", "pane", "nested", "bottom"]);
+        assert_eq!(names("tab pane pane"), ["nested"]);
+        assert_eq!(names("*").len(), 7);
+    }
+
+    #[test]
+    fn test_child_combinator() {
+        assert_eq!(names("tab > pane"), ["top", "pane", "bottom"]);
+        assert_eq!(names("layout > pane"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_filters() {
+        assert_eq!(names("tab[name=logs] pane"), ["bottom"]);
+        assert_eq!(names("pane[size]"), ["top"]);
+        assert_eq!(names("pane[focus=#true]"), ["nested"]);
+        assert_eq!(names("pane[val()=bottom]"), ["bottom"]);
+        assert_eq!(names("pane[val(0)=\"top\"][size=1]"), ["top"]);
+    }
+
+    #[test]
+    fn test_invalid_queries() {
+        assert!(Query::parse("").is_err());
+        assert!(Query::parse("> pane").is_err());
+        assert!(Query::parse("tab >").is_err());
+        assert!(Query::parse("pane[size").is_err());
+        assert!(Query::parse("pane[val(x)=1]").is_err());
+    }
+
+    #[test]
+    fn test_case() {
+        let document: KdlDocument = LAYOUT.parse().unwrap();
+        let paths = |query: &str, case| Query::parse(query).unwrap().with_case(case).paths(&document).len();
+
+        assert_eq!(paths("Pane", Case::Sensitive), 0);
+        assert_eq!(paths("Pane", Case::Insensitive), 4);
+        assert_eq!(paths("TAB[NAME=logs] > Pane", Case::Sensitive), 0);
+        assert_eq!(paths("TAB[NAME=logs] > Pane", Case::Insensitive), 1);
+        // values are still compared as they are
+        assert_eq!(paths("tab[name=LOGS]", Case::Insensitive), 0);
+    }
+
+    #[test]
+    fn test_matches_any() {
+        let document: KdlDocument = LAYOUT.parse().unwrap();
+
+        assert!(Query::parse("tab pane[focus]").unwrap().matches_any(&document));
+        assert!(!Query::parse("layout > pane").unwrap().matches_any(&document));
+    }
+
+    #[test]
+    fn test_node_mut() {
+        let mut document: KdlDocument = LAYOUT.parse().unwrap();
+        let paths = Query::parse("pane[focus]").unwrap().paths(&document);
+
+        assert_eq!(paths, [vec![0, 0, 1, 0]]);
+        node_mut(&mut document, &paths[0]).set_name("window");
+        assert!(document.to_string().contains("window focus=#true"));
+    }
+}
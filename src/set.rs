@@ -0,0 +1,203 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{Category, LabeledError, PipelineData, Signature, Spanned, SyntaxShape, Type, Value};
+
+use kdl::{KdlEntry, KdlEntryFormat, KdlNode};
+
+use crate::{
+    error::KdlPluginError,
+    query::{self, Query},
+    to, KDL,
+};
+
+pub struct KdlSet;
+
+impl PluginCommand for KdlSet {
+    type Plugin = KDL;
+
+    fn name(&self) -> &str {
+        "kdl set"
+    }
+
+    fn description(&self) -> &str {
+        "Set a value of the nodes matching a query, keeping the rest of the document as is"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .input_output_type(Type::String, Type::String)
+            .required("query", SyntaxShape::String, "The query of the nodes to change, e.g. \"tab > pane\"")
+            .required("value", SyntaxShape::Any, "The new value, a string, number, bool or null")
+            .named("prop", SyntaxShape::String, "The property to set, the first argument when not given", Some('p'))
+            .named("case", SyntaxShape::String, "How to match the names of nodes and properties, sensitive (default) or insensitive", None)
+            .switch("v1", "Force parsing as KDL v1 only", Some('1'))
+            .switch("v1-fallback", "Try KDL v2, fall back to v1 if parsing fails", None)
+            .category(Category::Experimental)
+    }
+
+    fn run(
+        &self,
+        plugin: &KDL,
+        _engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let value = input.into_value(call.head)?;
+        let result = set(plugin, call, &value)?;
+        Ok(PipelineData::Value(result, None))
+    }
+}
+
+/// Sets the property given with `--prop`, or the first argument, of every node matching the query
+/// given as the first positional argument to the second one, adding the entry when a node doesn't
+/// have it, in the syntax of the version the document was parsed as. Only the values change, so
+/// whitespace and comments stay where they are.
+pub(crate) fn set(plugin: &KDL, call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let at: Spanned<String> = call.req(0)?;
+    let value: Value = call.req(1)?;
+    let prop: Option<String> = call.get_flag("prop")?;
+
+    let query = Query::parse_arg(&at)?.with_case(query::case(call)?);
+    let (mut document, fell_back) = plugin.parse(call, input)?;
+    let v1 = fell_back || call.has_flag("v1")?;
+    let entry = to::build_argument(&value, &to::BuildOptions::default())?;
+
+    let paths = query.paths(&document);
+    if paths.is_empty() {
+        return Err(KdlPluginError::NoMatch { query: at.item, span: at.span }.into());
+    }
+
+    for path in paths {
+        set_entry(query::node_mut(&mut document, &path), prop.as_deref(), &entry, v1);
+    }
+
+    Ok(Value::string(document.to_string(), call.head))
+}
+
+/// Replaces the value of the last `prop` property of `node`, the one which counts, or of its first
+/// argument, in the formatting of the entry it replaces, and in v1 syntax when `v1`.
+pub(crate) fn set_entry(node: &mut KdlNode, prop: Option<&str>, new: &KdlEntry, v1: bool) {
+    let old = match prop {
+        Some(prop) => node.entries_mut().iter_mut().rev().find(|e| e.name().is_some_and(|n| n.value() == prop)),
+        None => node.entries_mut().iter_mut().find(|e| e.name().is_none()),
+    };
+
+    match old {
+        Some(old) => {
+            replace_entry(old, new);
+            if v1 {
+                old.ensure_v1();
+            }
+        }
+        None => {
+            let value_repr = value_repr(new);
+            let mut entry = new.clone();
+            entry.set_name(prop);
+            entry.set_format(KdlEntryFormat { value_repr, leading: " ".to_string(), ..Default::default() });
+            if v1 {
+                entry.ensure_v1();
+            }
+            match prop {
+                Some(_) => node.entries_mut().push(entry),
+                // arguments come before the properties of the node, which is easier to read
+                None => node.entries_mut().insert(0, entry),
+            }
+        }
+    }
+}
+
+/// Replaces the value of `old` by the one of `new`, keeping its name and formatting, and its type
+/// annotation unless `new` has one of its own.
+pub(crate) fn replace_entry(old: &mut KdlEntry, new: &KdlEntry) {
+    let mut entry = new.clone();
+    entry.set_name(old.name().cloned());
+    if let (None, Some(ty)) = (new.ty(), old.ty()) {
+        entry.set_ty(ty.clone());
+    }
+    if let Some(format) = old.format() {
+        entry.set_format(KdlEntryFormat { value_repr: value_repr(new), ..format.clone() });
+    }
+    *old = entry;
+}
+
+/// The repr of the value of an entry, the `$repr` a number was given keeping it rather than the
+/// repr of the value it replaces.
+fn value_repr(entry: &KdlEntry) -> String {
+    entry.format().map_or_else(|| entry.value().to_string(), |f| f.value_repr.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nu_protocol::{IntoSpanned, Span};
+
+    fn set_kdl(input: &str, at: &str, value: Value, prop: Option<&str>) -> Result<String, LabeledError> {
+        let mut call = EvaluatedCall::new(Span::test_data()).with_positional(Value::test_string(at)).with_positional(value);
+        if let Some(prop) = prop {
+            call = call.with_named("prop".to_string().into_spanned(Span::test_data()), Value::test_string(prop));
+        }
+
+        set(&KDL, &call, &Value::test_string(input)).map(|v| v.into_string().unwrap())
+    }
+
+    const CONFIG: &str = "// the servers\nserver \"main\"   port=8080  host=localhost // the main one\n\nserver \"backup\" port=0x1F90 {\n    /- retries 3\n}\n";
+
+    #[test]
+    fn test_set_property() {
+        let output = set_kdl(CONFIG, "server[val()=main]", Value::test_int(9090), Some("port")).unwrap();
+
+        assert_eq!(output, CONFIG.replacen("port=8080", "port=9090", 1));
+    }
+
+    #[test]
+    fn test_set_argument_of_every_match() {
+        let output = set_kdl(CONFIG, "server", Value::test_string("new name"), None).unwrap();
+
+        assert_eq!(output, CONFIG.replace("\"main\"", "\"new name\"").replace("\"backup\"", "\"new name\""));
+    }
+
+    #[test]
+    fn test_set_missing_entries() {
+        let output = set_kdl("a x=1 { b; }\nc\n", "*", Value::test_bool(true), Some("ok")).unwrap();
+        assert_eq!(output, "a x=1 ok=#true { b ok=#true; }\nc ok=#true\n");
+
+        let output = set_kdl("a x=1\n", "a", Value::test_int(5), None).unwrap();
+        assert_eq!(output, "a 5 x=1\n");
+    }
+
+    #[test]
+    fn test_set_annotated_value() {
+        let output = set_kdl("a (u16)1 port=(u16)  8080 // the port\n", "a", Value::test_int(5), None).unwrap();
+        assert_eq!(output, "a (u16)5 port=(u16)  8080 // the port\n");
+
+        let output = set_kdl("a (u16)1 port=(u16)  8080 // the port\n", "a", Value::test_int(9090), Some("port")).unwrap();
+        assert_eq!(output, "a (u16)1 port=(u16)  9090 // the port\n");
+    }
+
+    #[test]
+    fn test_set_v1_document() {
+        let input = include_str!("../zellij-layout.kdl");
+        let call = |flag: &str| {
+            EvaluatedCall::new(Span::test_data())
+                .with_positional(Value::test_string("pane[size]"))
+                .with_positional(Value::test_bool(true))
+                .with_named("prop".to_string().into_spanned(Span::test_data()), Value::test_string("focus"))
+                .with_flag(flag.to_string().into_spanned(Span::test_data()))
+        };
+
+        for flag in ["v1", "v1-fallback"] {
+            let output = set(&KDL, &call(flag), &Value::test_string(input)).unwrap().into_string().unwrap();
+
+            assert!(output.contains("focus=true"), "{}", output);
+            assert!(!output.contains('#'), "{}", output);
+            assert!(kdl::KdlDocument::parse_v1(&output).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_set_no_match() {
+        let error = set_kdl(CONFIG, "client", Value::test_int(1), Some("port")).unwrap_err();
+
+        assert_eq!(error.msg, "no node matches `client`");
+    }
+}
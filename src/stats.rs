@@ -0,0 +1,127 @@
+use std::collections::HashSet;
+
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{record, Category, LabeledError, PipelineData, Signature, Type, Value};
+
+use kdl::{KdlDocument, KdlNode};
+
+use crate::{error::KdlPluginError, text, KDL};
+
+pub struct KdlStats;
+
+impl PluginCommand for KdlStats {
+    type Plugin = KDL;
+
+    fn name(&self) -> &str {
+        "kdl stats"
+    }
+
+    fn description(&self) -> &str {
+        "Count the nodes, entries and levels of a KDL document, of v2 or v1"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .input_output_type(Type::String, Type::record())
+            .input_output_type(Type::Binary, Type::record())
+            .category(Category::Experimental)
+    }
+
+    fn run(
+        &self,
+        _plugin: &KDL,
+        _engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let value = input.into_value(call.head)?;
+        let result = stats(call, &value)?;
+        Ok(PipelineData::Value(result, None))
+    }
+}
+
+#[derive(Default)]
+struct Stats<'a> {
+    node_count: usize,
+    max_depth: usize,
+    total_entries: usize,
+    names: HashSet<&'a str>,
+}
+
+/// Gives a record with `node_count`, `max_depth`, `total_entries`, `unique_node_names` and
+/// `uses_v1_syntax`, the document being parsed as v2 and then as v1. The top-level nodes are at
+/// depth 1, so an empty document has a `max_depth` of 0.
+pub(crate) fn stats(call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let source = text(input)?;
+    let (document, v1) = match source.parse::<KdlDocument>() {
+        Ok(document) => (document, false),
+        Err(_) => {
+            let document = KdlDocument::parse_v1(source).map_err(|error| KdlPluginError::ParseFailed {
+                version: "v2 and v1",
+                error,
+                span: input.span(),
+            })?;
+            (document, true)
+        }
+    };
+
+    let mut stats = Stats::default();
+    collect(document.nodes(), 1, &mut stats);
+
+    let span = call.head;
+    Ok(Value::record(
+        record! {
+            "node_count" => Value::int(stats.node_count as i64, span),
+            "max_depth" => Value::int(stats.max_depth as i64, span),
+            "total_entries" => Value::int(stats.total_entries as i64, span),
+            "unique_node_names" => Value::int(stats.names.len() as i64, span),
+            "uses_v1_syntax" => Value::bool(v1, span),
+        },
+        span,
+    ))
+}
+
+fn collect<'a>(nodes: &'a [KdlNode], depth: usize, stats: &mut Stats<'a>) {
+    for node in nodes {
+        stats.node_count += 1;
+        stats.max_depth = stats.max_depth.max(depth);
+        stats.total_entries += node.entries().len();
+        stats.names.insert(node.name().value());
+        if let Some(children) = node.children() {
+            collect(children.nodes(), depth + 1, stats);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nu_protocol::Span;
+
+    fn stat(input: &str, key: &str) -> Value {
+        let output = stats(&EvaluatedCall::new(Span::test_data()), &Value::test_string(input)).unwrap();
+        output.get_data_by_key(key).unwrap()
+    }
+
+    #[test]
+    fn test_zellij_layout_stats() {
+        let input = include_str!("../zellij-layout.kdl");
+
+        assert_eq!(stat(input, "node_count"), Value::test_int(9));
+        assert_eq!(stat(input, "uses_v1_syntax"), Value::test_bool(true));
+    }
+
+    #[test]
+    fn test_stats() {
+        let input = r#"server main port=8080 { tls #true; route "/a"; route "/b" { auth } }
+title KDL"#;
+
+        assert_eq!(stat(input, "node_count"), Value::test_int(6));
+        assert_eq!(stat(input, "max_depth"), Value::test_int(3));
+        assert_eq!(stat(input, "total_entries"), Value::test_int(6));
+        assert_eq!(stat(input, "unique_node_names"), Value::test_int(5));
+        assert_eq!(stat(input, "uses_v1_syntax"), Value::test_bool(false));
+        assert_eq!(stat("", "max_depth"), Value::test_int(0));
+    }
+}
@@ -0,0 +1,192 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{Category, LabeledError, PipelineData, Record, Signature, Span, SyntaxShape, Type, Value};
+
+use kdl::{KdlDocument, KdlIdentifier, KdlValue};
+
+use crate::{error::KdlPluginError, to, KDL};
+
+pub struct KdlTemplate;
+
+impl PluginCommand for KdlTemplate {
+    type Plugin = KDL;
+
+    fn name(&self) -> &str {
+        "kdl template"
+    }
+
+    fn description(&self) -> &str {
+        "Replace the ${name} placeholders of the strings of a KDL document with the values of a record"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .input_output_type(Type::String, Type::String)
+            .required("substitutions", SyntaxShape::Record(vec![]), "The values of the placeholders, e.g. {host: localhost}")
+            .switch("strict", "Fail on a placeholder which has no value instead of keeping it", None)
+            .switch("identifiers", "Replace the placeholders of node and property names too", None)
+            .switch("v1", "Force parsing as KDL v1 only", Some('1'))
+            .switch("v1-fallback", "Try KDL v2, fall back to v1 if parsing fails", None)
+            .category(Category::Experimental)
+    }
+
+    fn run(
+        &self,
+        plugin: &KDL,
+        _engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let value = input.into_value(call.head)?;
+        let result = template(plugin, call, &value)?;
+        Ok(PipelineData::Value(result, None))
+    }
+}
+
+struct Substitutions<'a> {
+    values: &'a Record,
+    strict: bool,
+    identifiers: bool,
+    /// Whether the document was parsed as KDL v1, which the replaced strings are written in.
+    v1: bool,
+    span: Span,
+}
+
+/// Replaces the placeholders of the string values, and of the names with `--identifiers`, keeping
+/// the rest of the document as is.
+pub(crate) fn template(plugin: &KDL, call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let values: Record = call.req(0)?;
+    let (mut document, fell_back) = plugin.parse(call, input)?;
+
+    let substitutions = Substitutions {
+        values: &values,
+        strict: call.has_flag("strict")?,
+        identifiers: call.has_flag("identifiers")?,
+        v1: fell_back || call.has_flag("v1")?,
+        span: input.span(),
+    };
+    substitute_document(&mut document, &substitutions)?;
+
+    Ok(Value::string(document.to_string(), call.head))
+}
+
+fn substitute_document(document: &mut KdlDocument, substitutions: &Substitutions) -> Result<(), LabeledError> {
+    for node in document.nodes_mut() {
+        if substitutions.identifiers {
+            if let Some(name) = substitute(node.name().value(), substitutions)? {
+                node.set_name(KdlIdentifier::from(name));
+            }
+        }
+
+        for entry in node.entries_mut() {
+            if substitutions.identifiers {
+                if let Some(name) = entry.name().map(|name| substitute(name.value(), substitutions)).transpose()?.flatten() {
+                    entry.set_name(Some(KdlIdentifier::from(name)));
+                }
+            }
+
+            let Some(string) = entry.value().as_string() else { continue };
+            let Some(string) = substitute(string, substitutions)? else { continue };
+            // the repr of the entry is the text of the placeholder, always quoted as `{` can't be
+            // in a bare word
+            if let Some(format) = entry.format_mut() {
+                format.value_repr = to::quote(&string, false);
+            }
+            entry.set_value(KdlValue::String(string));
+            if substitutions.v1 {
+                entry.ensure_v1();
+            }
+        }
+
+        if let Some(children) = node.children_mut() {
+            substitute_document(children, substitutions)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The text with its placeholders replaced, `None` when it has none to keep the original repr.
+fn substitute(text: &str, substitutions: &Substitutions) -> Result<Option<String>, LabeledError> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    let mut replaced = false;
+
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start + 2..].find('}') else { break };
+        let name = &rest[start + 2..start + 2 + len];
+
+        result.push_str(&rest[..start]);
+        match substitutions.values.get(name) {
+            Some(value) => {
+                result.push_str(&value.coerce_string()?);
+                replaced = true;
+            }
+            None if substitutions.strict => {
+                return Err(KdlPluginError::UnresolvedPlaceholder { name: name.to_string(), span: substitutions.span }.into())
+            }
+            None => result.push_str(&rest[start..start + 3 + len]),
+        }
+        rest = &rest[start + 3 + len..];
+    }
+    result.push_str(rest);
+
+    Ok(replaced.then_some(result))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nu_protocol::{record, IntoSpanned};
+
+    fn template_kdl(input: &str, call: EvaluatedCall) -> Result<String, LabeledError> {
+        let values = record! { "host" => Value::test_string("localhost"), "port" => Value::test_int(8080) };
+        let call = call.with_positional(Value::test_record(values));
+
+        template(&KDL, &call, &Value::test_string(input)).map(|v| v.into_string().unwrap())
+    }
+
+    fn with_flag(name: &str) -> EvaluatedCall {
+        EvaluatedCall::new(Span::test_data()).with_flag(name.to_string().into_spanned(Span::test_data()))
+    }
+
+    #[test]
+    fn test_substitute_argument() {
+        let output = template_kdl("// the server\nserver \"${host}:${port}\" {\n    name main\n}\n", EvaluatedCall::new(Span::test_data()));
+
+        assert_eq!(output.unwrap(), "// the server\nserver \"localhost:8080\" {\n    name main\n}\n");
+    }
+
+    #[test]
+    fn test_substitute_property() {
+        let output = template_kdl("server host=\"${host}\"   port=\"${port}\" user=\"${user}\"\n", EvaluatedCall::new(Span::test_data()));
+
+        assert_eq!(output.unwrap(), "server host=\"localhost\"   port=\"8080\" user=\"${user}\"\n");
+    }
+
+    #[test]
+    fn test_substitute_identifiers() {
+        let input = "\"${host}\" \"${port}\"=1\n";
+
+        assert_eq!(template_kdl(input, EvaluatedCall::new(Span::test_data())).unwrap(), input);
+        assert_eq!(template_kdl(input, with_flag("identifiers")).unwrap(), "localhost \"8080\"=1\n");
+    }
+
+    #[test]
+    fn test_substitute_v1_document() {
+        let input = "server \"${host}/a\" tls=true\n";
+
+        let output = template_kdl(input, with_flag("v1")).unwrap();
+
+        assert_eq!(output, "server \"localhost\\/a\" tls=true\n");
+        assert!(KdlDocument::parse_v1(&output).is_ok());
+        assert!(template_kdl(input, EvaluatedCall::new(Span::test_data())).is_err());
+    }
+
+    #[test]
+    fn test_unresolved_placeholder() {
+        let error = template_kdl("server user=\"${user}\"", with_flag("strict")).unwrap_err();
+
+        assert_eq!(error.msg, "unresolved placeholder `${user}`");
+    }
+}
@@ -1,92 +1,2120 @@
-use nu_protocol::{LabeledError, Span, Value};
+use std::{borrow::Cow, collections::HashMap};
 
-use kdl::{KdlDocument, KdlEntry, KdlNode, KdlValue, KdlIdentifier};
+use nu_protocol::{format_duration, LabeledError, Record, Span, Value};
+
+use kdl::{KdlDocument, KdlDocumentFormat, KdlEntry, KdlEntryFormat, KdlNode, KdlNodeFormat, KdlValue, KdlIdentifier};
 use miette::SourceSpan;
 
+use crate::error::KdlPluginError;
+use crate::from::{ARGS_KEY, CHILDREN_KEY, ENTRIES_KEY, ENTRY_NAME_KEY, ENTRY_VALUE_KEY, PROPS_KEY};
+
+/// The name of the node a value which isn't a record is put in, when no `--node-name` is given.
+pub(crate) const DEFAULT_NODE_NAME: &str = "node";
+
+/// How many levels of records and lists `to kdl` converts when no `--max-depth` is given.
+pub(crate) const DEFAULT_MAX_DEPTH: usize = 128;
+
+/// The key of the type annotation of an explicitly annotated value.
+pub(crate) const TYPE_KEY: &str = "$type";
+/// The key of the value of an explicitly annotated value, or of a number with its source text.
+pub(crate) const VALUE_KEY: &str = "$value";
+/// The key of the source text of a number, written as is, e.g. `{$value: 1.5, $repr: "1.50"}`.
+pub(crate) const REPR_KEY: &str = "$repr";
+/// The key of the base to write an integer in, e.g. `{$radix: hex, $value: 255}` for `0xFF`.
+pub(crate) const RADIX_KEY: &str = "$radix";
+/// The key of the digits of an integer too big for nu, e.g. `{$bignum: "99999999999999999999"}`.
+pub(crate) const BIGNUM_KEY: &str = "$bignum";
+
+/// The key of a node's record holding the comment to write before the node, reserved like the
+/// other `$` keys so that a child called `comment` stays a child.
+pub(crate) const COMMENT_KEY: &str = "$comment";
+/// The key of a node's record which, when `true`, comments the node out with a `/-` slashdash.
+pub(crate) const DISABLED_KEY: &str = "$disabled";
+/// The key of a node's record holding the type annotation of the node, e.g. `container` for
+/// `(container)div { }`.
+pub(crate) const NODE_TYPE_KEY: &str = "$node_type";
+
+/// The key of a document's record listing the keys in the order to write their nodes in, the other
+/// ones coming after them, e.g. `{b: 2, a: 1, $order: [a b]}` for `a 1` then `b 2`.
+pub(crate) const ORDER_KEY: &str = "$order";
+
+/// The keys of the canonical shape of a node, all of them optional: `{$type, $args, $props,
+/// $children}`, the last three a list, a record and a record of the nodes in the same shape.
+pub(crate) const CANONICAL_ARGS_KEY: &str = "$args";
+pub(crate) const CANONICAL_PROPS_KEY: &str = "$props";
+pub(crate) const CANONICAL_CHILDREN_KEY: &str = "$children";
+
+const SLASHDASH: &str = "/-";
+
+/// Options of `to kdl` which change how a value is turned into KDL.
+pub(crate) struct BuildOptions {
+    /// Annotate every non-string scalar with its nu type, e.g. `(int)1` or `(bool)#true`, so
+    /// `from kdl` can give back the exact same type.
+    pub annotate_types: bool,
+    /// The name of the single top-level node built when the input isn't a record.
+    pub node_name: String,
+    /// Write the non-ASCII characters of string values as `\u{...}` escapes, for tools which
+    /// only read ASCII.
+    pub escape_nonascii: bool,
+    /// Quote every string value, even those which could be written as bare identifiers.
+    pub quote_all_strings: bool,
+    /// What to do with the node and property names which have spaces.
+    pub identifier_policy: IdentifierPolicy,
+    /// The annotation `--annotate-types` writes for a nu type name, e.g. `i32` for `int`, the
+    /// type name itself when it isn't there.
+    pub type_map: HashMap<String, String>,
+    /// How the fields of a record which is a node are written.
+    pub record_as: RecordAs,
+    /// Remove the indentation all the lines of multi-line strings have in common.
+    pub dedent: bool,
+    /// How the fields which are tables are written.
+    pub table_as: TableAs,
+    /// How many levels of records and lists a value can have, deeper ones failing.
+    pub max_depth: usize,
+    /// Write the records whose keys are just the indices `0`, `1`, ... as the arguments of their
+    /// node, e.g. `{greeting: {0: hello, 1: world}}` as `greeting hello world`.
+    pub values_as_args: bool,
+    /// The keys whose integers are seconds since the unix epoch, written as `(date)` strings.
+    pub epoch_fields: Vec<String>,
+    /// Write booleans as `1` and `0`, for the consumers which don't read KDL booleans.
+    pub bool_as_int: bool,
+    /// Write a list of single-key records as the top-level nodes of the document, in order.
+    pub root_nodes_from_list: bool,
+    /// The prefix of the keys which are properties, e.g. `@` for `{@port: 8080}`, stripped from
+    /// their names.
+    pub property_prefix: Option<String>,
+    /// Read a document given as a list of `[key, value]` pairs, whose keys can repeat unlike the
+    /// ones of a record.
+    pub allow_duplicate_keys: bool,
+    /// Write the fields which are empty records as nodes with an empty `{}` block rather than
+    /// bare nodes.
+    pub preserve_empty_children: bool,
+    /// The names of the child nodes the rows of the tables of these fields are written as, in the
+    /// node of the field, e.g. `route` for `routes { route { path /a } }`.
+    pub child_node_name: HashMap<String, String>,
+    /// How durations are written.
+    pub duration_format: DurationFormat,
+    /// Leave out the fields of records which are null, rather than writing them as bare nodes or
+    /// `#null` properties.
+    pub null_omit: bool,
+    /// Write the records whose keys are all `arg0`, `arg1`, ... as the arguments of their node, in
+    /// the order of the numbers, e.g. `{cmd: {arg1: fast, arg0: run}}` as `cmd run fast`.
+    pub arg_order_from_key_suffix: bool,
+}
+
+/// How `to kdl` writes a duration, e.g. `5min`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) enum DurationFormat {
+    /// As its number of nanoseconds: `300000000000`.
+    #[default]
+    Nanos,
+    /// As an ISO 8601 `(duration)` string: `(duration)PT5M`.
+    Iso,
+    /// As a `(duration)` string the way nu displays it: `(duration)"5min"`.
+    Human,
+}
+
+/// How `to kdl` writes a field which is a table, e.g. `{routes: [{path: /a}, {path: /b}]}`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) enum TableAs {
+    /// As the entries of a single node, a row being a property: `routes path=/a path=/b`.
+    #[default]
+    Entries,
+    /// As a node per row named after the field: `routes { path /a }` twice.
+    Nodes,
+    /// As a node per row named after the field in the singular: `route { path /a }` twice.
+    SingularNodes,
+}
+
+/// How `to kdl` writes the fields of a record given as a node, e.g. `{server: {host: x}}`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) enum RecordAs {
+    /// As properties, the fields which are records or lists still being children: `server host=x`.
+    Props,
+    /// As children: `server { host x }`.
+    #[default]
+    Children,
+}
+
+/// How `to kdl` writes a record key with spaces as a node or property name.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) enum IdentifierPolicy {
+    /// Fail, for documents whose names should never need quotes.
+    Error,
+    /// Quote the name, e.g. `"my key"`.
+    #[default]
+    Quote,
+    /// Replace the spaces with `_`, e.g. `my_key`.
+    Replace,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        Self {
+            annotate_types: false,
+            node_name: DEFAULT_NODE_NAME.to_string(),
+            escape_nonascii: false,
+            quote_all_strings: false,
+            identifier_policy: IdentifierPolicy::default(),
+            type_map: HashMap::new(),
+            record_as: RecordAs::default(),
+            dedent: false,
+            table_as: TableAs::default(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            values_as_args: false,
+            epoch_fields: Vec::new(),
+            bool_as_int: false,
+            root_nodes_from_list: false,
+            property_prefix: None,
+            allow_duplicate_keys: false,
+            preserve_empty_children: false,
+            child_node_name: HashMap::new(),
+            duration_format: DurationFormat::default(),
+            null_omit: false,
+            arg_order_from_key_suffix: false,
+        }
+    }
+}
+
 fn span(value: &Value) -> SourceSpan {
     let Span { start, end } = value.span();
-    SourceSpan::new(start.into(), (end - start).into())
+    SourceSpan::new(start.into(), end - start)
 }
 
-pub(crate) fn build_document(document: &Value) -> Result<KdlDocument, LabeledError> {
+pub(crate) fn build_document(document: &Value, options: &BuildOptions) -> Result<KdlDocument, LabeledError> {
     let mut doc = KdlDocument::new();
 
     doc.set_span(span(document));
 
     let nodes = doc.nodes_mut();
 
-    // TODO: implement the else branch
-    let record = document.as_record().map_err(|_| LabeledError::new("Expected a record"))?;
+    match document {
+        Value::Record { val: record, .. } => {
+            for (col, val) in ordered(record)? {
+                if options.null_omit && val.is_nothing() {
+                    continue;
+                }
+                let val = &*epoch(col, val, options);
+                match val {
+                    Value::List { vals, .. } if options.table_as != TableAs::Entries && !options.child_node_name.contains_key(node_name(col)) && is_table(vals) => {
+                        let name = match options.table_as {
+                            TableAs::SingularNodes => singular(node_name(col)),
+                            _ => node_name(col).to_string(),
+                        };
+                        for row in vals {
+                            nodes.push(build_node(&name, row, options)?);
+                        }
+                    }
+                    _ => nodes.push(build_node(node_name(col), val, options)?),
+                }
+            }
+        }
+        Value::List { vals, .. } if options.allow_duplicate_keys => {
+            for pair in vals {
+                let (name, node) = key_value_pair(pair)?;
+                nodes.push(build_node(node_name(name), node, options)?);
+            }
+        }
+        // a list of `{name: value}` records can repeat names, unlike the fields of a record
+        Value::List { vals, .. } if options.root_nodes_from_list && vals.iter().all(|val| matches!(val, Value::Record { val, .. } if val.len() == 1)) => {
+            for val in vals {
+                let (name, node) = val.as_record().unwrap().iter().next().unwrap();
+                nodes.push(build_node(node_name(name), node, options)?);
+            }
+        }
+        // nothing is the empty document, just like `{}`
+        Value::Nothing { .. } => {}
+        // a value without a name, e.g. `"hello" | to kdl`, becomes a single node
+        _ => nodes.push(build_node(&options.node_name, document, options)?),
+    }
+
+    Ok(doc)
+}
+
+/// The key and value of a `[key, value]` pair of `--allow-duplicate-keys`.
+fn key_value_pair(pair: &Value) -> Result<(&str, &Value), KdlPluginError> {
+    match pair {
+        Value::List { vals, .. } => match vals.as_slice() {
+            [Value::String { val: key, .. }, value] => Ok((key, value)),
+            _ => Err(KdlPluginError::InvalidPair { ty: pair.get_type(), span: pair.span() }),
+        },
+        _ => Err(KdlPluginError::InvalidPair { ty: pair.get_type(), span: pair.span() }),
+    }
+}
+
+/// The fields of a document's record in the order of its `$order` keys, if any, then the ones it
+/// doesn't list in their own order. Keys of `$order` which aren't in the record are ignored.
+fn ordered(record: &Record) -> Result<Vec<(&String, &Value)>, KdlPluginError> {
+    let Some(order) = record.get(ORDER_KEY) else { return Ok(record.iter().collect()) };
+    let keys = order.as_list().map_err(|_| KdlPluginError::InvalidOrder { ty: order.get_type(), span: order.span() })?;
 
+    let mut fields: Vec<(&String, &Value)> = Vec::with_capacity(record.len());
+    for key in keys {
+        let name = key.as_str().map_err(|_| KdlPluginError::InvalidOrder { ty: key.get_type(), span: key.span() })?;
+        if name == ORDER_KEY || fields.iter().any(|(col, _)| *col == name) {
+            continue;
+        }
+        fields.extend(record.iter().find(|(col, _)| *col == name));
+    }
     for (col, val) in record.iter() {
-        let node = build_node(col, val)?;
-        nodes.push(node);
+        if col != ORDER_KEY && !fields.iter().any(|(c, _)| *c == col) {
+            fields.push((col, val));
+        }
     }
 
-    Ok(doc)
+    Ok(fields)
+}
+
+/// Puts every children block of an autoformatted document with at most `threshold` nodes on a
+/// single line, e.g. `node { a 1; b 2 }`, all of them with `usize::MAX`.
+///
+/// A block with a comment stays on several lines, as a `//` comment would swallow the rest of it,
+/// and so does a block with a child which does.
+pub(crate) fn inline_children(document: &mut KdlDocument, threshold: usize) {
+    for node in document.nodes_mut() {
+        inline_node(node, threshold);
+    }
+}
+
+/// Puts the children block of a node on a single line when it can, telling whether it did or the
+/// node has none.
+fn inline_node(node: &mut KdlNode, threshold: usize) -> bool {
+    let Some(children) = node.children_mut() else { return true };
+
+    // every child block is inlined when it can be, even when this one can't
+    let mut inline = true;
+    for child in children.nodes_mut() {
+        inline &= inline_node(child, threshold);
+    }
+    inline &= !children.nodes().iter().any(|n| n.format().is_some_and(|f| !f.leading.trim().is_empty()));
+    let count = children.nodes().len();
+    if !inline || count > threshold {
+        return false;
+    }
+
+    for (i, child) in children.nodes_mut().iter_mut().enumerate() {
+        let format = child.format().cloned().unwrap_or_default();
+        child.set_format(KdlNodeFormat {
+            leading: if i == 0 { String::new() } else { " ".to_string() },
+            terminator: if i + 1 < count { ";".to_string() } else { String::new() },
+            trailing: String::new(),
+            ..format
+        });
+    }
+    let padding = if count == 0 { "" } else { " " };
+    children.set_format(KdlDocumentFormat { leading: padding.to_string(), trailing: padding.to_string() });
+    true
+}
+
+/// Puts the whole document on a single line, its nodes separated by `;` and their children in
+/// inline `{ }` blocks. The `//` comments, which would end the line, become `/* */` ones.
+pub(crate) fn oneline(document: &mut KdlDocument) {
+    document.set_format(KdlDocumentFormat::default());
+    oneline_nodes(document);
+}
+
+fn oneline_nodes(document: &mut KdlDocument) {
+    let count = document.nodes().len();
+    for (i, node) in document.nodes_mut().iter_mut().enumerate() {
+        let format = node.format().cloned().unwrap_or_default();
+        let mut leading = if i > 0 { " ".to_string() } else { String::new() };
+        for line in format.leading.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            leading.push_str(&block_comment(line));
+            leading.push(' ');
+        }
+        node.set_format(KdlNodeFormat {
+            leading,
+            terminator: if i + 1 < count { ";".to_string() } else { String::new() },
+            trailing: String::new(),
+            ..format
+        });
+        if let Some(children) = node.children_mut() {
+            let padding = if children.nodes().is_empty() { "" } else { " " };
+            children.set_format(KdlDocumentFormat { leading: padding.to_string(), trailing: padding.to_string() });
+            oneline_nodes(children);
+        }
+    }
+}
+
+/// A `/* */` comment for a `// comment` line, the other lines like the `/-` of disabled nodes
+/// being kept as they are.
+fn block_comment(line: &str) -> String {
+    match line.strip_prefix("//") {
+        Some(comment) => format!("/* {} */", comment.trim().replace("*/", "* /")),
+        None => line.to_string(),
+    }
+}
+
+/// Whether a list is made of records which are nodes rather than annotated values, e.g. a table.
+fn is_table(vals: &[Value]) -> bool {
+    !vals.is_empty() && vals.iter().all(|val| matches!(val, Value::Record { val: record, .. } if !is_single_value(record)))
+}
+
+/// The English singular of a name, well enough for the names of tables: `routes` is `route` and
+/// `entries` is `entry`, a name which doesn't end with `s` staying the same.
+fn singular(name: &str) -> String {
+    if let Some(stem) = name.strip_suffix("ies").filter(|stem| !stem.is_empty()) {
+        return format!("{}y", stem);
+    }
+    match name.strip_suffix('s') {
+        Some(stem) if !stem.is_empty() && !stem.ends_with('s') => stem.to_string(),
+        _ => name.to_string(),
+    }
 }
 
-fn build_node(name: &str, node: &Value) -> Result<KdlNode, LabeledError> {
-    let mut identifier = KdlIdentifier::from(name);
+/// The name of the node of a record key, without the `#n` suffix a record needs to repeat a name,
+/// `{pane: a, pane#1: b}` being two `pane` nodes, just like the paths of `kdl diff`.
+fn node_name(key: &str) -> &str {
+    match key.rsplit_once('#') {
+        Some((name, rank)) if !name.is_empty() && !rank.is_empty() && rank.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => key,
+    }
+}
+
+pub(crate) fn build_node(name: &str, node: &Value, options: &BuildOptions) -> Result<KdlNode, LabeledError> {
+    let name = &identifier_name(name, node.span(), options)?;
+    let mut identifier = KdlIdentifier::from(name.as_str());
     identifier.set_repr(name);
     let mut kdl_node = KdlNode::new(identifier);
 
     kdl_node.set_span(span(node));
 
+    // a string `$comment` field is written as a `//` comment right before the node, and a
+    // `$disabled` one comments the node out, the other fields being the node itself
+    if let Value::Record { val: record, .. } = node {
+        if let Some(Value::Bool { val: disabled, .. }) = record.get(DISABLED_KEY) {
+            let mut kdl_node = build_node(name, &without(record, DISABLED_KEY, node.span()), options)?;
+            if *disabled {
+                let mut format = kdl_node.format().cloned().unwrap_or_default();
+                format.leading.push_str(SLASHDASH);
+                kdl_node.set_format(format);
+            }
+            return Ok(kdl_node);
+        }
+        if let Some(Value::String { val: ty, .. }) = record.get(NODE_TYPE_KEY) {
+            let mut kdl_node = build_node(name, &without(record, NODE_TYPE_KEY, node.span()), options)?;
+            kdl_node.set_ty(identifier_name(ty, node.span(), options)?.as_str());
+            return Ok(kdl_node);
+        }
+        if let Some(Value::String { val: comment, .. }) = record.get(COMMENT_KEY) {
+            let mut kdl_node = build_node(name, &without(record, COMMENT_KEY, node.span()), options)?;
+            kdl_node.set_format(KdlNodeFormat {
+                leading: comment.lines().map(|line| format!("// {}\n", line)).collect(),
+                ..Default::default()
+            });
+            return Ok(kdl_node);
+        }
+    }
+
     kdl_node.clear_children();
-    let entries = kdl_node.entries_mut();
+    match node {
+        // the shape which says what every part is, before any of the shapes guessed from the keys
+        Value::Record { val: record, .. } if is_canonical(record) => {
+            if let Some(Value::String { val: ty, .. }) = record.get(TYPE_KEY) {
+                kdl_node.set_ty(identifier_name(ty, node.span(), options)?.as_str());
+            }
+            if let Some(args) = record.get(CANONICAL_ARGS_KEY) {
+                for arg in args.as_list().unwrap() {
+                    kdl_node.entries_mut().push(build_argument(arg, options)?);
+                }
+            }
+            if let Some(props) = record.get(CANONICAL_PROPS_KEY) {
+                for (key, val) in props.as_record().unwrap().iter() {
+                    let mut entry = build_argument(val, options)?;
+                    entry.set_name(Some(identifier_name(key, val.span(), options)?));
+                    kdl_node.entries_mut().push(entry);
+                }
+            }
+            if let Some(children) = record.get(CANONICAL_CHILDREN_KEY) {
+                kdl_node.set_children(build_document(children, options)?);
+            }
+        }
+        Value::List { vals, .. } if is_table(vals) && options.child_node_name.contains_key(name) => {
+            let child = &options.child_node_name[name];
+            let mut children = KdlDocument::new();
+            for row in vals {
+                children.nodes_mut().push(build_node(child, row, options)?);
+            }
+            kdl_node.set_children(children);
+        }
+        Value::Record { val: record, .. } if is_ordered_entries(record) => {
+            for entry in record.get(ENTRIES_KEY).unwrap().as_list().unwrap() {
+                let entry = entry.as_record().unwrap();
+                let mut kdl_entry = build_argument(entry.get(ENTRY_VALUE_KEY).unwrap(), options)?;
+                if let Value::String { val: name, .. } = entry.get(ENTRY_NAME_KEY).unwrap() {
+                    kdl_entry.set_name(Some(identifier_name(name, node.span(), options)?));
+                }
+                kdl_node.entries_mut().push(kdl_entry);
+            }
+            if let Some(children) = record.get(CHILDREN_KEY) {
+                kdl_node.set_children(build_document(children, options)?);
+            }
+        }
+        Value::Record { val: record, .. } if is_entries_and_children(record) => {
+            // KDL arguments always come before the child block, so the entries are pushed before
+            // the children are set, whatever the order of the keys in the record
+            push_entries(kdl_node.entries_mut(), record.get(ENTRIES_KEY).unwrap(), options)?;
+            kdl_node.set_children(build_document(record.get(CHILDREN_KEY).unwrap(), options)?);
+        }
+        Value::Record { val: record, .. } if is_flattened(record) => {
+            if let Some(args) = record.get(ARGS_KEY) {
+                for arg in args.as_list().unwrap() {
+                    kdl_node.entries_mut().push(build_argument(arg, options)?);
+                }
+            }
+            if let Some(props) = record.get(PROPS_KEY) {
+                for (key, val) in props.as_record().unwrap().iter() {
+                    let val = &*epoch(key, val, options);
+                    let mut entry = build_argument(val, options)?;
+                    entry.set_name(Some(identifier_name(key, val.span(), options)?));
+                    kdl_node.entries_mut().push(entry);
+                }
+            }
+            if let Some(children) = record.get(CHILDREN_KEY) {
+                kdl_node.set_children(build_document(children, options)?);
+            }
+        }
+        Value::Record { val: record, .. } if is_single_value(record) => {
+            push_entries(kdl_node.entries_mut(), node, options)?
+        }
+        Value::Record { val: record, .. } if options.values_as_args && is_tuple(record) => {
+            for val in record.values() {
+                kdl_node.entries_mut().push(build_argument(val, options)?);
+            }
+        }
+        Value::Record { val: record, .. } if options.arg_order_from_key_suffix && record.columns().all(|key| arg_index(key).is_some()) && !record.is_empty() => {
+            let mut args: Vec<(u64, &Value)> = record.iter().map(|(key, val)| (arg_index(key).unwrap(), val)).collect();
+            args.sort_by_key(|(index, _)| *index);
+            for (_, val) in args {
+                kdl_node.entries_mut().push(build_argument(val, options)?);
+            }
+        }
+        Value::Record { val: record, .. } if options.record_as == RecordAs::Props => {
+            let mut children = Record::new();
+            for (key, val) in record.iter() {
+                if options.null_omit && val.is_nothing() {
+                    continue;
+                }
+                let val = &*epoch(key, val, options);
+                match val {
+                    Value::Record { val: field, .. } if !is_single_value(field) => children.push(key, val.clone()),
+                    Value::List { .. } => children.push(key, val.clone()),
+                    _ => {
+                        let mut entry = build_argument(val, options)?;
+                        entry.set_name(Some(identifier_name(key, val.span(), options)?));
+                        kdl_node.entries_mut().push(entry);
+                    }
+                }
+            }
+            if !children.is_empty() {
+                kdl_node.set_children(build_document(&Value::record(children, node.span()), options)?);
+            }
+        }
+        Value::Record { val: record, .. } if options.property_prefix.as_ref().is_some_and(|prefix| record.columns().any(|key| key.starts_with(prefix.as_str()))) => {
+            let prefix = options.property_prefix.as_deref().unwrap();
+            let mut children = Record::new();
+            for (key, val) in record.iter() {
+                if options.null_omit && val.is_nothing() {
+                    continue;
+                }
+                match key.strip_prefix(prefix) {
+                    Some(name) => {
+                        let val = &*epoch(name, val, options);
+                        let mut entry = build_argument(val, options)?;
+                        entry.set_name(Some(identifier_name(name, val.span(), options)?));
+                        kdl_node.entries_mut().push(entry);
+                    }
+                    None => children.push(key, val.clone()),
+                }
+            }
+            if !children.is_empty() {
+                kdl_node.set_children(build_document(&Value::record(children, node.span()), options)?);
+            }
+        }
+        // a bare node, unless asked for the `{}` block `from kdl` reads back as an empty record
+        Value::Record { val: record, .. } if record.is_empty() && !options.preserve_empty_children => {}
+        // a record is the most intuitive way to write children, e.g. `{server: {host: "x"}}`
+        // becomes `server { host x }`
+        Value::Record { .. } => kdl_node.set_children(build_document(node, options)?),
+        _ => push_entries(kdl_node.entries_mut(), node, options)?,
+    }
+
+    Ok(kdl_node)
+}
+
+/// The value of a field, a `(date)` string in RFC 3339 for an integer of `--epoch-fields`, e.g.
+/// `(date)"2023-11-14T22:13:20+00:00"` for `{created: 1700000000}`.
+fn epoch<'a>(key: &str, val: &'a Value, options: &BuildOptions) -> Cow<'a, Value> {
+    match val {
+        Value::Int { val: seconds, .. } if options.epoch_fields.iter().any(|field| field == key) => {
+            match chrono::DateTime::from_timestamp(*seconds, 0) {
+                Some(date) => Cow::Owned(Value::record(
+                    Record::from_iter([
+                        (TYPE_KEY.to_string(), Value::string("date", val.span())),
+                        (VALUE_KEY.to_string(), Value::string(date.to_rfc3339(), val.span())),
+                    ]),
+                    val.span(),
+                )),
+                // out of the range of dates, left as the integer it is
+                None => Cow::Borrowed(val),
+            }
+        }
+        _ => Cow::Borrowed(val),
+    }
+}
+
+/// Collects the record keys of a value which have a code point KDL doesn't allow anywhere in a
+/// document, not even escaped by the quoting of names, like most control characters.
+pub(crate) fn invalid_identifiers(value: &Value, names: &mut Vec<String>) {
+    match value {
+        Value::Record { val: record, .. } => {
+            for (key, val) in record.iter() {
+                if key.chars().any(is_disallowed) && !names.contains(key) {
+                    names.push(key.clone());
+                }
+                invalid_identifiers(val, names);
+            }
+        }
+        Value::List { vals, .. } => vals.iter().for_each(|val| invalid_identifiers(val, names)),
+        _ => {}
+    }
+}
+
+/// The first NaN or infinite float of a value, which `to kdl --v1` can't write.
+pub(crate) fn non_finite_float(value: &Value) -> Option<&Value> {
+    match value {
+        Value::Float { val, .. } if !val.is_finite() => Some(value),
+        Value::Record { val: record, .. } => record.values().find_map(non_finite_float),
+        Value::List { vals, .. } => vals.iter().find_map(non_finite_float),
+        _ => None,
+    }
+}
+
+/// The code points of the KDL spec which can't be in a document, `\t`, `\n` and the other
+/// whitespace excepted.
+fn is_disallowed(c: char) -> bool {
+    matches!(c, '\u{0}'..='\u{8}' | '\u{E}'..='\u{1F}' | '\u{7F}' | '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}' | '\u{FEFF}')
+}
+
+/// Fails when records and lists are nested more than `max_depth` levels in a value, for
+/// `build_node` not to recurse into it. The walk uses a work stack rather than recursion, so that no
+/// value, however deep, overflows the stack of the plugin.
+pub(crate) fn check_depth(value: &Value, max_depth: usize) -> Result<(), KdlPluginError> {
+    let mut stack = vec![(value, 0)];
+    while let Some((value, depth)) = stack.pop() {
+        let vals: Box<dyn Iterator<Item = &Value>> = match value {
+            Value::Record { val: record, .. } => Box::new(record.values()),
+            Value::List { vals, .. } => Box::new(vals.iter()),
+            _ => continue,
+        };
+        if depth == max_depth {
+            return Err(KdlPluginError::TooDeep { max_depth, span: value.span() });
+        }
+        stack.extend(vals.map(|val| (val, depth + 1)));
+    }
+    Ok(())
+}
+
+/// The name of a node or property for a record key, following `--identifier-policy` when it has
+/// spaces.
+fn identifier_name(key: &str, span: Span, options: &BuildOptions) -> Result<String, LabeledError> {
+    if !key.contains(' ') {
+        return Ok(key.to_string());
+    }
+
+    match options.identifier_policy {
+        IdentifierPolicy::Error => Err(KdlPluginError::SpaceInIdentifier { name: key.to_string(), span }.into()),
+        IdentifierPolicy::Quote => Ok(key.to_string()),
+        IdentifierPolicy::Replace => Ok(key.replace(' ', "_")),
+    }
+}
+
+/// Whether `record` is the `{entries, children}` shape of `from kdl --ordered-entries`, `children`
+/// being optional and `entries` a table of `name` and `value`, which tells it apart from the
+/// default shape of a node with entries and children.
+fn is_ordered_entries(record: &Record) -> bool {
+    let entries = record.get(ENTRIES_KEY).and_then(|entries| entries.as_list().ok()).is_some_and(|entries| {
+        entries.iter().all(|entry| {
+            entry.as_record().is_ok_and(|entry| {
+                entry.len() == 2
+                    && entry.get(ENTRY_NAME_KEY).is_some_and(|name| matches!(name, Value::String { .. } | Value::Nothing { .. }))
+                    && entry.contains(ENTRY_VALUE_KEY)
+            })
+        })
+    });
+    let children = record.get(CHILDREN_KEY).is_none_or(|children| matches!(children, Value::Record { .. }));
+
+    entries && children && record.len() == 1 + usize::from(record.contains(CHILDREN_KEY))
+}
+
+/// Whether `record` is the `{entries, children}` shape `from kdl` gives to a node which has both
+/// entries and children.
+fn is_entries_and_children(record: &Record) -> bool {
+    record.len() == 2 && record.contains(ENTRIES_KEY) && record.contains(CHILDREN_KEY)
+}
+
+/// The node of a record without one of its special fields, its `$value` when that's the only
+/// field left, e.g. `{$disabled: true, $value: 80}` is the node `80`.
+fn without(record: &Record, key: &str, span: Span) -> Value {
+    let mut rest = record.clone();
+    rest.remove(key);
+
+    match rest.get(VALUE_KEY) {
+        Some(value) if rest.len() == 1 => value.clone(),
+        _ => Value::record(rest, span),
+    }
+}
+
+/// Writes the empty children blocks of `{}` records as `{}`, autoformatting putting their `}` on a
+/// line of its own.
+pub(crate) fn close_empty_blocks(document: &mut KdlDocument) {
+    for node in document.nodes_mut() {
+        let Some(children) = node.children_mut() else { continue };
+        if children.nodes().is_empty() {
+            children.set_format(KdlDocumentFormat::default());
+        } else {
+            close_empty_blocks(children);
+        }
+    }
+}
+
+/// Puts the `/-` of disabled nodes back in front of them, autoformatting having moved it to a
+/// line of its own.
+pub(crate) fn slashdash_disabled(document: &mut KdlDocument) {
+    for node in document.nodes_mut() {
+        if let Some(format) = node.format_mut() {
+            let indent = format.leading.rsplit('\n').next().unwrap_or_default().to_string();
+            let moved = format!("{}{}\n{}", indent, SLASHDASH, indent);
+            if format.leading.ends_with(&moved) {
+                let start = format.leading.len() - moved.len();
+                format.leading.replace_range(start.., &format!("{}{}", indent, SLASHDASH));
+            }
+        }
+        if let Some(children) = node.children_mut() {
+            slashdash_disabled(children);
+        }
+    }
+}
+
+/// Whether `record` is the canonical `{$type, $args, $props, $children}` shape of a node, with at
+/// least one of the last three, so that `{$type, $value}` stays an annotated value.
+fn is_canonical(record: &Record) -> bool {
+    record.iter().any(|(key, _)| matches!(key.as_str(), CANONICAL_ARGS_KEY | CANONICAL_PROPS_KEY | CANONICAL_CHILDREN_KEY))
+        && record.iter().all(|(key, val)| match key.as_str() {
+            TYPE_KEY => matches!(val, Value::String { .. }),
+            CANONICAL_ARGS_KEY => matches!(val, Value::List { .. }),
+            CANONICAL_PROPS_KEY | CANONICAL_CHILDREN_KEY => matches!(val, Value::Record { .. }),
+            _ => false,
+        })
+}
+
+/// Whether `record` is the `{args, props, children}` shape of `from kdl --flatten`, any part of
+/// which can be left out. The parts have to be a list, a record and a record respectively, so a
+/// node with a single child called e.g. `args` isn't taken for this shape.
+fn is_flattened(record: &Record) -> bool {
+    !record.is_empty()
+        && record.iter().all(|(key, val)| match key.as_str() {
+            ARGS_KEY => matches!(val, Value::List { .. }),
+            PROPS_KEY | CHILDREN_KEY => matches!(val, Value::Record { .. }),
+            _ => false,
+        })
+}
+
+fn push_entries(entries: &mut Vec<KdlEntry>, node: &Value, options: &BuildOptions) -> Result<(), LabeledError> {
     match node {
         Value::Nothing { .. } => {}
-        Value::String { .. } | Value::Int { .. } | Value::Float { .. } | Value::Bool { .. } => {
-            entries.push(build_entry(node).unwrap())
+        Value::String { .. } | Value::Int { .. } | Value::Float { .. } | Value::Bool { .. } | Value::Duration { .. } => {
+            entries.push(build_entry(node, options)?)
         }
         Value::List { vals, .. } => {
+            // single-key records are properties and everything else is an argument, kept in the
+            // order of the list, which is how `from kdl` represents a node with both
             for val in vals {
-                entries.push(build_entry(val).unwrap())
+                entries.push(build_entry(val, options)?)
             }
         }
-        // TODO: implement when node is a record, i.e. with children
-        // TODO: default arm
-        _ => todo!(),
+        Value::Record { val: record, .. } if record.len() == 1 || is_single_value(record) => {
+            entries.push(build_entry(node, options)?)
+        }
+        Value::Custom { .. } => entries.push(build_entry(node, options)?),
+        _ => return Err(unsupported(node)),
     }
 
-    Ok(kdl_node)
+    Ok(())
 }
 
-fn build_entry(entry: &Value) -> Result<KdlEntry, LabeledError> {
+fn build_entry(entry: &Value, options: &BuildOptions) -> Result<KdlEntry, LabeledError> {
     let entry_span = span(entry);
 
     let mut entry = match entry {
-        Value::Record { val: record, .. } => {
+        // e.g. the values of other plugins, which are supported when what they stand for is
+        Value::Custom { val, .. } => build_entry(&val.to_base_value(entry.span())?, options)?,
+        Value::Record { val: record, .. } if !is_single_value(record) => {
             if record.len() != 1 {
-                return Err(LabeledError::new("entry should be either a record with one key"));
+                return Err(KdlPluginError::InvalidProperty { len: record.len(), span: entry.span() }.into());
             }
 
             let (key, val) = record.iter().next().unwrap();
+            let key = options.property_prefix.as_deref().and_then(|prefix| key.strip_prefix(prefix)).unwrap_or(key);
 
-            let kdl_val = match val {
-                Value::String { val, .. } => KdlValue::String(val.to_string()),
-                Value::Int { val, .. } => KdlValue::from(*val as i128),
-                Value::Float { val, .. } => KdlValue::from(*val),
-                Value::Bool { val, .. } => KdlValue::Bool(*val),
-                Value::Nothing { .. } => KdlValue::Null,
-                _ => {
-                    return Err(LabeledError::new("value not supported, expected string, int, float, bool or null"));
-                }
+            let mut entry = build_argument(val, options)?;
+            // the key is the unquoted name, which kdl quotes again when it isn't a valid identifier
+            entry.set_name(Some(identifier_name(key, val.span(), options)?));
+            entry
+        }
+        _ => build_argument(entry, options)?,
+    };
+
+    entry.set_span(entry_span);
+
+    Ok(entry)
+}
+
+/// Builds a positional entry, the name of properties being set by `build_entry`.
+pub(crate) fn build_argument(value: &Value, options: &BuildOptions) -> Result<KdlEntry, LabeledError> {
+    if let Value::Record { val: record, .. } = value {
+        if is_number_repr(record) {
+            let mut entry = build_argument(record.get(VALUE_KEY).unwrap(), options)?;
+            // the repr is only written when it's a single number with the value, so that an edited
+            // `$value` isn't undone by a stale repr and no repr writes anything but a number
+            let repr = record.get(REPR_KEY).unwrap().as_str()?.trim();
+            let parsed: Option<KdlEntry> = repr.parse().ok();
+            if parsed.is_some_and(|parsed| {
+                matches!(parsed.value(), KdlValue::Integer(_) | KdlValue::Float(_)) && parsed.name().is_none() && parsed.ty().is_none() && parsed.value() == entry.value()
+            }) {
+                entry.set_format(KdlEntryFormat {
+                    value_repr: repr.to_string(),
+                    leading: " ".to_string(),
+                    autoformat_keep: true,
+                    ..Default::default()
+                });
+            }
+            return Ok(entry);
+        }
+        if let (true, Some(Value::String { val: digits, .. })) = (is_bignum(record), record.get(BIGNUM_KEY)) {
+            // kdl parses the digits, as any integer it can read is one it can write
+            let invalid = || KdlPluginError::InvalidBignum { digits: digits.clone(), span: value.span() };
+            let mut entry: KdlEntry = digits.trim().parse().map_err(|_| invalid())?;
+            if !matches!(entry.value(), KdlValue::Integer(_)) || entry.name().is_some() || entry.ty().is_some() {
+                return Err(invalid().into());
+            }
+            entry.set_format(KdlEntryFormat {
+                value_repr: digits.trim().to_string(),
+                leading: " ".to_string(),
+                autoformat_keep: true,
+                ..Default::default()
+            });
+            return Ok(entry);
+        }
+        if is_radix(record) {
+            let radix = record.get(RADIX_KEY).unwrap();
+            let value = record.get(VALUE_KEY).unwrap().as_int()?;
+            let digits = match radix.as_str()? {
+                "hex" => format!("0x{:X}", value.unsigned_abs()),
+                "oct" => format!("0o{:o}", value.unsigned_abs()),
+                "bin" => format!("0b{:b}", value.unsigned_abs()),
+                other => return Err(KdlPluginError::InvalidRadix { radix: other.to_string(), span: radix.span() }.into()),
             };
 
-            KdlEntry::new_prop(key.clone(), kdl_val)
+            let mut entry = KdlEntry::new(KdlValue::from(i128::from(value)));
+            entry.set_format(KdlEntryFormat {
+                value_repr: if value < 0 { format!("-{}", digits) } else { digits },
+                leading: " ".to_string(),
+                autoformat_keep: true,
+                ..Default::default()
+            });
+            return Ok(entry);
+        }
+    }
+
+    let (mut ty, value) = match value {
+        Value::Record { val: record, .. } if is_annotated(record) => {
+            let ty = record.get(TYPE_KEY).unwrap();
+            let ty = ty.as_str().map_err(|_| KdlPluginError::InvalidAnnotation {
+                ty: ty.get_type(),
+                span: ty.span(),
+            })?;
+            // the value can itself be a `$repr`, `$bignum` or `$radix` record, e.g. from
+            // `from kdl --keep-annotations --preserve-number-repr` of `(u8)0x10`
+            let mut entry = build_argument(record.get(VALUE_KEY).unwrap(), options)?;
+            entry.set_ty(ty);
+            return Ok(entry);
         }
-        Value::String { val, .. } => KdlEntry::new(KdlValue::String(val.to_string())),
-        Value::Int { val, .. } => KdlEntry::new(KdlValue::from(*val as i128)),
-        Value::Float { val, .. } => KdlEntry::new(KdlValue::from(*val)),
-        Value::Bool { val, .. } => KdlEntry::new(KdlValue::Bool(*val)),
-        Value::Nothing { .. } => KdlEntry::new(KdlValue::Null),
-        // TODO: default arm
-        _ => todo!(),
+        _ if options.annotate_types => {
+            let ty = type_annotation(value).map(|ty| options.type_map.get(ty).map_or(ty, String::as_str));
+            (ty, value)
+        }
+        _ => (None, value),
     };
 
-    entry.set_span(entry_span);
+    let kdl_val = match value {
+        Value::String { val, .. } if options.dedent && val.contains('\n') => KdlValue::String(dedent(val)),
+        Value::String { val, .. } => KdlValue::String(val.to_string()),
+        Value::Int { val, .. } => KdlValue::from(i128::from(*val)),
+        Value::Float { val, .. } => KdlValue::from(*val),
+        Value::Bool { val, .. } if options.bool_as_int => KdlValue::Integer(i128::from(*val)),
+        Value::Bool { val, .. } => KdlValue::Bool(*val),
+        Value::Nothing { .. } => KdlValue::Null,
+        Value::Duration { val, .. } if options.duration_format == DurationFormat::Nanos => KdlValue::from(i128::from(*val)),
+        Value::Duration { val, .. } => {
+            // the annotation tells the string is a duration, unless it already has one
+            ty = ty.or(Some("duration"));
+            match options.duration_format {
+                DurationFormat::Iso => KdlValue::String(iso_duration(*val)),
+                _ => KdlValue::String(format_duration(*val)),
+            }
+        }
+        _ => return Err(unsupported(value)),
+    };
 
+    let mut entry = KdlEntry::new(kdl_val);
+    if let Some(ty) = ty {
+        entry.set_ty(ty);
+    }
     Ok(entry)
 }
+
+/// The ISO 8601 form of a duration in nanoseconds, e.g. `PT5M` or `P1DT0.5S`, in days at most as
+/// the longer units have no fixed length, and with a `-` sign when it's negative.
+fn iso_duration(nanos: i64) -> String {
+    let sign = if nanos < 0 { "-" } else { "" };
+    let nanos = nanos.unsigned_abs();
+    let (seconds, fraction) = (nanos / 1_000_000_000, nanos % 1_000_000_000);
+    let (days, hours, minutes, seconds) = (seconds / 86_400, seconds / 3_600 % 24, seconds / 60 % 60, seconds % 60);
+
+    let mut iso = format!("{}P", sign);
+    if days > 0 {
+        iso.push_str(&format!("{}D", days));
+    }
+    if days == 0 || hours > 0 || minutes > 0 || seconds > 0 || fraction > 0 {
+        iso.push('T');
+    }
+    if hours > 0 {
+        iso.push_str(&format!("{}H", hours));
+    }
+    if minutes > 0 {
+        iso.push_str(&format!("{}M", minutes));
+    }
+    if fraction > 0 {
+        let digits = format!("{:09}", fraction);
+        iso.push_str(&format!("{}.{}S", seconds, digits.trim_end_matches('0')));
+    } else if seconds > 0 || iso.ends_with('T') {
+        iso.push_str(&format!("{}S", seconds));
+    }
+    iso
+}
+
+/// Quotes the string values of an autoformatted document for `--quote-all-strings` and
+/// `--escape-nonascii`, which has to come after autoformatting as it writes bare words again.
+pub(crate) fn quote_strings(document: &mut KdlDocument, options: &BuildOptions) {
+    for node in document.nodes_mut() {
+        for entry in node.entries_mut() {
+            let Some(val) = entry.value().as_string() else { continue };
+            let escaped = options.escape_nonascii && !val.is_ascii();
+            if !options.quote_all_strings && !escaped {
+                continue;
+            }
+
+            let value_repr = quote(val, options.escape_nonascii);
+            match entry.format_mut() {
+                Some(format) => format.value_repr = value_repr,
+                None => entry.set_format(KdlEntryFormat { value_repr, leading: " ".to_string(), ..Default::default() }),
+            }
+        }
+        if let Some(children) = node.children_mut() {
+            quote_strings(children, options);
+        }
+    }
+}
+
+/// Removes the leading whitespace all the lines which aren't blank have in common, like KDL does
+/// for its own multi-line strings.
+fn dedent(string: &str) -> String {
+    let indent = string
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or_default();
+
+    let lines: Vec<&str> = string.split('\n').map(|line| line.get(indent..).unwrap_or(line.trim_start())).collect();
+    lines.join("\n")
+}
+
+/// Quotes a string the way kdl would, with its non-ASCII characters as `\u{...}` escapes when
+/// `escape_nonascii` is set.
+pub(crate) fn quote(string: &str, escape_nonascii: bool) -> String {
+    let mut repr = String::with_capacity(string.len() + 2);
+    repr.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => repr.push_str("\\\""),
+            '\\' => repr.push_str("\\\\"),
+            '\n' => repr.push_str("\\n"),
+            '\r' => repr.push_str("\\r"),
+            '\t' => repr.push_str("\\t"),
+            c if c.is_ascii() && !c.is_ascii_control() => repr.push(c),
+            c if !escape_nonascii && !c.is_control() => repr.push(c),
+            c => repr.push_str(&format!("\\u{{{:x}}}", c as u32)),
+        }
+    }
+    repr.push('"');
+    repr
+}
+
+/// Whether `record` is a number with its source text, e.g. `{$value: 1.5, $repr: "1.50"}`.
+fn is_number_repr(record: &Record) -> bool {
+    record.len() == 2
+        && record.get(VALUE_KEY).is_some_and(|v| matches!(v, Value::Int { .. } | Value::Float { .. }))
+        && record.get(REPR_KEY).is_some_and(|r| matches!(r, Value::String { .. }))
+}
+
+/// Whether `record` is an integer to write in another base, e.g. `{$radix: hex, $value: 255}` for `0xFF`.
+fn is_radix(record: &Record) -> bool {
+    record.len() == 2
+        && record.get(VALUE_KEY).is_some_and(|v| matches!(v, Value::Int { .. }))
+        && record.get(RADIX_KEY).is_some_and(|r| matches!(r, Value::String { .. }))
+}
+
+/// Whether `record` is an integer too big for nu, e.g. `{$bignum: "99999999999999999999"}`.
+fn is_bignum(record: &Record) -> bool {
+    record.len() == 1 && record.get(BIGNUM_KEY).is_some_and(|d| matches!(d, Value::String { .. }))
+}
+
+/// Whether `record` stands for a single KDL value rather than a node or a property.
+fn is_single_value(record: &Record) -> bool {
+    is_annotated(record) || is_number_repr(record) || is_radix(record) || is_bignum(record)
+}
+
+/// Whether `record` is an explicitly annotated value, e.g. `{$type: u8, $value: 255}` for `(u8)255`.
+fn is_annotated(record: &Record) -> bool {
+    record.len() == 2 && record.contains(TYPE_KEY) && record.contains(VALUE_KEY)
+}
+
+/// Whether the keys of a record are the indices of its values, a tuple rather than fields.
+fn is_tuple(record: &Record) -> bool {
+    !record.is_empty() && record.columns().enumerate().all(|(i, key)| *key == i.to_string())
+}
+
+/// The number of an `arg<n>` key of `--arg-order-from-key-suffix`, e.g. 2 for `arg2`.
+fn arg_index(key: &str) -> Option<u64> {
+    let digits = key.strip_prefix("arg")?;
+    digits.bytes().all(|b| b.is_ascii_digit()).then(|| digits.parse().ok())?
+}
+
+fn unsupported(value: &Value) -> LabeledError {
+    KdlPluginError::UnsupportedValue { ty: value.get_type(), span: value.span() }.into()
+}
+
+/// The nu type name used as KDL type annotation by `--annotate-types`, strings don't need one.
+fn type_annotation(value: &Value) -> Option<&'static str> {
+    match value {
+        Value::Int { .. } => Some("int"),
+        Value::Float { .. } => Some("float"),
+        Value::Bool { .. } => Some("bool"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nu_plugin::EvaluatedCall;
+    use nu_protocol::{record, IntoSpanned, Spanned};
+
+    use crate::{from, KDL};
+
+    fn from_kdl(input: &str) -> Value {
+        KDL.from(None, &EvaluatedCall::new(Span::test_data()), &Value::test_string(input)).unwrap()
+    }
+
+    fn to_kdl(input: &Value) -> String {
+        to_kdl_with(EvaluatedCall::new(Span::test_data()), input)
+    }
+
+    fn to_kdl_with(call: EvaluatedCall, input: &Value) -> String {
+        KDL.to(None, &call, input).unwrap().into_string().unwrap()
+    }
+
+    fn flag(name: &str) -> Spanned<String> {
+        name.to_string().into_spanned(Span::test_data())
+    }
+
+    #[test]
+    fn test_round_trip_args_before_children() {
+        let input = r#"cmd "arg" { child "x" }"#;
+
+        let output = to_kdl(&from_kdl(input));
+
+        assert_eq!(output, "cmd arg {\n    child x\n}\n");
+        assert_eq!(from_kdl(&output), from_kdl(input));
+    }
+
+    #[test]
+    fn test_children_key_first_still_emits_args_first() {
+        let mut node = Record::new();
+        node.insert("children", Value::test_record(Record::from_iter([("child".to_string(), Value::test_string("x"))])));
+        node.insert("entries", Value::test_list(vec![Value::test_string("a"), Value::test_int(1)]));
+        let mut document = Record::new();
+        document.insert("cmd", Value::test_record(node));
+
+        let output = to_kdl(&Value::test_record(document));
+
+        assert_eq!(output, "cmd a 1 {\n    child x\n}\n");
+    }
+
+    #[test]
+    fn test_annotate_types_round_trip() {
+        let mut record = Record::new();
+        record.insert("flag", Value::test_bool(true));
+        record.insert("ratio", Value::test_float(2.0));
+        record.insert("count", Value::test_int(3));
+        record.insert("name", Value::test_string("true"));
+        let input = Value::test_record(record);
+
+        let output = to_kdl_with(EvaluatedCall::new(Span::test_data()).with_flag(flag("annotate-types")), &input);
+
+        assert_eq!(output, "flag (bool)#true\nratio (float)2.0\ncount (int)3\nname \"true\"\n");
+        assert_eq!(from_kdl(&output), input);
+    }
+
+    #[test]
+    fn test_annotations_override_string_ambiguous_values() {
+        let output = from_kdl(r#"node (float)2 (bool)"true" (int)"5""#);
+
+        assert_eq!(
+            output.get_data_by_key("node").unwrap(),
+            Value::test_list(vec![Value::test_float(2.0), Value::test_bool(true), Value::test_int(5)])
+        );
+    }
+
+    #[test]
+    fn test_record_as_children() {
+        let input = from_kdl(r#"server { host "x"; port 8080 }"#);
+
+        let output = to_kdl(&input);
+
+        assert_eq!(output, "server {\n    host x\n    port 8080\n}\n");
+        assert_eq!(from_kdl(&output), input);
+    }
+
+    #[test]
+    fn test_nested_records_as_children() {
+        let input = from_kdl(r#"server { http { host "x"; port 8080 }; name "main" }"#);
+
+        let output = to_kdl(&input);
+
+        assert_eq!(output, "server {\n    http {\n        host x\n        port 8080\n    }\n    name main\n}\n");
+        assert_eq!(from_kdl(&output), input);
+    }
+
+    #[test]
+    fn test_scalar_with_node_name() {
+        let call = EvaluatedCall::new(Span::test_data()).with_named(flag("node-name"), Value::test_string("greeting"));
+
+        assert_eq!(to_kdl_with(call, &Value::test_string("hello")), "greeting hello\n");
+    }
+
+    #[test]
+    fn test_list_with_node_name() {
+        let call = EvaluatedCall::new(Span::test_data()).with_named(flag("node-name"), Value::test_string("bookmarks"));
+        let input = Value::test_list(vec![Value::test_int(12), Value::test_int(15)]);
+
+        assert_eq!(to_kdl_with(call, &input), "bookmarks 12 15\n");
+    }
+
+    #[test]
+    fn test_scalar_with_default_node_name() {
+        assert_eq!(to_kdl(&Value::test_int(1)), "node 1\n");
+    }
+
+    #[test]
+    fn test_multi_key_entry_error_has_span() {
+        let mut entry = Record::new();
+        entry.insert("a", Value::test_int(1));
+        entry.insert("b", Value::test_int(2));
+        let mut document = Record::new();
+        document.insert("node", Value::test_list(vec![Value::test_int(1), Value::record(entry, Span::new(5, 17))]));
+
+        let error = KDL.to(None, &EvaluatedCall::new(Span::test_data()), &Value::test_record(document)).unwrap_err();
+
+        assert_eq!(error.labels.len(), 1);
+        assert_eq!(error.labels[0].span, Span::new(5, 17));
+    }
+
+    #[test]
+    fn test_unsupported_value_error_has_span() {
+        let mut document = Record::new();
+        document.insert("node", Value::filesize(1024, Span::new(3, 8)));
+
+        let error = KDL.to(None, &EvaluatedCall::new(Span::test_data()), &Value::test_record(document)).unwrap_err();
+
+        assert_eq!(error.labels[0].span, Span::new(3, 8));
+    }
+
+    #[test]
+    fn test_round_trip_single_property() {
+        let keep_props = EvaluatedCall::new(Span::test_data()).with_flag(flag("keep-props"));
+        let input = "x foo=1\ny {\n    foo 1\n}\n";
+
+        let value = KDL.from(None, &keep_props, &Value::test_string(input)).unwrap();
+
+        assert_ne!(value.get_data_by_key("x"), value.get_data_by_key("y"));
+        assert_eq!(to_kdl(&value), input);
+    }
+
+    #[test]
+    fn test_round_trip_args_and_props() {
+        let input = from_kdl(r#"node "a" "b" key=1"#);
+
+        let output = to_kdl(&input);
+
+        assert_eq!(output, "node a b key=1\n");
+        assert_eq!(from_kdl(&output), input);
+    }
+
+    #[test]
+    fn test_interleaved_args_and_props_keep_their_order() {
+        let output = to_kdl(&from_kdl(r#"node key=1 "a" other=#true "b""#));
+
+        assert_eq!(output, "node key=1 a other=#true b\n");
+    }
+
+    #[test]
+    fn test_comment_key_round_trip() {
+        let mut server = Record::new();
+        server.insert("$comment", Value::test_string("the main server"));
+        server.insert("host", Value::test_string("x"));
+        let mut document = Record::new();
+        document.insert("server", Value::test_record(server));
+
+        let output = to_kdl(&Value::test_record(document));
+
+        assert_eq!(output, "// the main server\nserver {\n    host x\n}\n");
+        let parsed: KdlDocument = output.parse().unwrap();
+        assert!(parsed.nodes()[0].format().unwrap().leading.contains("// the main server"));
+        assert_eq!(from_kdl(&output), from_kdl("server { host x }"));
+    }
+
+    #[test]
+    fn test_comment_child_round_trip() {
+        for input in ["post {\n    comment hi\n    title x\n}\n", "n {\n    comment y\n}\n"] {
+            assert_eq!(to_kdl(&from_kdl(input)), input);
+        }
+    }
+
+    #[test]
+    fn test_multi_line_comment_on_child() {
+        let mut pane = Record::new();
+        pane.insert("$comment", Value::test_string("first line\nsecond line"));
+        pane.insert("size", Value::test_int(1));
+        let mut tab = Record::new();
+        tab.insert("pane", Value::test_record(pane));
+        let mut document = Record::new();
+        document.insert("tab", Value::test_record(tab));
+
+        let output = to_kdl(&Value::test_record(document));
+
+        assert_eq!(output, "tab {\n    // first line\n    // second line\n    pane {\n        size 1\n    }\n}\n");
+    }
+
+    #[test]
+    fn test_nothing_is_empty_document() {
+        assert_eq!(to_kdl(&Value::test_nothing()), "");
+        assert_eq!(to_kdl(&Value::test_record(Record::new())), "");
+    }
+
+    #[test]
+    fn test_unsupported_top_level_value_errors() {
+        let error = KDL.to(None, &EvaluatedCall::new(Span::test_data()), &Value::test_filesize(1024)).unwrap_err();
+
+        assert_eq!(error.msg, "value not supported, expected string, int, float, bool, duration or null");
+    }
+
+    fn annotated(ty: &str, value: Value) -> Value {
+        let mut record = Record::new();
+        record.insert("$type", Value::test_string(ty));
+        record.insert("$value", value);
+        Value::test_record(record)
+    }
+
+    #[test]
+    fn test_annotated_argument() {
+        let mut document = Record::new();
+        document.insert("port", annotated("u16", Value::test_int(8080)));
+
+        let output = to_kdl(&Value::test_record(document));
+
+        assert_eq!(output, "port (u16)8080\n");
+        assert_eq!(from_kdl(&output).get_data_by_key("port").unwrap(), Value::test_int(8080));
+    }
+
+    #[test]
+    fn test_annotated_property() {
+        let mut document = Record::new();
+        document.insert(
+            "node",
+            Value::test_list(vec![
+                Value::test_string("a"),
+                Value::test_record(Record::from_iter([("size".to_string(), annotated("u8", Value::test_int(255)))])),
+            ]),
+        );
+
+        assert_eq!(to_kdl(&Value::test_record(document)), "node a size=(u8)255\n");
+    }
+
+    #[test]
+    fn test_annotation_should_be_a_string() {
+        let mut document = Record::new();
+        let mut bad = Record::new();
+        bad.insert("$type", Value::test_int(1));
+        bad.insert("$value", Value::test_int(8080));
+        document.insert("port", Value::test_record(bad));
+
+        let error = KDL.to(None, &EvaluatedCall::new(Span::test_data()), &Value::test_record(document)).unwrap_err();
+
+        assert_eq!(error.msg, "type annotation should be a string");
+    }
+
+    #[test]
+    fn test_flattened_round_trip() {
+        let flatten = || EvaluatedCall::new(Span::test_data()).with_flag(flag("flatten"));
+        let flattened = KDL.from(None, &flatten(), &Value::test_string(include_str!("../sample.kdl"))).unwrap();
+
+        let output = to_kdl(&flattened);
+        let reparsed = KDL.from(None, &flatten(), &Value::test_string(output.clone())).unwrap();
+
+        // spans differ between the two documents, so compare what they serialize to
+        assert_eq!(to_kdl(&reparsed), output);
+        assert!(output.contains("server main host=\"127.0.0.1\" port=8080 {"));
+        assert!(output.ends_with("\nempty\n"));
+    }
+
+    #[test]
+    fn test_canonical_shape_round_trip() {
+        let annotated = |ty: &str, value: Value| Value::test_record(record! { "$type" => Value::test_string(ty), "$value" => value });
+        let document = Value::test_record(record! {
+            "server" => Value::test_record(record! {
+                "$type" => Value::test_string("service"),
+                "$args" => Value::test_list(vec![Value::test_string("main"), annotated("u16", Value::test_int(8080))]),
+                "$props" => Value::test_record(record! { "tls" => Value::test_bool(true), "weight" => annotated("f32", Value::test_float(0.5)) }),
+                "$children" => Value::test_record(record! {
+                    "route" => Value::test_record(record! {
+                        "$args" => Value::test_list(vec![Value::test_string("/a")]),
+                        "$props" => Value::test_record(record! { "method" => Value::test_string("GET") }),
+                    }),
+                    "limits" => Value::test_record(record! {
+                        "$type" => Value::test_string("quota"),
+                        "$children" => Value::test_record(record! { "max" => Value::test_record(record! { "$args" => Value::test_list(vec![Value::test_int(10)]) }) }),
+                    }),
+                    // the keys of the shape are the only ones it has, this is a node with children
+                    "args" => Value::test_record(record! { "$args" => Value::test_int(1), "other" => Value::test_int(2) }),
+                }),
+            }),
+            "annotated" => annotated("u8", Value::test_int(1)),
+        });
+
+        let output = to_kdl(&document);
+
+        let expected = "(service)server main (u16)8080 tls=#true weight=(f32)0.5 {\n    route \"/a\" method=GET\n    (quota)limits {\n        max 10\n    }\n    args {\n        $args 1\n        other 2\n    }\n}\nannotated (u8)1\n";
+        assert_eq!(output, expected);
+        // read back without losing anything, the shape of --flatten is written as the same document
+        let flatten = EvaluatedCall::new(Span::test_data()).with_flag(flag("flatten"));
+        let flattened = KDL.from(None, &flatten, &Value::test_string(output)).unwrap();
+        assert_eq!(to_kdl(&flattened), expected);
+    }
+
+    #[test]
+    fn test_flattened_shape() {
+        let mut props = Record::new();
+        props.insert("port", Value::test_int(8080));
+        let mut node = Record::new();
+        node.insert("props", Value::test_record(props));
+        node.insert("args", Value::test_list(vec![Value::test_string("main")]));
+        node.insert("children", Value::test_record(Record::from_iter([("tls".to_string(), Value::test_bool(false))])));
+        let mut document = Record::new();
+        document.insert("server", Value::test_record(node));
+
+        let output = to_kdl(&Value::test_record(document));
+
+        assert_eq!(output, "server main port=8080 {\n    tls #false\n}\n");
+    }
+
+    #[test]
+    fn test_quoted_property_key_round_trip() {
+        let parsed = from_kdl(r#"node "weird key!"=1 plain=2"#);
+        let entries = parsed.get_data_by_key("node").unwrap();
+        assert!(entries.as_list().unwrap()[0].get_data_by_key("weird key!").is_some());
+
+        let output = to_kdl(&parsed);
+        assert_eq!(output, "node \"weird key!\"=1 plain=2\n");
+        assert_eq!(from_kdl(&output), parsed);
+    }
+
+    #[test]
+    fn test_ordered_entries_round_trip() {
+        let ordered = EvaluatedCall::new(Span::test_data()).with_flag(flag("ordered-entries"));
+        let input = "node 1 a=2 3 b=4 {\n    child x=1 y\n}\nbare\n";
+
+        let parsed = KDL.from(None, &ordered, &Value::test_string(input)).unwrap();
+        let entries = parsed.get_data_by_key("node").unwrap().get_data_by_key("entries").unwrap();
+        let names: Vec<_> = entries.into_list().unwrap().iter().map(|e| e.get_data_by_key("name").unwrap()).collect();
+        assert_eq!(names, [Value::test_nothing(), Value::test_string("a"), Value::test_nothing(), Value::test_string("b")]);
+
+        assert_eq!(to_kdl(&parsed), input);
+    }
+
+    #[test]
+    fn test_type_map() {
+        let type_map = Value::test_record(record! { "int" => Value::test_string("i32"), "float" => Value::test_string("f64") });
+        let call = EvaluatedCall::new(Span::test_data())
+            .with_flag(flag("annotate-types"))
+            .with_named(flag("type-map"), type_map);
+        let document = Value::test_record(record! {
+            "count" => Value::test_int(3),
+            "ratio" => Value::test_float(0.5),
+            "flag" => Value::test_bool(true),
+        });
+
+        assert_eq!(to_kdl_with(call, &document), "count (i32)3\nratio (f64)0.5\nflag (bool)#true\n");
+
+        let type_map = Value::test_record(record! { "int" => Value::test_int(32) });
+        let call = EvaluatedCall::new(Span::test_data()).with_named(flag("type-map"), type_map);
+        assert_eq!(KDL.to(None, &call, &document).unwrap_err().msg, "type annotation should be a string");
+    }
+
+    #[test]
+    fn test_table_as() {
+        let document = Value::test_record(record! {
+            "server" => Value::test_record(record! {
+                "name" => Value::test_string("x"),
+                "routes" => Value::test_list(vec![
+                    Value::test_record(record! { "path" => Value::test_string("/a") }),
+                    Value::test_record(record! { "path" => Value::test_string("/b"), "auth" => Value::test_bool(true) }),
+                ]),
+            }),
+        });
+        let table_as = |mode: &str| EvaluatedCall::new(Span::test_data()).with_named(flag("table-as"), Value::test_string(mode));
+
+        let output = to_kdl_with(table_as("nodes"), &document);
+
+        assert_eq!(output, "server {\n    name x\n    routes {\n        path \"/a\"\n    }\n    routes {\n        path \"/b\"\n        auth #true\n    }\n}\n");
+        let server = KdlDocument::parse(&output).unwrap().get("server").unwrap().children().unwrap().clone();
+        let rows: Vec<Value> = server.nodes().iter().filter(|node| node.name().value() == "routes").map(|node| from::parse_node(node, &Default::default())).collect();
+        assert_eq!(rows, document.get_data_by_key("server").unwrap().get_data_by_key("routes").unwrap().into_list().unwrap());
+
+        let singular = to_kdl_with(table_as("singular-nodes"), &document);
+        assert_eq!(singular.matches("route {").count(), 2);
+        assert_eq!(super::singular("entries"), "entry");
+        assert_eq!(super::singular("address"), "address");
+    }
+
+    #[test]
+    fn test_child_node_name() {
+        let routes = Value::test_list(vec![
+            Value::test_record(record! { "path" => Value::test_string("/a") }),
+            Value::test_record(record! { "path" => Value::test_string("/b") }),
+        ]);
+        let document = Value::test_record(record! { "routes" => routes.clone(), "others" => routes });
+        let call = || {
+            let names = Value::test_record(record! { "routes" => Value::test_string("route") });
+            EvaluatedCall::new(Span::test_data()).with_named(flag("child-node-name"), names)
+        };
+
+        let output = to_kdl_with(call(), &document);
+        assert_eq!(output, "routes {\n    route {\n        path \"/a\"\n    }\n    route {\n        path \"/b\"\n    }\n}\nothers path=\"/a\" path=\"/b\"\n");
+
+        // the fields it doesn't map are written as --table-as says
+        let output = to_kdl_with(call().with_named(flag("table-as"), Value::test_string("nodes")), &document);
+        assert!(output.starts_with("routes {\n    route {"));
+        assert_eq!(output.matches("others {").count(), 2);
+    }
+
+    #[test]
+    fn test_number_like_keys() {
+        let v1 = || EvaluatedCall::new(Span::test_data()).with_flag(flag("v1"));
+
+        for key in ["123", "1.5", "-0", "true", "null", "inf", "-inf", "nan", "+1", ".5", "1e3", "0x1F"] {
+            // as a node name and a property name, which kdl quotes as they'd be read as values
+            let document = Value::test_record(record! {
+                key => Value::test_record(record! { key => Value::test_int(1) }),
+                "node" => Value::test_list(vec![Value::test_int(1), Value::test_record(record! { key => Value::test_int(2) })]),
+            });
+
+            let output = to_kdl(&document);
+            assert_eq!(output, format!("\"{0}\" {{\n    \"{0}\" 1\n}}\nnode 1 \"{0}\"=2\n", key));
+            assert_eq!(from_kdl(&output), document);
+
+            let output = to_kdl_with(v1(), &document);
+            assert_eq!(output, format!("\"{0}\" {{\n    \"{0}\" 1\n}}\nnode 1 \"{0}\"=2\n", key));
+            assert_eq!(KDL.from(None, &v1(), &Value::test_string(output)).unwrap(), document);
+        }
+    }
+
+    #[test]
+    fn test_strict_identifiers() {
+        let document = Value::test_record(record! {
+            "ok" => Value::test_record(record! { "a\u{1}" => Value::test_int(1), "my key" => Value::test_int(2) }),
+            "b\u{feff}" => Value::test_list(vec![Value::test_record(record! { "a\u{1}" => Value::test_int(3) })]),
+        });
+        let call = EvaluatedCall::new(Span::test_data()).with_flag(flag("strict-identifiers"));
+
+        let error = KDL.to(None, &call, &document).unwrap_err();
+
+        assert_eq!(error.msg, "invalid identifiers: `a\\u{1}`, `b\\u{feff}`");
+        assert!(KDL.to(None, &EvaluatedCall::new(Span::test_data()), &document).is_ok());
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let nested = (0..1000).fold(Value::test_int(1), |value, _| Value::test_record(record! { "a" => value }));
+
+        let error = KDL.to(None, &EvaluatedCall::new(Span::test_data()), &nested).unwrap_err();
+        assert_eq!(error.msg, "more than 128 levels of records and lists");
+
+        let max_depth = |depth: i64| EvaluatedCall::new(Span::test_data()).with_named(flag("max-depth"), Value::test_int(depth));
+        let shallow = Value::test_record(record! { "a" => Value::test_list(vec![Value::test_int(1)]) });
+        assert!(KDL.to(None, &max_depth(2), &shallow).is_ok());
+        assert!(KDL.to(None, &max_depth(1), &shallow).is_err());
+    }
+
+    #[test]
+    fn test_values_as_args() {
+        let document = Value::test_record(record! {
+            "greeting" => Value::test_record(record! { "0" => Value::test_string("hello"), "1" => Value::test_string("world") }),
+            "point" => Value::test_record(record! { "1" => Value::test_int(1), "0" => Value::test_int(2) }),
+        });
+        let call = EvaluatedCall::new(Span::test_data()).with_flag(flag("values-as-args"));
+
+        assert_eq!(to_kdl_with(call, &document), "greeting hello world\npoint {\n    \"1\" 1\n    \"0\" 2\n}\n");
+        assert_eq!(to_kdl(&document), "greeting {\n    \"0\" hello\n    \"1\" world\n}\npoint {\n    \"1\" 1\n    \"0\" 2\n}\n");
+    }
+
+    #[test]
+    fn test_arg_order_from_key_suffix() {
+        let document = Value::test_record(record! {
+            "cmd" => Value::test_record(record! { "arg0" => Value::test_string("run"), "arg1" => Value::test_string("--fast"), "arg2" => Value::test_string("input.txt") }),
+            "sorted" => Value::test_record(record! { "arg10" => Value::test_int(10), "arg2" => Value::test_int(2), "arg0" => Value::test_int(0) }),
+            "mixed" => Value::test_record(record! { "arg0" => Value::test_int(0), "name" => Value::test_string("x") }),
+        });
+        let call = EvaluatedCall::new(Span::test_data()).with_flag(flag("arg-order-from-key-suffix"));
+
+        assert_eq!(to_kdl_with(call, &document), "cmd run --fast input.txt\nsorted 0 2 10\nmixed {\n    arg0 0\n    name x\n}\n");
+        assert!(to_kdl(&document).starts_with("cmd {\n    arg0 run\n"));
+    }
+
+    #[test]
+    fn test_epoch_fields() {
+        let document = Value::test_record(record! {
+            "post" => Value::test_record(record! { "created" => Value::test_int(1_700_000_000), "views" => Value::test_int(1_700_000_000) }),
+        });
+        let fields = Value::test_list(vec![Value::test_string("created")]);
+        let call = EvaluatedCall::new(Span::test_data()).with_named(flag("epoch-fields"), fields);
+
+        let output = to_kdl_with(call.clone(), &document);
+        assert_eq!(output, "post {\n    created (date)\"2023-11-14T22:13:20+00:00\"\n    views 1700000000\n}\n");
+
+        let props = call.clone().with_named(flag("record-as"), Value::test_string("props"));
+        assert_eq!(to_kdl_with(props, &document), "post created=(date)\"2023-11-14T22:13:20+00:00\" views=1700000000\n");
+
+        // the field of a prefixed property is its name without the prefix
+        let prefixed = Value::test_record(record! { "post" => Value::test_record(record! { "@created" => Value::test_int(1_700_000_000), "title" => Value::test_string("x") }) });
+        let prefix = call.with_named(flag("property-prefix"), Value::test_string("@"));
+        assert_eq!(to_kdl_with(prefix, &prefixed), "post created=(date)\"2023-11-14T22:13:20+00:00\" {\n    title x\n}\n");
+    }
+
+    #[test]
+    fn test_bool_as_int() {
+        let document = Value::test_record(record! { "on" => Value::test_bool(true), "off" => Value::test_bool(false) });
+        let call = EvaluatedCall::new(Span::test_data()).with_flag(flag("bool-as-int"));
+
+        let output = to_kdl_with(call, &document);
+
+        assert_eq!(output, "on 1\noff 0\n");
+        assert_eq!(to_kdl(&document), "on #true\noff #false\n");
+        assert_eq!(from_kdl(&output), Value::test_record(record! { "on" => Value::test_int(1), "off" => Value::test_int(0) }));
+    }
+
+    #[test]
+    fn test_bignum_round_trip() {
+        let call = EvaluatedCall::new(Span::test_data()).with_flag(flag("preserve-number-repr"));
+        let input = "big 1234567890123456789012345 -1_000_000_000_000_000_000_000\n";
+
+        let value = KDL.from(None, &call, &Value::test_string(input)).unwrap();
+
+        let bignum = |digits: &str| Value::test_record(record! { "$bignum" => Value::test_string(digits) });
+        assert_eq!(
+            value.get_data_by_key("big").unwrap(),
+            Value::test_list(vec![bignum("1234567890123456789012345"), bignum("-1_000_000_000_000_000_000_000")])
+        );
+        assert_eq!(to_kdl(&value), input);
+
+        let invalid = Value::test_record(record! { "big" => bignum("12a") });
+        assert_eq!(KDL.to(None, &EvaluatedCall::new(Span::test_data()), &invalid).unwrap_err().msg, "invalid $bignum `12a`");
+    }
+
+    #[test]
+    fn test_root_nodes_from_list() {
+        let document = Value::test_list(vec![
+            Value::test_record(record! { "pane" => Value::test_string("a") }),
+            Value::test_record(record! { "pane" => Value::test_string("b") }),
+            Value::test_record(record! { "tab" => Value::test_string("x") }),
+        ]);
+        let call = EvaluatedCall::new(Span::test_data()).with_flag(flag("root-nodes-from-list"));
+
+        assert_eq!(to_kdl_with(call, &document), "pane a\npane b\ntab x\n");
+        assert_eq!(to_kdl(&document), "node pane=a pane=b tab=x\n");
+    }
+
+    #[test]
+    fn test_order() {
+        let document = Value::test_record(record! {
+            "a" => Value::test_int(1),
+            "b" => Value::test_int(2),
+            "c" => Value::test_int(3),
+            "$order" => Value::test_list(vec![Value::test_string("c"), Value::test_string("b"), Value::test_string("a")]),
+        });
+        assert_eq!(to_kdl(&document), "c 3\nb 2\na 1\n");
+
+        // the keys it doesn't list come after, and the ones the record doesn't have are ignored
+        let document = Value::test_record(record! {
+            "a" => Value::test_int(1),
+            "b" => Value::test_int(2),
+            "$order" => Value::test_list(vec![Value::test_string("missing"), Value::test_string("b")]),
+        });
+        assert_eq!(to_kdl(&document), "b 2\na 1\n");
+
+        let document = Value::test_record(record! { "a" => Value::test_int(1), "$order" => Value::test_string("a") });
+        assert!(build_document(&document, &BuildOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_property_prefix_round_trip() {
+        let prefix = |extra: Option<&str>| {
+            let call = EvaluatedCall::new(Span::test_data()).with_named(flag("property-prefix"), Value::test_string("@"));
+            match extra {
+                Some(name) => call.with_flag(flag(name)),
+                None => call,
+            }
+        };
+        let input = "node arg port=8080\nlone port=8080\nchild {\n    port 8080\n}\nserver host=x {\n    tls #true\n}\n";
+
+        for extra in [None, Some("merge-props-into-children")] {
+            let value = KDL.from(None, &prefix(extra), &Value::test_string(input)).unwrap();
+            assert_eq!(to_kdl_with(prefix(None), &value), input);
+        }
+    }
+
+    #[test]
+    fn test_allow_duplicate_keys() {
+        let pair = |key: &str, value: Value| Value::test_list(vec![Value::test_string(key), value]);
+        let document = Value::test_list(vec![
+            pair("pane", Value::test_string("a")),
+            pair("pane", Value::test_string("b")),
+            pair("tab", Value::test_record(record! { "pane" => Value::test_int(1) })),
+        ]);
+        let call = || EvaluatedCall::new(Span::test_data()).with_flag(flag("allow-duplicate-keys"));
+
+        assert_eq!(to_kdl_with(call(), &document), "pane a\npane b\ntab {\n    pane 1\n}\n");
+    }
+
+    #[test]
+    fn test_invalid_key_value_pair() {
+        let call = EvaluatedCall::new(Span::test_data()).with_flag(flag("allow-duplicate-keys"));
+
+        for pair in [
+            Value::test_list(vec![Value::test_string("pane")]),
+            Value::test_list(vec![Value::test_int(1), Value::test_string("a")]),
+            Value::test_string("pane"),
+        ] {
+            let document = Value::test_list(vec![pair]);
+            assert_eq!(KDL.to(None, &call, &document).unwrap_err().msg, "invalid [key, value] pair");
+        }
+    }
+
+    #[test]
+    fn test_node_type_round_trip() {
+        let keep_annotations = EvaluatedCall::new(Span::test_data()).with_flag(flag("keep-annotations"));
+        let input = "(container)div {\n    (text)span hi\n    p there\n}\n";
+
+        let value = KDL.from(None, &keep_annotations, &Value::test_string(input)).unwrap();
+
+        let div = value.get_data_by_key("div").unwrap();
+        assert_eq!(div.get_data_by_key("$node_type").unwrap(), Value::test_string("container"));
+        assert_eq!(
+            div.get_data_by_key("span").unwrap(),
+            Value::test_record(record! { "$node_type" => Value::test_string("text"), "$value" => Value::test_string("hi") })
+        );
+        assert_eq!(to_kdl(&value), input);
+    }
+
+    #[test]
+    fn test_record_as() {
+        let document = Value::test_record(record! {
+            "server" => Value::test_record(record! {
+                "host" => Value::test_string("x"),
+                "port" => Value::test_int(8080),
+                "tls" => Value::test_record(record! { "enabled" => Value::test_bool(true) }),
+            }),
+        });
+        let record_as = |mode: &str| EvaluatedCall::new(Span::test_data()).with_named(flag("record-as"), Value::test_string(mode));
+
+        let children = "server {\n    host x\n    port 8080\n    tls {\n        enabled #true\n    }\n}\n";
+        assert_eq!(to_kdl(&document), children);
+        assert_eq!(to_kdl_with(record_as("children"), &document), children);
+        assert_eq!(to_kdl_with(record_as("props"), &document), "server host=x port=8080 {\n    tls enabled=#true\n}\n");
+        assert_eq!(KDL.to(None, &record_as("args"), &document).unwrap_err().msg, "invalid --record-as `args`");
+    }
+
+    #[test]
+    fn test_dedent() {
+        let script = "\n    echo start\n      indented\n\n    echo end\n";
+        let document = Value::test_record(record! { "script" => Value::test_string(script) });
+
+        let output = to_kdl_with(EvaluatedCall::new(Span::test_data()).with_flag(flag("dedent")), &document);
+
+        let parsed = from_kdl(&output).get_data_by_key("script").unwrap();
+        assert_eq!(parsed, Value::test_string("\necho start\n  indented\n\necho end\n"));
+        assert_eq!(from_kdl(&to_kdl(&document)).get_data_by_key("script").unwrap(), Value::test_string(script));
+    }
+
+    #[test]
+    fn test_identifier_policy() {
+        let document = Value::test_record(record! {
+            "my key" => Value::test_list(vec![Value::test_record(record! { "my prop" => Value::test_int(1) })]),
+        });
+        let with_policy = |policy: &str| {
+            let call = EvaluatedCall::new(Span::test_data()).with_named(flag("identifier-policy"), Value::test_string(policy));
+            KDL.to(None, &call, &document).map(|output| output.into_string().unwrap())
+        };
+
+        assert_eq!(to_kdl(&document), "\"my key\" \"my prop\"=1\n");
+        assert_eq!(with_policy("quote").unwrap(), "\"my key\" \"my prop\"=1\n");
+        assert_eq!(with_policy("replace").unwrap(), "my_key my_prop=1\n");
+        assert_eq!(with_policy("error").unwrap_err().msg, "`my key` has spaces");
+        assert_eq!(with_policy("drop").unwrap_err().msg, "invalid --identifier-policy `drop`");
+    }
+
+    #[test]
+    fn test_integer_extremes() {
+        for (int, kdl) in [(-1, "node -1\n"), (0, "node 0\n"), (i64::MIN, "node -9223372036854775808\n"), (i64::MAX, "node 9223372036854775807\n")] {
+            let output = to_kdl(&Value::test_int(int));
+
+            assert_eq!(output, kdl);
+            assert_eq!(from_kdl(&output).get_data_by_key("node").unwrap().as_int().unwrap(), int);
+        }
+    }
+
+    #[test]
+    fn test_integer_extremes_everywhere() {
+        let annotate = || EvaluatedCall::new(Span::test_data()).with_flag(flag("annotate-types"));
+        let radix = |radix: &str, int: i64| {
+            Value::test_record(record! { "$radix" => Value::test_string(radix), "$value" => Value::test_int(int) })
+        };
+
+        for int in [i64::MIN, i64::MAX] {
+            let cases = [
+                (to_kdl(&Value::test_record(record! { "node" => Value::test_record(record! { "p" => Value::test_int(int) }) })), format!("node {{\n    p {}\n}}\n", int)),
+                (to_kdl_with(annotate(), &Value::test_record(record! { "node" => Value::test_int(int) })), format!("node (int){}\n", int)),
+                (to_kdl(&Value::test_record(record! { "node" => radix("hex", int) })), format!("node {}0x{:X}\n", if int < 0 { "-" } else { "" }, int.unsigned_abs())),
+                (to_kdl(&Value::test_record(record! { "node" => radix("bin", int) })), format!("node {}0b{:b}\n", if int < 0 { "-" } else { "" }, int.unsigned_abs())),
+            ];
+
+            for (output, kdl) in cases {
+                assert_eq!(output, kdl);
+                // the annotation, if any, and the radix are dropped when reading it back
+                let node = from_kdl(&output).get_data_by_key("node").unwrap();
+                let value = node.get_data_by_key("p").unwrap_or(node);
+                assert_eq!(value.as_int().unwrap(), int, "{}", output);
+            }
+        }
+    }
+
+    #[test]
+    fn test_integral_floats_stay_floats() {
+        // kdl writes a float with a `.0` or an exponent, so it can't be read back as an int
+        for (float, kdl) in [(2.0, "x 2.0\n"), (-0.0, "x -0.0\n"), (1e20, "x 1e20\n")] {
+            let output = to_kdl(&Value::test_record(record! { "x" => Value::test_float(float) }));
+
+            assert_eq!(output, kdl);
+            let parsed = from_kdl(&output).get_data_by_key("x").unwrap();
+            assert!(matches!(parsed, Value::Float { val, .. } if val == float));
+        }
+    }
+
+    #[test]
+    fn test_shortest_float_repr() {
+        let write = |float: f64| to_kdl(&Value::test_record(record! { "x" => Value::test_float(float) }));
+        let read = |kdl: &str| from_kdl(kdl).get_data_by_key("x").unwrap().as_float().unwrap();
+
+        let third = 1.0 / 3.0;
+        for (float, kdl) in [(0.1, "x 0.1\n"), (0.3, "x 0.3\n"), (0.1 + 0.2, "x 0.30000000000000004\n"), (third, "x 0.3333333333333333\n")] {
+            assert_eq!(write(float), kdl);
+            assert_eq!(read(kdl).to_bits(), float.to_bits(), "{}", kdl);
+        }
+        for float in [f64::MAX, f64::MIN_POSITIVE, 5e-324, 1e-7] {
+            let kdl = write(float);
+            assert_eq!(read(&kdl).to_bits(), float.to_bits(), "{}", kdl);
+        }
+    }
+
+    #[test]
+    fn test_duration_format() {
+        let document = Value::test_record(record! { "timeout" => Value::test_duration(5 * 60 * 1_000_000_000) });
+        let format = |format: &str| EvaluatedCall::new(Span::test_data()).with_named(flag("duration-format"), Value::test_string(format));
+
+        assert_eq!(to_kdl(&document), "timeout 300000000000\n");
+        assert_eq!(to_kdl_with(format("nanos"), &document), "timeout 300000000000\n");
+        assert_eq!(to_kdl_with(format("iso"), &document), "timeout (duration)PT5M\n");
+        assert_eq!(to_kdl_with(format("human"), &document), "timeout (duration)\"5min\"\n");
+        assert_eq!(KDL.to(None, &format("secs"), &document).unwrap_err().msg, "invalid --duration-format `secs`");
+
+        let day = 86_400 * 1_000_000_000;
+        for (nanos, iso) in [(0, "PT0S"), (day, "P1D"), (day + 500_000_000, "P1DT0.5S"), (-3_661_000_000_000, "-PT1H1M1S")] {
+            assert_eq!(iso_duration(nanos), iso);
+        }
+    }
+
+    #[test]
+    fn test_escape_nonascii() {
+        let mut document = Record::new();
+        document.insert("dessert", Value::test_string("crème brûlée 🍮"));
+        document.insert("plain", Value::test_string("a \"quoted\" word"));
+        let document = Value::test_record(document);
+
+        let output = to_kdl_with(EvaluatedCall::new(Span::test_data()).with_flag(flag("escape-nonascii")), &document);
+
+        assert!(output.is_ascii(), "{} is not ASCII only", output);
+        assert_eq!(output, "dessert \"cr\\u{e8}me br\\u{fb}l\\u{e9}e \\u{1f36e}\"\nplain \"a \\\"quoted\\\" word\"\n");
+        assert_eq!(from_kdl(&output).get_data_by_key("dessert").unwrap(), Value::test_string("crème brûlée 🍮"));
+
+        let output = to_kdl_with(EvaluatedCall::new(Span::test_data()).with_flag(flag("escape-nonascii")), &Value::test_string("Zoë"));
+        assert_eq!(output, "node \"Zo\\u{eb}\"\n");
+    }
+
+    #[test]
+    fn test_repeated_names_with_suffix() {
+        let mut document = Record::new();
+        document.insert("pane", Value::test_string("a"));
+        document.insert("pane#1", Value::test_string("b"));
+        document.insert("tab#x", Value::test_string("c"));
+
+        let output = to_kdl(&Value::test_record(document));
+
+        assert_eq!(output, "pane a\npane b\n\"tab#x\" c\n");
+    }
+
+    #[test]
+    fn test_emit_version_comment() {
+        let document = Value::test_record(record! { "enabled" => Value::test_bool(true) });
+        let comment = || EvaluatedCall::new(Span::test_data()).with_flag(flag("emit-version-comment"));
+
+        let v2 = to_kdl_with(comment(), &document);
+        assert_eq!(v2, "// kdl-version: 2\nenabled #true\n");
+        assert_eq!(from_kdl(&v2), document);
+
+        let v1 = to_kdl_with(comment().with_flag(flag("v1")), &document);
+        assert_eq!(v1, "// kdl-version: 1\nenabled true\n");
+        let v1_call = EvaluatedCall::new(Span::test_data()).with_flag(flag("v1"));
+        assert_eq!(KDL.from(None, &v1_call, &Value::test_string(v1)).unwrap(), document);
+    }
+
+    #[test]
+    fn test_properties_called_like_structural_keys() {
+        for input in [
+            "widget entries=5 {\n    child 1\n}\n",
+            "widget children=5 {\n    child 1\n}\n",
+            "widget entries=5 children=6 {\n    entries 1\n    children 2\n}\n",
+            "widget 1 entries=5 children=6\n",
+        ] {
+            let value = from_kdl(input);
+            assert_eq!(to_kdl(&value), input);
+        }
+
+        // the properties are nested under `entries`, so they can't be taken for the children
+        let widget = from_kdl("widget entries=5 {\n    child 1\n}\n").get_data_by_key("widget").unwrap();
+        assert_eq!(widget.get_data_by_key("entries").unwrap(), Value::test_record(record! { "entries" => Value::test_int(5) }));
+        assert_eq!(widget.get_data_by_key("children").unwrap(), Value::test_record(record! { "child" => Value::test_int(1) }));
+
+        // a lone one is as ambiguous as any lone property, which --keep-props is for
+        let keep_props = EvaluatedCall::new(Span::test_data()).with_flag(flag("keep-props"));
+        let value = KDL.from(None, &keep_props, &Value::test_string("widget entries=5\n")).unwrap();
+        assert_eq!(to_kdl(&value), "widget entries=5\n");
+    }
+
+    #[test]
+    fn test_null_omit() {
+        let document = Value::test_record(record! {
+            "a" => Value::test_int(1),
+            "b" => Value::test_nothing(),
+            "c" => Value::test_record(record! { "d" => Value::test_nothing(), "e" => Value::test_int(3) }),
+        });
+        let null_omit = || EvaluatedCall::new(Span::test_data()).with_flag(flag("null-omit"));
+
+        assert_eq!(to_kdl(&document), "a 1\nb\nc {\n    d\n    e 3\n}\n");
+        assert_eq!(to_kdl_with(null_omit(), &document), "a 1\nc {\n    e 3\n}\n");
+        let props = null_omit().with_named(flag("record-as"), Value::test_string("props"));
+        assert_eq!(to_kdl_with(props, &document), "a 1\nc e=3\n");
+        let prefixed = Value::test_record(record! { "n" => Value::test_record(record! { "@a" => Value::test_nothing(), "@b" => Value::test_int(2), "c" => Value::test_nothing() }) });
+        let prefix = null_omit().with_named(flag("property-prefix"), Value::test_string("@"));
+        assert_eq!(to_kdl_with(prefix, &prefixed), "n b=2\n");
+        // a null which isn't a field is still written
+        let list = Value::test_record(record! { "a" => Value::test_list(vec![Value::test_nothing(), Value::test_int(1)]) });
+        assert_eq!(to_kdl_with(null_omit(), &list), "a #null 1\n");
+    }
+
+    #[test]
+    fn test_preserve_empty_children() {
+        let preserve = || EvaluatedCall::new(Span::test_data()).with_flag(flag("preserve-empty-children"));
+
+        let document = Value::test_record(record! { "parent" => Value::test_record(Record::new()) });
+        assert_eq!(to_kdl(&document), "parent\n");
+        assert_eq!(to_kdl_with(preserve(), &document), "parent {}\n");
+        assert_eq!(from_kdl("parent {}"), document);
+
+        let output = to_kdl_with(preserve(), &Value::test_record(record! { "parent" => Value::test_nothing() }));
+        assert_eq!(output, "parent\n");
+
+        let nested = Value::test_record(record! { "parent" => Value::test_record(record! { "child" => Value::test_record(Record::new()) }) });
+        assert_eq!(to_kdl(&nested), "parent {\n    child\n}\n");
+        assert_eq!(to_kdl_with(preserve(), &nested), "parent {\n    child {}\n}\n");
+    }
+
+    #[test]
+    fn test_empty_string_is_not_a_bare_node() {
+        let document = Value::test_record(record! { "empty" => Value::test_string(""), "bare" => Value::test_nothing() });
+
+        let output = to_kdl(&document);
+        assert_eq!(output, "empty \"\"\nbare\n");
+        assert_eq!(from_kdl(&output), document);
+
+        let call = EvaluatedCall::new(Span::test_data()).with_flag(flag("v1"));
+        let output = to_kdl_with(call, &document);
+        assert_eq!(output, "empty \"\"\nbare\n");
+    }
+
+    #[test]
+    fn test_oneline() {
+        let server = |comment: Option<&str>| {
+            let mut server = Record::new();
+            if let Some(comment) = comment {
+                server.insert("$comment", Value::test_string(comment));
+            }
+            server.insert("host", Value::test_string("x"));
+            server.insert("tls", Value::test_record(record! { "enabled" => Value::test_bool(true), "ciphers" => Value::test_list(vec![Value::test_string("a"), Value::test_string("b")]) }));
+            Value::test_record(record! { "server" => Value::test_record(server), "port" => Value::test_int(8080) })
+        };
+        let call = EvaluatedCall::new(Span::test_data()).with_flag(flag("oneline"));
+
+        let output = to_kdl_with(call, &server(Some("the server")));
+
+        assert!(!output.contains('\n'), "{:?}", output);
+        assert_eq!(output, "/* the server */ server { host x; tls { enabled #true; ciphers a b } }; port 8080");
+        assert_eq!(from_kdl(&output), server(None));
+    }
+
+    #[test]
+    fn test_inline_children() {
+        let mut children = Record::new();
+        children.insert("a", Value::test_int(1));
+        children.insert("b", Value::test_record(Record::from_iter([("c".to_string(), Value::test_int(2))])));
+        let mut document = Record::new();
+        document.insert("node", Value::test_record(children));
+        document.insert("empty", Value::test_record(Record::new()));
+        let document = Value::test_record(document);
+
+        // the empty block is kept to check it's written as `{}` either way
+        let preserve = || EvaluatedCall::new(Span::test_data()).with_flag(flag("preserve-empty-children"));
+        let multi_line = to_kdl_with(preserve(), &document);
+        let inline = to_kdl_with(preserve().with_flag(flag("inline-children")), &document);
+
+        assert_eq!(multi_line, "node {\n    a 1\n    b {\n        c 2\n    }\n}\nempty {}\n");
+        assert_eq!(inline, "node { a 1; b { c 2 } }\nempty {}\n");
+        assert_eq!(to_kdl_with(preserve(), &from_kdl(&inline)), multi_line);
+    }
+
+    #[test]
+    fn test_multiline_threshold() {
+        let children = |n: usize| Value::test_record((0..n).map(|i| (format!("c{}", i), Value::test_int(i as i64))).collect::<Record>());
+        let document = Value::test_record(record! { "small" => children(2), "big" => children(5) });
+        let threshold = EvaluatedCall::new(Span::test_data()).with_named(flag("multiline-threshold"), Value::test_int(3));
+
+        let output = to_kdl_with(threshold, &document);
+
+        assert_eq!(output, "small { c0 0; c1 1 }\nbig {\n    c0 0\n    c1 1\n    c2 2\n    c3 3\n    c4 4\n}\n");
+        assert_eq!(to_kdl(&document), to_kdl(&from_kdl(&output)));
+    }
+
+    #[test]
+    fn test_multiline_threshold_nested() {
+        let document = Value::test_record(record! {
+            "node" => Value::test_record(record! {
+                "a" => Value::test_record(record! { "x" => Value::test_int(1), "y" => Value::test_int(2), "z" => Value::test_int(3) }),
+            }),
+        });
+        let threshold = EvaluatedCall::new(Span::test_data()).with_named(flag("multiline-threshold"), Value::test_int(2));
+
+        assert_eq!(to_kdl_with(threshold, &document), "node {\n    a {\n        x 1\n        y 2\n        z 3\n    }\n}\n");
+    }
+
+    /// A custom value standing for a standard one, like the values of other plugins.
+    #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+    struct Wrapped(Option<i64>);
+
+    #[typetag::serde]
+    impl nu_protocol::CustomValue for Wrapped {
+        fn clone_value(&self, span: Span) -> Value {
+            Value::custom(Box::new(self.clone()), span)
+        }
+
+        fn type_name(&self) -> String {
+            "wrapped".to_string()
+        }
+
+        fn to_base_value(&self, span: Span) -> Result<Value, nu_protocol::ShellError> {
+            Ok(match self.0 {
+                Some(int) => Value::int(int, span),
+                None => Value::filesize(1, span),
+            })
+        }
+
+        fn as_any(&self) -> &dyn std::any::Any {
+            self
+        }
+
+        fn as_mut_any(&mut self) -> &mut dyn std::any::Any {
+            self
+        }
+    }
+
+    #[test]
+    fn test_custom_value() {
+        let mut document = Record::new();
+        document.insert("node", Value::test_custom_value(Box::new(Wrapped(Some(42)))));
+        document.insert("list", Value::test_list(vec![Value::test_int(1), Value::test_custom_value(Box::new(Wrapped(Some(2))))]));
+
+        assert_eq!(to_kdl(&Value::test_record(document)), "node 42\nlist 1 2\n");
+    }
+
+    #[test]
+    fn test_unsupported_custom_value() {
+        let mut document = Record::new();
+        document.insert("node", Value::test_custom_value(Box::new(Wrapped(None))));
+
+        let error = KDL.to(None, &EvaluatedCall::new(Span::test_data()), &Value::test_record(document)).unwrap_err();
+
+        assert_eq!(error.msg, "value not supported, expected string, int, float, bool, duration or null");
+    }
+
+    #[test]
+    fn test_quote_all_strings() {
+        let mut document = Record::new();
+        document.insert("mode", Value::test_string("enabled"));
+        let first = Value::test_record(Record::from_iter([("first".to_string(), Value::test_string("Zoë"))]));
+        document.insert("name", Value::test_list(vec![Value::test_string("x"), first]));
+        document.insert("size", Value::test_int(1));
+        let document = Value::test_record(document);
+
+        let quoted = to_kdl_with(EvaluatedCall::new(Span::test_data()).with_flag(flag("quote-all-strings")), &document);
+
+        assert_eq!(to_kdl(&document), "mode enabled\nname x first=Zoë\nsize 1\n");
+        assert_eq!(quoted, "mode \"enabled\"\nname \"x\" first=\"Zoë\"\nsize 1\n");
+        assert_eq!(from_kdl(&quoted), from_kdl(&to_kdl(&document)));
+    }
+
+    #[test]
+    fn test_number_repr_round_trip() {
+        let preserve = EvaluatedCall::new(Span::test_data()).with_flag(flag("preserve-number-repr"));
+        let input = "a 1.50\nb 1e3\nc +5 size=0x10\nd 5\n";
+
+        let output = to_kdl(&KDL.from(None, &preserve, &Value::test_string(input)).unwrap());
+
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn test_annotated_number_repr_round_trip() {
+        let input = "n (u8)0x10\nm (f32)1.50 big=(u128)99999999999999999999\n";
+
+        for flag_name in ["keep-annotations", "flatten", "ordered-entries"] {
+            let call = EvaluatedCall::new(Span::test_data()).with_flag(flag(flag_name)).with_flag(flag("preserve-number-repr"));
+            let parsed = KDL.from(None, &call, &Value::test_string(input)).unwrap();
+
+            assert_eq!(to_kdl(&parsed), input, "{}", flag_name);
+        }
+    }
+
+    #[test]
+    fn test_stale_number_repr() {
+        let preserve = EvaluatedCall::new(Span::test_data()).with_flag(flag("preserve-number-repr"));
+        let mut document = KDL.from(None, &preserve, &Value::test_string("n 1.50\nm 0x10\n")).unwrap();
+        let mut edited = document.as_record().unwrap().get("n").unwrap().as_record().unwrap().clone();
+        edited.insert("$value", Value::test_float(2.0));
+        document = Value::test_record(record! { "n" => Value::test_record(edited), "m" => document.get_data_by_key("m").unwrap() });
+
+        assert_eq!(to_kdl(&document), "n 2.0\nm 0x10\n");
+        // a repr of another type of number isn't the value either
+        let int_repr = Value::test_record(record! { "n" => Value::test_record(record! { "$value" => Value::test_int(1), "$repr" => Value::test_string("1.0") }) });
+        assert_eq!(to_kdl(&int_repr), "n 1\n");
+    }
+
+    #[test]
+    fn test_injected_number_repr() {
+        for repr in ["1 evil=2 { x }", "1; evil", "(u8)1", "x=1", "\"1\""] {
+            let document = Value::test_record(record! { "n" => Value::test_record(record! { "$value" => Value::test_int(1), "$repr" => Value::test_string(repr) }) });
+
+            assert_eq!(to_kdl(&document), "n 1\n", "{}", repr);
+        }
+    }
+
+    #[test]
+    fn test_radix() {
+        let radix = |radix: &str, value: i64| Value::test_record(record! { "$radix" => Value::test_string(radix), "$value" => Value::test_int(value) });
+        let document = Value::test_record(record! {
+            "color" => radix("hex", 255),
+            "mode" => radix("oct", 0o755),
+            "mask" => radix("bin", -5),
+        });
+
+        let output = to_kdl(&document);
+
+        assert_eq!(output, "color 0xFF\nmode 0o755\nmask -0b101\n");
+        let parsed = from_kdl(&output);
+        assert_eq!(parsed.get_data_by_key("color").unwrap(), Value::test_int(255));
+        assert_eq!(parsed.get_data_by_key("mode").unwrap(), Value::test_int(0o755));
+        assert_eq!(parsed.get_data_by_key("mask").unwrap(), Value::test_int(-5));
+        let error = KDL.to(None, &EvaluatedCall::new(Span::test_data()), &Value::test_record(record! { "a" => radix("dec", 1) }));
+        assert_eq!(error.unwrap_err().msg, "invalid radix `dec`");
+    }
+
+    #[test]
+    fn test_signed_number_repr_round_trip() {
+        let preserve = EvaluatedCall::new(Span::test_data()).with_flag(flag("preserve-number-repr"));
+
+        for input in ["a +5\n", "a -0\n", "a 5\n", "a -0.0 +1.5\n"] {
+            let output = to_kdl(&KDL.from(None, &preserve, &Value::test_string(input)).unwrap());
+
+            assert_eq!(output, input);
+        }
+    }
+
+    #[test]
+    fn test_disabled_nodes() {
+        let mut server = Record::new();
+        server.insert("$disabled", Value::test_bool(true));
+        server.insert("$comment", Value::test_string("the old one"));
+        server.insert("host", Value::test_string("old"));
+        let mut document = Record::new();
+        document.insert("server", Value::test_record(server));
+        document.insert("port", Value::test_record(record! { "$disabled" => Value::test_bool(true), "$value" => Value::test_int(80) }));
+        document.insert("host", Value::test_record(record! { "$disabled" => Value::test_bool(false), "$value" => Value::test_string("new") }));
+
+        let output = to_kdl(&Value::test_record(document));
+
+        assert_eq!(output, "// the old one\n/-server {\n    host old\n}\n/-port 80\nhost new\n");
+        let parsed = from_kdl(&output);
+        assert_eq!(parsed.as_record().unwrap().columns().collect::<Vec<_>>(), ["host"]);
+        assert_eq!(parsed.get_data_by_key("host").unwrap(), Value::test_string("new"));
+    }
+}
@@ -0,0 +1,97 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{Category, LabeledError, PipelineData, Record, Signature, Spanned, SyntaxShape, Type, Value};
+
+use crate::KDL;
+
+/// The name of the children a list is written as, the usual one for KDL list items.
+const ITEM_NODE_NAME: &str = "-";
+
+pub struct KdlWrap;
+
+impl PluginCommand for KdlWrap {
+    type Plugin = KDL;
+
+    fn name(&self) -> &str {
+        "kdl wrap"
+    }
+
+    fn description(&self) -> &str {
+        "Make any value a KDL document with a single node of the given name"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .input_output_type(Type::Any, Type::String)
+            .required("name", SyntaxShape::String, "The name of the node")
+            .category(Category::Experimental)
+    }
+
+    fn run(
+        &self,
+        plugin: &KDL,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let value = input.into_value(call.head)?;
+        let result = wrap(plugin, Some(engine), call, &value)?;
+        Ok(PipelineData::Value(result, None))
+    }
+}
+
+/// Writes the value as the node named by the first positional argument, like `to kdl` writes a
+/// field of a record: scalars are its arguments and records its children. A list is a `-` child
+/// per element instead of the arguments of the node, so lists of records can be wrapped too.
+pub(crate) fn wrap(plugin: &KDL, engine: Option<&EngineInterface>, call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+    let name: Spanned<String> = call.req(0)?;
+    let span = input.span();
+
+    let node = match input {
+        Value::List { vals, .. } => {
+            // the `#n` suffix repeats a name, see `to kdl`
+            let items = vals.iter().enumerate().map(|(i, val)| {
+                let key = if i == 0 { ITEM_NODE_NAME.to_string() } else { format!("{}#{}", ITEM_NODE_NAME, i) };
+                (key, val.clone())
+            });
+            Value::record(items.collect(), span)
+        }
+        _ => input.clone(),
+    };
+
+    let mut document = Record::new();
+    document.push(name.item, node);
+    plugin.to(engine, call, &Value::record(document, span))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nu_protocol::{record, Span};
+
+    fn wrap_kdl(name: &str, value: Value) -> String {
+        let call = EvaluatedCall::new(Span::test_data()).with_positional(Value::test_string(name));
+        wrap(&KDL, None, &call, &value).unwrap().into_string().unwrap()
+    }
+
+    #[test]
+    fn test_wrap_scalar() {
+        assert_eq!(wrap_kdl("port", Value::test_int(8080)), "port 8080\n");
+        assert_eq!(wrap_kdl("title", Value::test_string("my app")), "title \"my app\"\n");
+    }
+
+    #[test]
+    fn test_wrap_list() {
+        let list = Value::test_list(vec![Value::test_int(1), Value::test_record(record! { "a" => Value::test_string("x") })]);
+
+        assert_eq!(wrap_kdl("items", list), "items {\n    - 1\n    - {\n        a x\n    }\n}\n");
+        assert_eq!(wrap_kdl("items", Value::test_list(vec![])), "items\n");
+    }
+
+    #[test]
+    fn test_wrap_record() {
+        let server = Value::test_record(record! { "host" => Value::test_string("x"), "port" => Value::test_int(80) });
+
+        assert_eq!(wrap_kdl("server", server), "server {\n    host x\n    port 80\n}\n");
+    }
+}